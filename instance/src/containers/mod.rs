@@ -1,13 +1,22 @@
 use std::{
     collections::VecDeque,
+    path::{Path, PathBuf},
     sync::{atomic::AtomicI32, Arc, Mutex},
 };
 
 use number_prefix::NumberPrefix;
-use oci_spec::{distribution::Reference, runtime::Spec};
+use oci_spec::{
+    distribution::Reference,
+    image::{ImageConfiguration, ImageManifest},
+    runtime::Spec,
+};
 use rt::RuntimeOverrides;
 
-use crate::{containers::registry::RegistryErrors, host::HostCommunication};
+use crate::{
+    containers::registry::{RegistryAuth, RegistryErrors},
+    host::HostCommunication,
+    sh,
+};
 
 pub mod fs;
 pub mod registry;
@@ -15,28 +24,114 @@ pub mod rt;
 
 const CONCURRENT_LAYER_DOWNLOADS: usize = 5;
 
+type PulledImage = (ImageManifest, ImageConfiguration, Vec<PathBuf>);
+
+/// Prepares the container in `/mnt`, using the image cached on the host drive `image_drive` when possible.
 pub fn pull_and_prepare_image(
     reference: Reference,
     overrides: &RuntimeOverrides,
+    credentials: Option<&RegistryAuth>,
+    image_drive: Option<&str>,
     comm: Arc<Mutex<HostCommunication>>,
 ) -> Result<Spec, registry::RegistryErrors> {
+    let folder = PathBuf::from("/mnt");
+
+    let cached_image = image_drive.and_then(|drive| match load_cached_image(drive, &folder) {
+        Ok(image) => Some(image),
+        Err(e) => {
+            log::warn!("Unable to use cached image from {}: {:?}", drive, e);
+            comm.lock().unwrap().log_system_message(format!(
+                "Unable to use the cached container image ({}), pulling it instead.",
+                e
+            ));
+            None
+        }
+    });
+
+    let (manifest, config, layer_folders) = match cached_image {
+        Some(image) => {
+            comm.lock().unwrap().state_change(
+                vmproto::guest::InitVmState::PullingContainerImage,
+                Some("Using cached container image.".to_string()),
+            );
+            image
+        }
+        None => pull_image(&reference, credentials, &folder, comm.clone())?,
+    };
+
+    config
+        .to_file(&folder.join("image_config.json"))
+        .expect("Unable to save config");
+
+    let spec = rt::create_runtime_spec(&config, &overrides)
+        .map_err(|_| registry::RegistryErrors::UnableToConstructRuntimeConfig)?;
+
+    spec.save(&folder.join("config.json"))
+        .expect("Unable to save runtime spec");
+
+    manifest
+        .to_file_pretty(&folder.join("manifest.json"))
+        .expect("Unable to save manifest");
+
+    // Create the overlay filesystem
+    let merged_path = folder.join("rootfs");
+    let work_path = folder.join("work");
+    std::fs::create_dir_all(&merged_path).map_err(|_| registry::RegistryErrors::IOErr)?;
+    std::fs::create_dir_all(&work_path).map_err(|_| registry::RegistryErrors::IOErr)?;
+
+    fs::create_overlay_fs(&merged_path, &work_path, &layer_folders);
+    fs::prepare_fs(&merged_path).expect("Unable to prepare filesystem");
+
+    log::info!("Image pulled and extracted successfully.");
+
+    Ok(spec)
+}
+
+/// Mounts an image filesystem built by the host image cache and returns it with its layer folders.
+fn load_cached_image(drive: &str, folder: &Path) -> anyhow::Result<PulledImage> {
+    let mount_path = folder.join("image");
+    std::fs::create_dir_all(&mount_path)?;
+    sh::try_cmd(&[
+        "mount",
+        "-t",
+        "ext4",
+        "-o",
+        "ro",
+        drive,
+        mount_path.to_str().unwrap(),
+    ])?;
+
+    let manifest = ImageManifest::from_file(mount_path.join("manifest.json"))?;
+    let config = ImageConfiguration::from_file(mount_path.join("image_config.json"))?;
+    let layer_folders = manifest
+        .layers()
+        .iter()
+        .map(|layer| {
+            mount_path
+                .join("layers")
+                .join(layer.digest().to_string().replace(":", ""))
+        })
+        .collect::<Vec<_>>();
+    if let Some(missing) = layer_folders.iter().find(|folder| !folder.is_dir()) {
+        anyhow::bail!("Layer {} missing in cached image", missing.display());
+    }
+    Ok((manifest, config, layer_folders))
+}
+
+fn pull_image(
+    reference: &Reference,
+    credentials: Option<&RegistryAuth>,
+    folder: &Path,
+    comm: Arc<Mutex<HostCommunication>>,
+) -> Result<PulledImage, registry::RegistryErrors> {
     comm.lock().unwrap().state_change(
         vmproto::guest::InitVmState::PullingContainerImage,
         Some(format!("Starting to pull container image.")),
     );
 
-    let mut auth: Option<String> = None;
-
-    if reference.registry() == "docker.io" {
-        auth = Some(
-            registry::docker_io_oauth("repository", &reference.repository(), &["pull"])
-                .map_err(|_| registry::RegistryErrors::AuthenticationError)?,
-        );
-    }
-
-    let folder = std::path::PathBuf::from("/mnt");
+    let auth = registry::authenticate(reference, credentials)?;
 
-    let (manifest, config) = registry::get_manifest_and_config(&reference, auth.as_deref())?;
+    let (manifest, config) = registry::get_manifest_and_config(reference, auth.as_ref())?;
 
     let layers_folder = folder.join("layers");
     std::fs::create_dir_all(&layers_folder).map_err(|_| registry::RegistryErrors::IOErr)?;
@@ -85,7 +180,7 @@ pub fn pull_and_prepare_image(
                         &reference,
                         &layer,
                         &folder,
-                        auth.as_deref(),
+                        auth.as_ref(),
                     )?;
 
                     let layer_compressed_size =
@@ -113,30 +208,5 @@ pub fn pull_and_prepare_image(
         jh.join().map_err(|_| registry::RegistryErrors::IOErr)??;
     }
 
-    config
-        .to_file(&folder.join("image_config.json"))
-        .expect("Unable to save config");
-
-    let spec = rt::create_runtime_spec(&config, &overrides)
-        .map_err(|_| registry::RegistryErrors::UnableToConstructRuntimeConfig)?;
-
-    spec.save(&folder.join("config.json"))
-        .expect("Unable to save runtime spec");
-
-    manifest
-        .to_file_pretty(&folder.join("manifest.json"))
-        .expect("Unable to save manifest");
-
-    // Create the overlay filesystem
-    let merged_path = folder.join("rootfs");
-    let work_path = folder.join("work");
-    std::fs::create_dir_all(&merged_path).map_err(|_| registry::RegistryErrors::IOErr)?;
-    std::fs::create_dir_all(&work_path).map_err(|_| registry::RegistryErrors::IOErr)?;
-
-    fs::create_overlay_fs(&merged_path, &work_path, &layer_folders);
-    fs::prepare_fs(&merged_path).expect("Unable to prepare filesystem");
-
-    log::info!("Image pulled and extracted successfully.");
-
-    Ok(spec)
+    Ok((manifest, config, layer_folders))
 }
@@ -1,10 +1,14 @@
+use std::collections::HashMap;
 use std::path::Path;
 
 use backoff::ExponentialBackoff;
 use oci_spec::image::{Arch, Descriptor, ImageConfiguration, ImageManifest, MediaType, Os};
 use oci_spec::{distribution::Reference, image::ImageIndex};
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::WWW_AUTHENTICATE;
+use reqwest::StatusCode;
 use serde::Deserialize;
+use vmproto::registry::{parse_challenge, registry_url, token_request, TokenResponse};
 
 #[derive(Debug)]
 pub enum RegistryErrors {
@@ -23,24 +27,30 @@ pub enum RegistryErrors {
 
 const SUPPORTED_ARCH: Arch = Arch::Amd64; //TODO: Arm64?
 
+/// Credentials for a registry, either given by the host or obtained through its token server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistryAuth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
 pub fn get_manifest_and_config(
     reference: &Reference,
-    auth_token: Option<&str>,
+    auth: Option<&RegistryAuth>,
 ) -> Result<(ImageManifest, ImageConfiguration), RegistryErrors> {
     let index_url = format!(
-        "https://{}/v2/{}/manifests/{}",
-        reference.resolve_registry(),
+        "{}/v2/{}/manifests/{}",
+        registry_url(reference.resolve_registry()),
         reference.repository(),
         reference.tag().unwrap_or("latest"),
     );
     log::debug!("Pulling index from {}", index_url);
     let index_data: serde_json::Value =
-        get_with_backoff(&index_url, auth_token)?
-            .json()
-            .map_err(|e| {
-                log::debug!("Image index response is not JSON: {:?}", e);
-                RegistryErrors::UnableToParseImageIndex
-            })?;
+        get_with_backoff(&index_url, auth)?.json().map_err(|e| {
+            log::debug!("Image index response is not JSON: {:?}", e);
+            RegistryErrors::UnableToParseImageIndex
+        })?;
 
     let schema_version = index_data
         .get("schemaVersion")
@@ -64,13 +74,13 @@ pub fn get_manifest_and_config(
                 .ok_or(RegistryErrors::NoCompatibleImageAvailable)?;
 
             let manifest_url = format!(
-                "https://{}/v2/{}/manifests/{}",
-                reference.resolve_registry(),
+                "{}/v2/{}/manifests/{}",
+                registry_url(reference.resolve_registry()),
                 reference.repository(),
                 compatible_manifest.digest()
             );
             log::debug!("Pulling manifest from {}", manifest_url);
-            ImageManifest::from_reader(get(&manifest_url, auth_token)?).map_err(|e| {
+            ImageManifest::from_reader(get(&manifest_url, auth)?).map_err(|e| {
                 log::debug!("UnableToParseImageManifest: {:?}", e);
                 RegistryErrors::UnableToParseImageManifest
             })
@@ -86,13 +96,13 @@ pub fn get_manifest_and_config(
     }?;
 
     let config_url = format!(
-        "https://{}/v2/{}/blobs/{}",
-        reference.resolve_registry(),
+        "{}/v2/{}/blobs/{}",
+        registry_url(reference.resolve_registry()),
         reference.repository(),
         manifest.config().digest()
     );
     log::debug!("Pulling config from {}", config_url);
-    let config: ImageConfiguration = ImageConfiguration::from_reader(get(&config_url, auth_token)?)
+    let config: ImageConfiguration = ImageConfiguration::from_reader(get(&config_url, auth)?)
         .map_err(|e| {
             log::debug!("UnableToParseImageConfiguration: {:?}", e);
             RegistryErrors::UnableToParseImageConfiguration
@@ -104,16 +114,16 @@ pub fn pull_and_extract_layer(
     reference: &Reference,
     layer: &Descriptor,
     output_folder: &Path,
-    auth_token: Option<&str>,
+    auth: Option<&RegistryAuth>,
 ) -> Result<usize, RegistryErrors> {
     let blob_url = format!(
-        "https://{}/v2/{}/blobs/{}",
-        reference.resolve_registry(),
+        "{}/v2/{}/blobs/{}",
+        registry_url(reference.resolve_registry()),
         reference.repository(),
         layer.digest()
     );
 
-    let mut blob_resp = get_with_backoff(&blob_url, auth_token)?;
+    let mut blob_resp = get_with_backoff(&blob_url, auth)?;
     let blob_resp_size = blob_resp.content_length().unwrap_or(0);
     extract_layer(&mut blob_resp, &output_folder, layer.media_type())?;
     Ok(blob_resp_size as usize)
@@ -145,36 +155,93 @@ fn extract_layer(
     })
 }
 
-pub fn docker_io_oauth(
-    scope_type: &str,
-    resource_name: &str,
-    actions: &[&str],
-) -> Result<String, String> {
-    let url = format!(
-        "https://auth.docker.io/token?service=registry.docker.io&scope={}:{}:{}",
-        scope_type,
-        resource_name,
-        actions.join(",")
-    );
-    let resp = reqwest::blocking::get(url).map_err(|e| {
-        log::debug!("Error while obtaining docker.io token: {:?}", e);
-        e.to_string()
+/// Works out how to authenticate against the registry of `reference` for pulling from it.
+/// Registries asking for a bearer token get one from the realm of their `WWW-Authenticate` challenge,
+/// using the basic credentials if any, while a given bearer token is used as is.
+pub fn authenticate(
+    reference: &Reference,
+    credentials: Option<&RegistryAuth>,
+) -> Result<Option<RegistryAuth>, RegistryErrors> {
+    if let Some(RegistryAuth::Bearer { .. }) = credentials {
+        return Ok(credentials.cloned());
+    }
+
+    let url = format!("{}/v2/", registry_url(reference.resolve_registry()));
+    let resp = Client::new().get(&url).send().map_err(|e| {
+        log::error!("Network error while accessing {}: {}", url, e);
+        RegistryErrors::NetworkError
     })?;
+    if resp.status() != StatusCode::UNAUTHORIZED {
+        return Ok(credentials.cloned());
+    }
+
+    let (scheme, params) = resp
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_challenge)
+        .ok_or_else(|| {
+            log::error!("Registry {} did not send a usable challenge", url);
+            RegistryErrors::AuthenticationError
+        })?;
 
-    #[derive(Deserialize)]
-    struct TokenResponse {
-        token: String,
+    match scheme.as_str() {
+        "basic" => match credentials {
+            Some(credentials) => Ok(Some(credentials.clone())),
+            None => {
+                log::error!("Registry {} requires credentials", url);
+                Err(RegistryErrors::AuthenticationError)
+            }
+        },
+        "bearer" => fetch_token(reference, &params, credentials).map(Some),
+        _ => {
+            log::error!("Unsupported authentication scheme {} for {}", scheme, url);
+            Err(RegistryErrors::AuthenticationError)
+        }
     }
-    let resp: TokenResponse = resp.json().map_err(|e| e.to_string())?;
+}
 
-    log::debug!("Obtained docker.io token: {}", resp.token);
+fn fetch_token(
+    reference: &Reference,
+    params: &HashMap<String, String>,
+    credentials: Option<&RegistryAuth>,
+) -> Result<RegistryAuth, RegistryErrors> {
+    let (realm, query) =
+        token_request(reference.repository(), params).ok_or(RegistryErrors::AuthenticationError)?;
+    let resp = with_auth(Client::new().get(realm).query(&query), credentials)
+        .send()
+        .map_err(|e| {
+            log::error!("Network error while obtaining token from {}: {}", realm, e);
+            RegistryErrors::NetworkError
+        })?;
+    if !resp.status().is_success() {
+        log::error!("Token request to {} failed: {}", realm, resp.status());
+        return Err(RegistryErrors::AuthenticationError);
+    }
+    let resp: TokenResponse = resp.json().map_err(|e| {
+        log::debug!("Token response is not JSON: {:?}", e);
+        RegistryErrors::AuthenticationError
+    })?;
+    log::debug!("Obtained registry token from {}", realm);
 
-    Ok(resp.token)
+    resp.into_token()
+        .map(|token| RegistryAuth::Bearer { token })
+        .ok_or(RegistryErrors::AuthenticationError)
+}
+
+fn with_auth(request: RequestBuilder, auth: Option<&RegistryAuth>) -> RequestBuilder {
+    match auth {
+        Some(RegistryAuth::Basic { username, password }) => {
+            request.basic_auth(username, Some(password))
+        }
+        Some(RegistryAuth::Bearer { token }) => request.bearer_auth(token),
+        None => request,
+    }
 }
 
 fn get_with_backoff(
     url: &str,
-    auth_token: Option<&str>,
+    auth: Option<&RegistryAuth>,
 ) -> Result<reqwest::blocking::Response, RegistryErrors> {
     let backoff = ExponentialBackoff {
         initial_interval: std::time::Duration::from_secs(3),
@@ -184,7 +251,7 @@ fn get_with_backoff(
         ..Default::default()
     };
     let op = || {
-        get(url, auth_token).map_err(|e| match e {
+        get(url, auth).map_err(|e| match e {
             RegistryErrors::NetworkError => backoff::Error::transient(e),
             _ => backoff::Error::permanent(e),
         })
@@ -194,13 +261,12 @@ fn get_with_backoff(
     })
 }
 
-fn get(url: &str, auth_token: Option<&str>) -> Result<reqwest::blocking::Response, RegistryErrors> {
+fn get(
+    url: &str,
+    auth: Option<&RegistryAuth>,
+) -> Result<reqwest::blocking::Response, RegistryErrors> {
     let client = Client::new();
-    let mut request = client.get(url);
-    if let Some(token) = auth_token {
-        request = request.bearer_auth(token);
-    }
-    let resp = request.send().map_err(|e| {
+    let resp = with_auth(client.get(url), auth).send().map_err(|e| {
         log::error!("Network error while accessing {}: {}", url, e);
         RegistryErrors::NetworkError
     })?;
@@ -215,3 +281,131 @@ fn get(url: &str, auth_token: Option<&str>) -> Result<reqwest::blocking::Respons
         Err(RegistryErrors::RegistryResponseError)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const SERVICE: &str = "test-registry";
+    const TOKEN: &str = "test-token";
+    const BASIC_AUTH: &str = "Basic dXNlcjpwYXNzd29yZA=="; // user:password
+
+    /// Stand-in for a registry behind a token server, like GHCR or Docker Hub.
+    /// Serves `test/image` on a random loopback port and returns its address.
+    fn test_registry() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let realm = format!("http://{}/token", addr);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+
+                let mut authorization = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(':') {
+                        Some((name, value)) if name.eq_ignore_ascii_case("authorization") => {
+                            authorization = Some(value.trim().to_string())
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+
+                let challenge = format!(
+                    "WWW-Authenticate: Bearer realm=\"{}\",service=\"{}\"\r\n",
+                    realm, SERVICE
+                );
+                let (status, headers, body) = if path.starts_with("/token?") {
+                    let scoped = path.contains("scope=repository%3Atest%2Fimage%3Apull")
+                        && path.contains(&format!("service={}", SERVICE));
+                    match (scoped, authorization.as_deref()) {
+                        (true, Some(BASIC_AUTH)) => (
+                            "200 OK",
+                            String::new(),
+                            format!("{{\"token\":\"{}\"}}", TOKEN),
+                        ),
+                        _ => ("401 Unauthorized", String::new(), String::new()),
+                    }
+                } else if authorization == Some(format!("Bearer {}", TOKEN))
+                    && path.starts_with("/v2/test/image/")
+                {
+                    ("200 OK", String::new(), "{}".to_string())
+                } else {
+                    ("401 Unauthorized", challenge, String::new())
+                };
+
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+                    status,
+                    body.len(),
+                    headers,
+                    body
+                );
+            }
+        });
+        addr
+    }
+
+    fn reference(addr: &str) -> Reference {
+        Reference::try_from(format!("{}/test/image:latest", addr)).unwrap()
+    }
+
+    fn manifest_url(addr: &str) -> String {
+        format!("http://{}/v2/test/image/manifests/latest", addr)
+    }
+
+    #[test]
+    fn bearer_challenge_with_basic_credentials() {
+        let addr = test_registry();
+        let credentials = RegistryAuth::Basic {
+            username: "user".to_string(),
+            password: "password".to_string(),
+        };
+
+        let auth = authenticate(&reference(&addr), Some(&credentials)).unwrap();
+        assert_eq!(
+            auth,
+            Some(RegistryAuth::Bearer {
+                token: TOKEN.to_string()
+            })
+        );
+        assert!(get(&manifest_url(&addr), auth.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn bearer_challenge_with_wrong_credentials() {
+        let addr = test_registry();
+        let credentials = RegistryAuth::Basic {
+            username: "user".to_string(),
+            password: "wrong".to_string(),
+        };
+
+        let res = authenticate(&reference(&addr), Some(&credentials));
+        assert!(matches!(res, Err(RegistryErrors::AuthenticationError)));
+    }
+
+    #[test]
+    fn bearer_token_is_used_as_is() {
+        let addr = test_registry();
+        let credentials = RegistryAuth::Bearer {
+            token: TOKEN.to_string(),
+        };
+
+        let auth = authenticate(&reference(&addr), Some(&credentials)).unwrap();
+        assert_eq!(auth.as_ref(), Some(&credentials));
+        assert!(get(&manifest_url(&addr), auth.as_ref()).is_ok());
+        assert!(get(&manifest_url(&addr), None).is_err());
+    }
+}
@@ -1,10 +1,14 @@
-use std::collections::{BTreeMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
 
 use oci_spec::{
     image::ImageConfiguration,
     runtime::{
-        Capability, LinuxBuilder, LinuxCapabilitiesBuilder, LinuxIdMappingBuilder,
-        LinuxNamespaceBuilder, LinuxNamespaceType, ProcessBuilder, RootBuilder, Spec, SpecBuilder,
+        get_default_mounts, Capability, LinuxBuilder, LinuxCapabilitiesBuilder,
+        LinuxIdMappingBuilder, LinuxNamespaceBuilder, LinuxNamespaceType, MountBuilder,
+        ProcessBuilder, RootBuilder, Spec, SpecBuilder,
     },
     OciSpecError,
 };
@@ -35,11 +39,19 @@ const DEFAULT_NAMESPACES: &[LinuxNamespaceType] = &[
     LinuxNamespaceType::Cgroup,
 ];
 
+#[derive(Debug)]
+pub struct BindMount {
+    pub source: PathBuf,
+    pub destination: String,
+    pub read_only: bool,
+}
+
 #[derive(Debug, Default)]
 pub struct RuntimeOverrides {
     pub additional_args: Option<Vec<String>>,
     pub additional_env: Option<BTreeMap<String, String>>,
     pub terminal: bool,
+    pub bind_mounts: Vec<BindMount>, // Volumes mounted in the guest
 }
 
 pub fn create_runtime_spec(
@@ -127,13 +139,30 @@ pub fn create_runtime_spec(
         .collect::<Result<Vec<_>, OciSpecError>>()?;
 
     let linux: oci_spec::runtime::Linux = LinuxBuilder::default()
+        .cgroups_path(PathBuf::from(crate::stats::CONTAINER_CGROUP))
         .namespaces(namespaces)
         .uid_mappings(vec![mapping.clone()])
         .gid_mappings(vec![mapping])
         .build()?;
 
+    let mut mounts = get_default_mounts();
+    for bind_mount in &overrides.bind_mounts {
+        mounts.push(
+            MountBuilder::default()
+                .destination(&bind_mount.destination)
+                .typ("bind")
+                .source(&bind_mount.source)
+                .options(vec![
+                    "rbind".to_string(),
+                    if bind_mount.read_only { "ro" } else { "rw" }.to_string(),
+                ])
+                .build()?,
+        );
+    }
+
     spec.process(process.build()?)
         .root(root)
+        .mounts(mounts)
         .hostname("node")
         .linux(linux)
         .uid_mappings(vec![mapping])
@@ -0,0 +1,130 @@
+use std::{
+    io::Read,
+    process::Command,
+    sync::{Arc, Mutex},
+};
+
+use vmproto::{
+    guest::{ExecOutput, ExecOutputType, GuestPacket},
+    host::ExecRequest,
+};
+
+use crate::host::HostCommunication;
+
+const EXEC_CHUNK_SIZE: usize = 4 * 1024;
+
+/// Runs `crun exec` in the running container and streams its output back to the host.
+/// Always ends with an `ExecExited` packet for the request, also when the exec fails to start.
+pub fn spawn_exec(
+    comm: Arc<Mutex<HostCommunication>>,
+    request: ExecRequest,
+    container_running: Arc<Mutex<bool>>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let exec_id = request.exec_id;
+        if !*container_running.lock().unwrap() {
+            log::debug!("Exec {} requested while container is not running", exec_id);
+            send_exec_failure(&comm, exec_id, "Container is not running\n");
+            return;
+        }
+        if request.args.is_empty() {
+            send_exec_failure(&comm, exec_id, "No command given\n");
+            return;
+        }
+
+        log::debug!(
+            "Executing {:?} in container (exec {})",
+            request.args,
+            exec_id
+        );
+        let mut child = match Command::new("/bin/crun")
+            .arg("exec")
+            .arg("container")
+            .args(&request.args)
+            .current_dir("/mnt")
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                log::error!("Failed to spawn crun exec: {}", e);
+                send_exec_failure(&comm, exec_id, &format!("Failed to spawn exec: {}\n", e));
+                return;
+            }
+        };
+
+        let stdout = child.stdout.take().expect("Failed to get exec stdout");
+        let stderr = child.stderr.take().expect("Failed to get exec stderr");
+        let stdout_thread = spawn_pipe_to_exec_output(
+            comm.clone(),
+            exec_id,
+            Box::new(stdout),
+            ExecOutputType::Stdout,
+        );
+        let stderr_thread = spawn_pipe_to_exec_output(
+            comm.clone(),
+            exec_id,
+            Box::new(stderr),
+            ExecOutputType::Stderr,
+        );
+
+        let exit_code = match child.wait() {
+            Ok(status) => status.code().unwrap_or(-1),
+            Err(e) => {
+                log::error!("Failed to wait for exec {}: {}", exec_id, e);
+                -1
+            }
+        };
+        let _ = stdout_thread.join();
+        let _ = stderr_thread.join();
+
+        log::debug!("Exec {} exited with code {}", exec_id, exit_code);
+        let _ = comm
+            .lock()
+            .unwrap()
+            .write(GuestPacket::ExecExited((exec_id, exit_code)));
+    })
+}
+
+fn send_exec_failure(comm: &Arc<Mutex<HostCommunication>>, exec_id: u32, message: &str) {
+    let mut comm = comm.lock().unwrap();
+    let _ = comm.write(GuestPacket::ExecOutput(ExecOutput {
+        exec_id,
+        output_type: ExecOutputType::Stderr,
+        data: message.as_bytes().to_vec(),
+    }));
+    let _ = comm.write(GuestPacket::ExecExited((exec_id, -1)));
+}
+
+fn spawn_pipe_to_exec_output(
+    comm: Arc<Mutex<HostCommunication>>,
+    exec_id: u32,
+    mut pipe: Box<dyn Read + Send>,
+    output_type: ExecOutputType,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0; EXEC_CHUNK_SIZE];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    let packet = GuestPacket::ExecOutput(ExecOutput {
+                        exec_id,
+                        output_type,
+                        data: buf[..n].to_vec(),
+                    });
+                    if comm.lock().unwrap().write(packet).is_err() {
+                        log::error!("Unable to forward exec {} output to host", exec_id);
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Error reading exec {} output: {}", exec_id, e);
+                    return;
+                }
+            }
+        }
+    })
+}
@@ -3,6 +3,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use backoff::ExponentialBackoff;
 use vmproto::{
     guest::{serialize_guest_packet, GuestPacket, LogMessage, LogMessageType},
     host::HostPacket,
@@ -21,31 +22,92 @@ pub enum CommErrors {
 
 pub struct HostCommunication {
     stream: VsockStream,
+    port: u32,
+    generation: u64, // Bumped on every reconnect
+}
+
+fn connect(port: u32) -> Result<VsockStream, CommErrors> {
+    let stream: VsockStream = VsockStream::connect_with_cid_port(vsock::VMADDR_CID_HOST, port)
+        .map_err(|e| {
+            log::error!("Unable to connect to vsock: {}", e);
+            CommErrors::UnableToConnect
+        })?;
+    stream.set_read_timeout(None).map_err(CommErrors::IoError)?;
+    stream
+        .set_write_timeout(None)
+        .map_err(CommErrors::IoError)?;
+    Ok(stream)
 }
 
 impl HostCommunication {
     pub fn new(port: u32) -> Result<Self, CommErrors> {
-        let stream: VsockStream = VsockStream::connect_with_cid_port(vsock::VMADDR_CID_HOST, port)
-            .map_err(|e| {
-                log::error!("Unable to connect to vsock: {}", e);
-                CommErrors::UnableToConnect
-            })?;
-        stream.set_read_timeout(None).map_err(CommErrors::IoError)?;
-        stream
-            .set_write_timeout(None)
-            .map_err(CommErrors::IoError)?;
+        let stream = connect(port)?;
+        Ok(HostCommunication {
+            stream,
+            port,
+            generation: 0,
+        })
+    }
+
+    /// Replaces the connection to the host.
+    /// The vsock transport is reset when the VM is snapshotted or restored, or the host side may have restarted.
+    fn reconnect(&mut self) -> Result<(), CommErrors> {
+        let backoff = ExponentialBackoff {
+            initial_interval: std::time::Duration::from_millis(50),
+            max_interval: std::time::Duration::from_secs(1),
+            // Long enough for the nodemanager to be restarted and reattach
+            max_elapsed_time: Some(std::time::Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let stream = backoff::retry(backoff, || {
+            connect(self.port).map_err(backoff::Error::transient)
+        })
+        .map_err(|_| CommErrors::UnableToConnect)?;
+        log::info!("Reconnected to host");
+        self.stream = stream;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Reconnect for the reader side. Only reconnects if no one else did since `seen_generation`.
+    pub fn reconnect_read_stream(
+        &mut self,
+        seen_generation: u64,
+    ) -> Result<(VsockStream, u64), CommErrors> {
+        if self.generation == seen_generation {
+            self.reconnect()?;
+        }
+        Ok((self.clone_stream()?, self.generation))
+    }
 
-        Ok(HostCommunication { stream })
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 
     pub fn write(&mut self, packet: GuestPacket) -> Result<(), CommErrors> {
-        self.write_without_flush(packet)?;
-        self.flush()?;
-        Ok(())
+        self.write_packets(&[packet])
+    }
+
+    /// Writes and flushes the packets, reconnecting and retrying once if the connection is gone.
+    fn write_packets(&mut self, packets: &[GuestPacket]) -> Result<(), CommErrors> {
+        let write = |comm: &mut Self| -> Result<(), CommErrors> {
+            for packet in packets {
+                comm.write_without_flush(packet)?;
+            }
+            comm.flush()
+        };
+        match write(self) {
+            Err(CommErrors::IoError(e)) => {
+                log::warn!("Lost connection to host ({}), reconnecting", e);
+                self.reconnect()?;
+                write(self)
+            }
+            res => res,
+        }
     }
 
-    fn write_without_flush(&mut self, packet: GuestPacket) -> Result<(), CommErrors> {
-        let data = serialize_guest_packet(&packet);
+    fn write_without_flush(&mut self, packet: &GuestPacket) -> Result<(), CommErrors> {
+        let data = serialize_guest_packet(packet);
 
         self.stream
             .write_all(&(data.len() as u32).to_be_bytes())
@@ -67,29 +129,25 @@ impl HostCommunication {
 
     pub fn state_change(&mut self, state: vmproto::guest::InitVmState, message: Option<String>) {
         log::debug!("Sending state change: {:?}", state);
-        self.write_without_flush(GuestPacket::VmState((
+        let mut packets = vec![GuestPacket::VmState((
             state,
             vmproto::guest::get_timestamp_ms(),
-        )))
-        .expect("Failed to write init state change");
+        ))];
         if let Some(message) = message {
-            self.write_without_flush(GuestPacket::Log(LogMessage::system(message)))
-                .expect("Failed to write log message");
+            packets.push(GuestPacket::Log(LogMessage::system(message)));
         }
-        self.flush()
-            .expect("Failed to flush stream after init state change");
+        self.write_packets(&packets)
+            .expect("Failed to write init state change");
     }
 
     pub fn exit(&mut self, exit_code: vmproto::guest::GuestExitCode, message: Option<String>) {
         log::debug!("Sending exit code: {:?}", exit_code);
-        self.write_without_flush(GuestPacket::Exited(exit_code))
-            .expect("Failed to write init state change");
+        let mut packets = vec![GuestPacket::Exited(exit_code)];
         if let Some(message) = message {
-            self.write_without_flush(GuestPacket::Log(LogMessage::system(message)))
-                .expect("Failed to write log message");
+            packets.push(GuestPacket::Log(LogMessage::system(message)));
         }
-        self.flush()
-            .expect("Failed to flush stream after init state change");
+        self.write_packets(&packets)
+            .expect("Failed to write exit code");
     }
 
     pub fn clone_stream(&mut self) -> Result<VsockStream, CommErrors> {
@@ -172,7 +230,7 @@ pub fn read_packet(stream: &mut VsockStream) -> Result<HostPacket, CommErrors> {
         .read_exact(&mut len_buf)
         .map_err(CommErrors::IoError)?;
     let len = u32::from_be_bytes(len_buf) as usize;
-    if len > BUFFER_SIZE {
+    if len > vmproto::host::MAX_HOST_PACKET_SIZE {
         log::error!("Received packet length exceeds buffer size: {}", len);
         return Err(CommErrors::HostDeserializationError);
     }
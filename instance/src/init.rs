@@ -1,6 +1,27 @@
-use std::process::Command;
+use std::{path::PathBuf, process::Command};
 
-use crate::sh::cmd;
+use crate::sh::{cmd, try_cmd};
+
+const VOLUMES_PATH: &str = "/mnt/volumes";
+
+/// How to format and mount the scratch drive, sent by the host.
+#[derive(serde::Deserialize)]
+#[serde(default)]
+pub struct ScratchFilesystem {
+    fs_type: String,
+    features: Vec<String>,
+    mount_options: Vec<String>,
+}
+
+impl Default for ScratchFilesystem {
+    fn default() -> Self {
+        Self {
+            fs_type: "ext4".to_string(),
+            features: vec!["^has_journal".to_string()],
+            mount_options: Vec::new(),
+        }
+    }
+}
 
 fn mke2fs(args: &[&str]) {
     let output = Command::new("/sbin/mke2fs")
@@ -42,13 +63,6 @@ pub fn init() {
     log::debug!("Mounting /sys/fs/cgroup");
     cmd(&["mount", "-t", "cgroup2", "cgroup2", "/sys/fs/cgroup"]);
 
-    // Creating R/W fs in /mnt
-    log::debug!("Creating FS in /dev/vdb");
-    mke2fs(&["-t", "ext4", "-O", "^has_journal", "/dev/vdb"]);
-
-    log::debug!("Mounting /dev/vdb");
-    cmd(&["mount", "/dev/vdb", "/mnt"]);
-
     // Setting session id
     log::debug!("Setting session id");
     let session_id: i32 = unsafe { libc::setsid() };
@@ -66,3 +80,119 @@ pub fn init() {
     log::debug!("Enabling IP forwarding");
     cmd(&["sysctl", "-w", "net.ipv4.ip_forward=1"]);
 }
+
+/// The IPv6 address of the guest, sent by the host. The kernel `ip=` argument only covers IPv4.
+#[derive(serde::Deserialize)]
+pub struct Ipv6Config {
+    address: String,
+    prefix_len: u8,
+    gateway: String,
+}
+
+/// Adds the IPv6 address and default route to eth0.
+pub fn configure_ipv6(config: &Ipv6Config) -> anyhow::Result<()> {
+    log::debug!(
+        "Configuring {}/{} on eth0",
+        config.address,
+        config.prefix_len
+    );
+    // Nobody else uses the subnet, don't wait for duplicate address detection
+    try_cmd(&["sysctl", "-w", "net.ipv6.conf.eth0.accept_dad=0"])?;
+    try_cmd(&[
+        "ip",
+        "-6",
+        "addr",
+        "add",
+        &format!("{}/{}", config.address, config.prefix_len),
+        "dev",
+        "eth0",
+    ])?;
+    try_cmd(&[
+        "ip",
+        "-6",
+        "route",
+        "add",
+        "default",
+        "via",
+        &config.gateway,
+        "dev",
+        "eth0",
+    ])
+}
+
+/// An extra interface of the guest in a private network between instances.
+#[derive(serde::Deserialize)]
+pub struct PrivateNetworkConfig {
+    pub name: String,
+    interface: String,
+    address: String,
+    prefix_len: u8,
+    gateway: String,
+    subnet: String,
+}
+
+/// Brings up the interface of a private network, the other members are reached through its gateway.
+pub fn configure_private_network(config: &PrivateNetworkConfig) -> anyhow::Result<()> {
+    log::debug!(
+        "Configuring {}/{} on {} for private network {}",
+        config.address,
+        config.prefix_len,
+        config.interface,
+        config.name
+    );
+    try_cmd(&[
+        "ip",
+        "addr",
+        "add",
+        &format!("{}/{}", config.address, config.prefix_len),
+        "dev",
+        &config.interface,
+    ])?;
+    try_cmd(&["ip", "link", "set", &config.interface, "up"])?;
+    try_cmd(&[
+        "ip",
+        "route",
+        "add",
+        &config.subnet,
+        "via",
+        &config.gateway,
+        "dev",
+        &config.interface,
+    ])
+}
+
+/// Creates the R/W filesystem on the scratch drive and mounts it in /mnt.
+pub fn mount_scratch(fs: &ScratchFilesystem) {
+    log::debug!("Creating {} FS in /dev/vdb", fs.fs_type);
+    let features = fs.features.join(",");
+    let mut args = vec!["-t", fs.fs_type.as_str()];
+    if !features.is_empty() {
+        args.extend(["-O", features.as_str()]);
+    }
+    args.push("/dev/vdb");
+    mke2fs(&args);
+
+    log::debug!("Mounting /dev/vdb");
+    let mount_options = fs.mount_options.join(",");
+    let mut args = vec!["mount"];
+    if !mount_options.is_empty() {
+        args.extend(["-o", mount_options.as_str()]);
+    }
+    args.extend(["/dev/vdb", "/mnt"]);
+    cmd(&args);
+}
+
+/// Mounts a volume attached by the host, to be bind mounted into the container from the returned path.
+pub fn mount_volume(device: &str, name: &str, read_only: bool) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(VOLUMES_PATH).join(name);
+    std::fs::create_dir_all(&path)?;
+    log::debug!("Mounting volume {} from {}", name, device);
+    try_cmd(&[
+        "mount",
+        "-o",
+        if read_only { "ro" } else { "rw" },
+        device,
+        path.to_str().unwrap(),
+    ])?;
+    Ok(path)
+}
@@ -16,10 +16,13 @@ use vmproto::{
 };
 
 mod containers;
+mod exec;
 mod host;
 mod init;
 mod mmds;
 mod sh;
+mod stats;
+mod terminal;
 
 fn main() {
     simple_logger::init_with_level(if cfg!(debug_assertions) {
@@ -47,12 +50,39 @@ fn main() {
         cmd_args: Option<Vec<String>>,
         env: Option<BTreeMap<String, String>>,
         vsock_port: u32,
+        #[serde(default)]
+        terminal: bool,
+        #[serde(default)]
+        stdin: bool,
+        #[serde(default)]
+        image_drive: Option<String>,
+        #[serde(default)]
+        registry_auth: Option<containers::registry::RegistryAuth>,
+        #[serde(default)]
+        volumes: Vec<VolumeConfig>,
+        #[serde(default)]
+        scratch_filesystem: init::ScratchFilesystem,
+        #[serde(default)]
+        ipv6: Option<init::Ipv6Config>,
+        #[serde(default)]
+        private_networks: Vec<init::PrivateNetworkConfig>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct VolumeConfig {
+        name: String,
+        device: String,
+        mount_path: String,
+        #[serde(default)]
+        read_only: bool,
     }
 
     let config: Config = mmds
         .get("/latest/container")
         .expect("Unable to get container config");
 
+    init::mount_scratch(&config.scratch_filesystem);
+
     let comm = Arc::new(Mutex::new(
         host::HostCommunication::new(config.vsock_port as u32)
             .expect("Unable to connect to host communication channel"),
@@ -63,17 +93,54 @@ fn main() {
         Some(format!("Instance v. {}", env!("CARGO_PKG_VERSION"))),
     );
 
+    // The container can still use IPv4 without it
+    if let Some(ipv6) = &config.ipv6 {
+        if let Err(e) = init::configure_ipv6(ipv6) {
+            log::error!("Unable to configure IPv6: {:?}", e);
+            comm.lock()
+                .unwrap()
+                .log_system_message(format!("Unable to configure IPv6: {}", e));
+        }
+    }
+
+    for network in &config.private_networks {
+        if let Err(e) = init::configure_private_network(network) {
+            log::error!("Unable to join private network {}: {:?}", network.name, e);
+            comm.lock().unwrap().log_system_message(format!(
+                "Unable to join private network {}: {}",
+                network.name, e
+            ));
+        }
+    }
+
     let (exit_tx, exit_rx) = std::sync::mpsc::channel();
 
     let container_running = Arc::new(Mutex::new(false));
-    let mut read_stream = comm
-        .lock()
-        .unwrap()
-        .clone_stream()
-        .expect("Failed to clone stream");
+    let container_stdin = Arc::new(Mutex::new(terminal::ContainerStdin::default()));
+    let (mut read_stream, mut read_generation) = {
+        let mut comm = comm.lock().unwrap();
+        (
+            comm.clone_stream().expect("Failed to clone stream"),
+            comm.generation(),
+        )
+    };
     let host_requested_shutdown_tx = exit_tx.clone();
+    let reader_comm = comm.clone();
+    let exec_container_running = container_running.clone();
+    let host_stdin = container_stdin.clone();
     std::thread::spawn(move || loop {
-        let packet = read_packet(&mut read_stream).expect("Failed to read packet from host");
+        let packet = match read_packet(&mut read_stream) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!("Unable to read packet from host ({:?}), reconnecting", e);
+                (read_stream, read_generation) = reader_comm
+                    .lock()
+                    .unwrap()
+                    .reconnect_read_stream(read_generation)
+                    .expect("Unable to reconnect to host");
+                continue;
+            }
+        };
         match packet {
             HostPacket::Shutdown => {
                 log::info!("Received shutdown command from host");
@@ -81,6 +148,15 @@ fn main() {
                     .send(GuestExitCode::GracefulShutdown)
                     .expect("Failed to send shutdown exit code");
             }
+            HostPacket::Exec(request) => {
+                log::info!("Received exec request {} from host", request.exec_id);
+                exec::spawn_exec(reader_comm.clone(), request, exec_container_running.clone());
+            }
+            HostPacket::Stdin(data) => host_stdin.lock().unwrap().write(&data),
+            HostPacket::CloseStdin => host_stdin.lock().unwrap().close(),
+            HostPacket::ResizeTerminal((rows, cols)) => {
+                host_stdin.lock().unwrap().resize(rows, cols)
+            }
         }
     });
 
@@ -97,13 +173,40 @@ fn main() {
         }
     };
 
+    let mut bind_mounts = Vec::with_capacity(config.volumes.len());
+    for volume in config.volumes {
+        match init::mount_volume(&volume.device, &volume.name, volume.read_only) {
+            Ok(source) => bind_mounts.push(crate::containers::rt::BindMount {
+                source,
+                destination: volume.mount_path,
+                read_only: volume.read_only,
+            }),
+            Err(e) => {
+                log::error!("Unable to mount volume {}: {:?}", volume.name, e);
+                comm.lock().unwrap().exit(
+                    GuestExitCode::FailedToMountVolume,
+                    Some(format!("Unable to mount volume {}: {}", volume.name, e)),
+                );
+                shutdown();
+                return;
+            }
+        }
+    }
+
     let rt_overrides = crate::containers::rt::RuntimeOverrides {
         additional_args: config.cmd_args,
         additional_env: config.env,
-        terminal: false,
+        terminal: config.terminal,
+        bind_mounts,
     };
 
-    if let Err(r) = containers::pull_and_prepare_image(reference, &rt_overrides, comm.clone()) {
+    if let Err(r) = containers::pull_and_prepare_image(
+        reference,
+        &rt_overrides,
+        config.registry_auth.as_ref(),
+        config.image_drive.as_deref(),
+        comm.clone(),
+    ) {
         log::error!("Unable to pull and extract container image: {:?}", r);
         comm.lock().unwrap().exit(
             GuestExitCode::FailedToPullContainerImage,
@@ -126,16 +229,40 @@ fn main() {
 
     *container_running.lock().unwrap() = true;
 
-    let mut out = Command::new("/bin/crun")
-        .arg("run")
+    let console_listener = if config.terminal {
+        let _ = std::fs::remove_file(terminal::CONSOLE_SOCKET_PATH);
+        Some(
+            std::os::unix::net::UnixListener::bind(terminal::CONSOLE_SOCKET_PATH)
+                .expect("Failed to bind console socket"),
+        )
+    } else {
+        None
+    };
+
+    let mut run_cmd = Command::new("/bin/crun");
+    run_cmd.arg("run");
+    if console_listener.is_some() {
+        run_cmd
+            .arg("--console-socket")
+            .arg(terminal::CONSOLE_SOCKET_PATH);
+    }
+    let mut out = run_cmd
         .arg("container")
         .current_dir("/mnt")
-        .stdin(std::process::Stdio::null())
+        .stdin(if config.stdin && !config.terminal {
+            std::process::Stdio::piped()
+        } else {
+            std::process::Stdio::null()
+        })
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
         .spawn()
         .expect("Failed to spawn container");
 
+    if let Some(stdin) = out.stdin.take() {
+        container_stdin.lock().unwrap().set_pipe(Box::new(stdin));
+    }
+
     let stdout = out.stdout.take().expect("Failed to get stdout");
     let stderr = out.stderr.take().expect("Failed to get stderr");
 
@@ -144,10 +271,31 @@ fn main() {
     let stderr_thread =
         host::spawn_pipe_to_log(comm.clone(), Box::new(stderr), LogMessageType::Stderr);
 
+    let terminal_thread =
+        console_listener.and_then(
+            |listener| match terminal::receive_terminal_master(&listener) {
+                Ok(master) => {
+                    let reader = master.try_clone().expect("Failed to clone terminal");
+                    container_stdin.lock().unwrap().set_terminal(master);
+                    Some(terminal::spawn_terminal_to_host(comm.clone(), reader))
+                }
+                Err(e) => {
+                    comm.lock()
+                        .unwrap()
+                        .log_system_message(format!("Unable to set up container terminal: {}", e));
+                    None
+                }
+            },
+        );
+
+    stats::spawn_reporter(comm.clone(), container_running.clone());
+
     let container_exit_tx = exit_tx.clone();
     let comm_clone = comm.clone();
+    let container_running_clone = container_running.clone();
     std::thread::spawn(move || {
         let res = out.wait().expect("Failed to wait for container process");
+        *container_running_clone.lock().unwrap() = false;
         comm_clone.lock().unwrap().log_system_message(format!(
             "Container exited with code: {}",
             res.code()
@@ -186,6 +334,9 @@ fn main() {
     }
     let _ = stdout_thread.join();
     let _ = stderr_thread.join();
+    if let Some(terminal_thread) = terminal_thread {
+        let _ = terminal_thread.join();
+    }
 
     comm.lock().unwrap().exit(res, None);
 
@@ -1,21 +1,25 @@
 pub fn cmd(cmd: &[&str]) {
+    if let Err(e) = try_cmd(cmd) {
+        panic!("{}", e);
+    }
+}
+
+pub fn try_cmd(cmd: &[&str]) -> anyhow::Result<()> {
     let output = std::process::Command::new("/sbin/busybox")
         .args(cmd)
         .stderr(std::process::Stdio::null())
         .stdout(std::process::Stdio::null())
         .stdin(std::process::Stdio::null())
-        .spawn()
-        .expect("Failed to start command");
+        .spawn()?;
 
     // Wait for the command to finish
-    let output = output
-        .wait_with_output()
-        .expect("Failed to wait on command");
+    let output = output.wait_with_output()?;
 
     if !output.status.success() {
         log::error!("Command {:?} failed with status: {}", cmd, output.status);
-        panic!("Command failed: {:?}", cmd);
+        anyhow::bail!("Command failed: {:?}", cmd);
     } else {
         log::debug!("Command succeeded: {}", output.status);
     }
+    Ok(())
 }
@@ -0,0 +1,150 @@
+//! Resource usage of the container, read from its cgroup, the scratch filesystem and the network devices of the VM.
+
+use std::{
+    ffi::CString,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use vmproto::guest::{get_timestamp_ms, GuestPacket, ResourceUsage};
+
+use crate::host::HostCommunication;
+
+/// Relative to the cgroup2 mount, set as the cgroups path of the container.
+pub const CONTAINER_CGROUP: &str = "/container";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const SCRATCH_PATH: &str = "/mnt";
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Sends the resource usage to the host until the container stops running.
+pub fn spawn_reporter(comm: Arc<Mutex<HostCommunication>>, container_running: Arc<Mutex<bool>>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(REPORT_INTERVAL);
+        if !*container_running.lock().unwrap() {
+            return;
+        }
+        let usage = read_usage();
+        if let Err(e) = comm.lock().unwrap().write(GuestPacket::Metrics(usage)) {
+            log::warn!("Unable to send resource usage: {:?}", e);
+        }
+    });
+}
+
+/// Missing stats are left at zero, e.g. for controllers which aren't enabled.
+pub fn read_usage() -> ResourceUsage {
+    let cgroup = format!("{}{}", CGROUP_ROOT, CONTAINER_CGROUP);
+    let read = |file: &str| std::fs::read_to_string(format!("{}/{}", cgroup, file)).ok();
+
+    let mut usage = ResourceUsage {
+        timestamp_ms: get_timestamp_ms(),
+        ..Default::default()
+    };
+    if let Some(cpu_stat) = read("cpu.stat") {
+        usage.cpu_usage_usec = keyed_value(&cpu_stat, "usage_usec").unwrap_or_default();
+        usage.cpu_user_usec = keyed_value(&cpu_stat, "user_usec").unwrap_or_default();
+        usage.cpu_system_usec = keyed_value(&cpu_stat, "system_usec").unwrap_or_default();
+    }
+    usage.memory_bytes = read("memory.current")
+        .and_then(|current| current.trim().parse().ok())
+        .unwrap_or_default();
+    // "max" without a limit
+    usage.memory_limit_bytes = read("memory.max").and_then(|max| max.trim().parse().ok());
+    if let Some(io_stat) = read("io.stat") {
+        (usage.disk_read_bytes, usage.disk_write_bytes) = io_bytes(&io_stat);
+    }
+    if let Some((used, total)) = filesystem_usage(SCRATCH_PATH) {
+        usage.disk_used_bytes = used;
+        usage.disk_total_bytes = total;
+    }
+    if let Ok(net_dev) = std::fs::read_to_string("/proc/net/dev") {
+        (
+            usage.net_rx_bytes,
+            usage.net_rx_packets,
+            usage.net_tx_bytes,
+            usage.net_tx_packets,
+        ) = network_totals(&net_dev);
+    }
+    usage
+}
+
+/// The value of a `key value` line, like in `cpu.stat`.
+fn keyed_value(content: &str, key: &str) -> Option<u64> {
+    content.lines().find_map(|line| {
+        let (k, v) = line.split_once(' ')?;
+        (k == key).then(|| v.trim().parse().ok())?
+    })
+}
+
+/// Bytes read and written over all devices in `io.stat`, lines like `254:0 rbytes=1 wbytes=2 rios=3 ...`.
+fn io_bytes(io_stat: &str) -> (u64, u64) {
+    let (mut read, mut written) = (0, 0);
+    for field in io_stat.split_whitespace() {
+        match field.split_once('=') {
+            Some(("rbytes", v)) => read += v.parse::<u64>().unwrap_or_default(),
+            Some(("wbytes", v)) => written += v.parse::<u64>().unwrap_or_default(),
+            _ => {}
+        }
+    }
+    (read, written)
+}
+
+/// Received bytes and packets, then transmitted bytes and packets, of all interfaces besides the loopback.
+fn network_totals(net_dev: &str) -> (u64, u64, u64, u64) {
+    let mut totals = (0, 0, 0, 0);
+    // Two header lines, then `iface: rx_bytes rx_packets ... (8 rx fields) tx_bytes tx_packets ...`
+    for line in net_dev.lines().skip(2) {
+        let Some((iface, counters)) = line.split_once(':') else {
+            continue;
+        };
+        if iface.trim() == "lo" {
+            continue;
+        }
+        let counters = counters
+            .split_whitespace()
+            .map(|v| v.parse::<u64>().unwrap_or_default())
+            .collect::<Vec<_>>();
+        if counters.len() < 10 {
+            continue;
+        }
+        totals.0 += counters[0];
+        totals.1 += counters[1];
+        totals.2 += counters[8];
+        totals.3 += counters[9];
+    }
+    totals
+}
+
+/// Used and total bytes of the filesystem at `path`.
+fn filesystem_usage(path: &str) -> Option<(u64, u64)> {
+    let c_path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } < 0 {
+        return None;
+    }
+    let total = stat.f_blocks as u64 * stat.f_frsize as u64;
+    let free = stat.f_bfree as u64 * stat.f_frsize as u64;
+    Some((total - free, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stat_files() {
+        let cpu_stat = "usage_usec 1500\nuser_usec 1000\nsystem_usec 500\nnr_periods 0\n";
+        assert_eq!(keyed_value(cpu_stat, "usage_usec"), Some(1500));
+        assert_eq!(keyed_value(cpu_stat, "system_usec"), Some(500));
+        assert_eq!(keyed_value(cpu_stat, "usage"), None);
+
+        let io_stat = "254:16 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+                       254:32 rbytes=100 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n";
+        assert_eq!(io_bytes(io_stat), (4196, 8192));
+
+        let net_dev = "Inter-|   Receive                                                |  Transmit\n \
+            face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n    \
+            lo:     100       2    0    0    0     0          0         0      100       2    0    0    0     0       0          0\n  \
+            eth0:    5000      40    0    0    0     0          0         0     3000      30    0    0    0     0       0          0\n";
+        assert_eq!(network_totals(net_dev), (5000, 40, 3000, 30));
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::net::UnixListener,
+    },
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use vmproto::guest::GuestPacket;
+
+use crate::host::HostCommunication;
+
+pub const CONSOLE_SOCKET_PATH: &str = "/run/console.sock";
+
+const TERMINAL_CHUNK_SIZE: usize = 4 * 1024;
+const CONSOLE_SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the host provided stdin of the container ends up.
+/// Either the write end of a stdin pipe or the master side of the container terminal.
+#[derive(Default)]
+pub struct ContainerStdin {
+    pipe: Option<Box<dyn Write + Send>>,
+    terminal: Option<File>,
+}
+
+impl ContainerStdin {
+    pub fn set_pipe(&mut self, pipe: Box<dyn Write + Send>) {
+        self.pipe = Some(pipe);
+    }
+
+    pub fn set_terminal(&mut self, terminal: File) {
+        self.terminal = Some(terminal);
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        let res = match (&mut self.terminal, &mut self.pipe) {
+            (Some(terminal), _) => terminal.write_all(data),
+            (None, Some(pipe)) => pipe.write_all(data).and_then(|_| pipe.flush()),
+            (None, None) => {
+                log::debug!(
+                    "Dropping {} bytes of stdin, container stdin not open",
+                    data.len()
+                );
+                return;
+            }
+        };
+        if let Err(e) = res {
+            log::debug!("Unable to write to container stdin: {}", e);
+        }
+    }
+
+    /// Closes the stdin pipe. A terminal is kept open since its output is still read.
+    pub fn close(&mut self) {
+        self.pipe = None;
+    }
+
+    pub fn resize(&self, rows: u16, cols: u16) {
+        let terminal = match &self.terminal {
+            Some(terminal) => terminal,
+            None => {
+                log::debug!("Ignoring terminal resize, container has no terminal");
+                return;
+            }
+        };
+        let size = libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let r = unsafe { libc::ioctl(terminal.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+        if r < 0 {
+            log::debug!(
+                "Unable to resize terminal: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[repr(C, align(8))]
+struct ControlMessageBuffer([u8; 64]);
+
+/// Accepts a connection on the crun `--console-socket` and receives the terminal master fd.
+/// Gives up after a timeout so a crun failing before connecting does not hang the init.
+pub fn receive_terminal_master(listener: &UnixListener) -> std::io::Result<File> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + CONSOLE_SOCKET_TIMEOUT;
+    let stream = loop {
+        match listener.accept() {
+            Ok((stream, _)) => break stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+
+    // crun sends the terminal name as payload and the master fd as SCM_RIGHTS
+    let mut data = [0u8; 256];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut control = ControlMessageBuffer([0; 64]);
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.0.len() as _;
+
+    let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if cmsg.is_null()
+        || unsafe { (*cmsg).cmsg_level } != libc::SOL_SOCKET
+        || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "No terminal fd received on console socket",
+        ));
+    }
+    let fd: RawFd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub fn spawn_terminal_to_host(
+    comm: Arc<Mutex<HostCommunication>>,
+    mut terminal: File,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0; TERMINAL_CHUNK_SIZE];
+        loop {
+            match terminal.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    let packet = GuestPacket::TerminalOutput(buf[..n].to_vec());
+                    if comm.lock().unwrap().write(packet).is_err() {
+                        log::error!("Unable to forward terminal output to host");
+                        return;
+                    }
+                }
+                Err(e) => {
+                    // EIO once the last process holding the terminal exits
+                    log::debug!("Terminal closed: {}", e);
+                    return;
+                }
+            }
+        }
+    })
+}
@@ -1,17 +1,52 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::io::Write;
 
 use chrono::DateTime;
 use log::error;
 use log::info;
+use proto::node::AttachInput;
+use proto::node::BalloonStats;
+use proto::node::BasicAuth;
+use proto::node::CreateVolumeRequest;
 use proto::node::DeprovisionRequest;
+use proto::node::DrainRequest;
 use proto::node::Empty;
+use proto::node::ExecRequest;
+use proto::node::InstanceEvent;
 use proto::node::InstanceId;
+use proto::node::InstanceInfo;
+use proto::node::InstanceStats;
+use proto::node::IoLimits;
+use proto::node::ListInstancesRequest;
+use proto::node::LogMessage;
+use proto::node::PatchInstanceLimitsRequest;
+use proto::node::Protocol;
 use proto::node::ProvisionRequest;
+use proto::node::RateLimiter;
+use proto::node::RegistryCredentials;
+use proto::node::RestartPolicy;
+use proto::node::RestoreInstanceRequest;
+use proto::node::SetBalloonRequest;
+use proto::node::SnapshotId;
+use proto::node::SnapshotInstanceRequest;
+use proto::node::TerminalSize;
+use proto::node::TokenBucket;
+use proto::node::UnpublishServicePortRequest;
+use proto::node::VolumeMount;
+use proto::node::VolumeName;
+use proto::node::WatchEventsRequest;
+use proto::node::instance_event::Event;
 use proto::node::node_manager_client::NodeManagerClient;
+use proto::node::registry_credentials::Credentials;
+use proto::node::restart_policy::Mode as RestartMode;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use proto::node::PublishServicePortRequest;
+use proto::node::PublishedPort;
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_stream::wrappers::ReceiverStream;
 
 #[derive(Debug, Parser)]
 #[command(name = "nodecli")]
@@ -25,6 +60,7 @@ struct Cli {
 }
 
 #[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)] // Parsed once, the size doesn't matter
 enum Commands {
     #[command(arg_required_else_help = true)]
     Run {
@@ -33,6 +69,11 @@ enum Commands {
         vcpus: u8,
         #[arg(long, default_value_t = 1024, help = "Memory in MB")]
         memory_mb: u32,
+        #[arg(
+            long,
+            help = "Size of the scratch disk in GB, the node default if unset"
+        )]
+        disk_gb: Option<u64>,
 
         #[arg(
             long,
@@ -41,9 +82,76 @@ enum Commands {
         )]
         dont_tail_logs: bool,
 
+        #[arg(
+            short,
+            long,
+            default_value_t = false,
+            help = "Keep stdin open and attach to the container"
+        )]
+        interactive: bool,
+
+        #[arg(
+            short,
+            long,
+            default_value_t = false,
+            help = "Allocate a terminal for the container"
+        )]
+        tty: bool,
+
         #[arg(short, long, help = "Environment variables to set in the container")]
         environment: Option<Vec<String>>,
 
+        #[arg(
+            long = "label",
+            help = "Labels to attach to the instance, as KEY=VALUE"
+        )]
+        labels: Option<Vec<String>>,
+
+        #[arg(
+            long,
+            value_parser = parse_restart_policy,
+            help = "Restart the container once it exits: never, on-failure[:max-retries] or always"
+        )]
+        restart: Option<RestartPolicy>,
+
+        #[arg(
+            short,
+            long = "volume",
+            value_parser = parse_volume_mount,
+            help = "Volumes to mount in the container, as NAME:PATH[:ro]"
+        )]
+        volumes: Vec<VolumeMount>,
+
+        #[arg(
+            long = "network",
+            help = "Private networks of the node to join, attached as eth1, eth2, ..."
+        )]
+        networks: Vec<String>,
+
+        #[arg(
+            long,
+            requires = "registry_password",
+            help = "Username for pulling from a private registry"
+        )]
+        registry_username: Option<String>,
+        #[arg(long, requires = "registry_username")]
+        registry_password: Option<String>,
+        #[arg(
+            long,
+            conflicts_with_all = ["registry_username", "registry_credentials"],
+            help = "Bearer token for pulling from a private registry"
+        )]
+        registry_token: Option<String>,
+        #[arg(
+            long,
+            conflicts_with = "registry_username",
+            help = "Name of registry credentials configured on the node"
+        )]
+        registry_credentials: Option<String>,
+
+        #[command(flatten)]
+        io_limits: IoLimitArgs,
+
         #[arg(
             trailing_var_arg = true,
             allow_hyphen_values = true,
@@ -57,7 +165,24 @@ enum Commands {
         instance_id: String,
     },
     #[command(arg_required_else_help = false)]
-    Ls,
+    Ls {
+        #[arg(
+            short = 'l',
+            long = "selector",
+            help = "Only list instances matching the label selector, e.g. env=prod,tier in (web,api)"
+        )]
+        selector: Vec<String>,
+    },
+    #[command(arg_required_else_help = true)]
+    Inspect {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+    },
+    #[command(arg_required_else_help = true)]
+    Stats {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+    },
     Logs {
         #[arg(help = "Instance UUID")]
         instance_id: String,
@@ -69,12 +194,200 @@ enum Commands {
         #[arg(help = "Instance UUID")]
         instance_id: String,
 
-        #[arg(help = "Port to publish on the host")]
+        #[arg(help = "Port to publish on the host, 0 to let the node pick a free one")]
         host_port: u16,
         #[arg(help = "Port to route to in the container")]
         guest_port: u16,
+
+        #[arg(
+            long,
+            default_value = "tcp",
+            value_parser = parse_protocol,
+            help = "Protocol to forward: tcp, udp or sctp"
+        )]
+        protocol: Protocol,
+
+        #[arg(
+            long,
+            help = "Publish a range of this many ports, starting at the given ones"
+        )]
+        count: Option<u16>,
+
+        #[arg(long, help = "Only forward traffic to this address of the host")]
+        host_ip: Option<String>,
+    },
+    Unpub {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+
+        #[arg(help = "Published host port, the first one of a range")]
+        host_port: u16,
+
+        #[arg(
+            long,
+            default_value = "tcp",
+            value_parser = parse_protocol,
+            help = "Protocol of the published port: tcp, udp or sctp"
+        )]
+        protocol: Protocol,
+
+        #[arg(long, help = "Host address the port was published on")]
+        host_ip: Option<String>,
     },
-    Drain,
+    Ports {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+    },
+    #[command(arg_required_else_help = true)]
+    Attach {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+
+        #[arg(
+            short,
+            long,
+            default_value_t = false,
+            help = "The container runs with a terminal, puts the local terminal in raw mode"
+        )]
+        tty: bool,
+    },
+    #[command(arg_required_else_help = true)]
+    Exec {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            required = true,
+            help = "Command to execute in the container"
+        )]
+        args: Vec<String>,
+    },
+    #[command(arg_required_else_help = true)]
+    Snapshot {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Remove the instance after taking the snapshot"
+        )]
+        stop: bool,
+    },
+    #[command(arg_required_else_help = true)]
+    Restore {
+        #[arg(help = "Snapshot UUID")]
+        snapshot_id: String,
+    },
+    #[command(about = "List the snapshots on the node")]
+    Snapshots,
+    #[command(arg_required_else_help = true)]
+    RmSnapshot {
+        #[arg(help = "Snapshot UUID")]
+        snapshot_id: String,
+    },
+    Drain {
+        #[arg(
+            short = 'l',
+            long = "selector",
+            help = "Only shut down instances matching the label selector"
+        )]
+        selector: Vec<String>,
+    },
+    Events {
+        #[arg(long, help = "Replay the buffered events after this revision")]
+        after_revision: Option<u64>,
+    },
+    #[command(
+        arg_required_else_help = true,
+        about = "Change the I/O limits of a running instance, prints the limits in effect"
+    )]
+    Limits {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+
+        #[command(flatten)]
+        io_limits: IoLimitArgs,
+    },
+    #[command(
+        arg_required_else_help = true,
+        about = "Take memory back from a running instance, 0 gives all of it back"
+    )]
+    Balloon {
+        #[arg(help = "Instance UUID")]
+        instance_id: String,
+        #[arg(help = "Memory in MB to take from the guest")]
+        target_mb: u32,
+    },
+    #[command(subcommand)]
+    Volume(VolumeCommands),
+    #[command(about = "Show the resources of the node and how much of them is allocated")]
+    Capacity,
+}
+
+#[derive(Debug, Args)]
+struct IoLimitArgs {
+    #[arg(
+        long,
+        help = "Limit the scratch disk and each volume to bytes per second"
+    )]
+    disk_bps: Option<u64>,
+    #[arg(
+        long,
+        help = "Limit the scratch disk and each volume to requests per second"
+    )]
+    disk_iops: Option<u64>,
+    #[arg(long, help = "Limit received network traffic to bytes per second")]
+    net_rx_bps: Option<u64>,
+    #[arg(long, help = "Limit received network traffic to packets per second")]
+    net_rx_pps: Option<u64>,
+    #[arg(long, help = "Limit sent network traffic to bytes per second")]
+    net_tx_bps: Option<u64>,
+    #[arg(long, help = "Limit sent network traffic to packets per second")]
+    net_tx_pps: Option<u64>,
+}
+
+impl IoLimitArgs {
+    /// Only the limiters with a flag given are set, a rate of 0 leaves that bucket out.
+    fn io_limits(&self) -> Option<IoLimits> {
+        let limiter = |bytes: Option<u64>, ops: Option<u64>| {
+            (bytes.is_some() || ops.is_some()).then(|| RateLimiter {
+                bandwidth: per_second(bytes),
+                ops: per_second(ops),
+            })
+        };
+        let limits = IoLimits {
+            drive: limiter(self.disk_bps, self.disk_iops),
+            network_rx: limiter(self.net_rx_bps, self.net_rx_pps),
+            network_tx: limiter(self.net_tx_bps, self.net_tx_pps),
+        };
+        (limits != IoLimits::default()).then_some(limits)
+    }
+}
+
+fn per_second(rate: Option<u64>) -> Option<TokenBucket> {
+    rate.filter(|rate| *rate > 0).map(|rate| TokenBucket {
+        size: rate,
+        refill_time_ms: 1000,
+        one_time_burst: None,
+    })
+}
+
+#[derive(Debug, Subcommand)]
+enum VolumeCommands {
+    #[command(arg_required_else_help = true)]
+    Create {
+        name: String,
+        #[arg(long, default_value_t = 1024, help = "Size in MB")]
+        size_mb: u64,
+    },
+    #[command(arg_required_else_help = true)]
+    Rm {
+        name: String,
+    },
+    Ls,
 }
 
 #[tokio::main]
@@ -95,8 +408,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             container_reference,
             vcpus,
             memory_mb,
+            disk_gb,
             dont_tail_logs,
+            interactive,
+            tty,
             environment,
+            labels,
+            restart,
+            volumes,
+            networks,
+            registry_username,
+            registry_password,
+            registry_token,
+            registry_credentials,
+            io_limits,
             args,
         } => {
             let mut parsed_env =
@@ -113,12 +438,44 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
 
+            let mut parsed_labels = HashMap::with_capacity(labels.as_ref().map_or(0, |v| v.len()));
+            for label in labels.unwrap_or_default() {
+                if let Some((key, value)) = label.split_once('=') {
+                    parsed_labels.insert(key.to_string(), value.to_string());
+                } else {
+                    error!("Invalid label format: {}, expected \"KEY=VALUE\".", label);
+                }
+            }
+
             let request = tonic::Request::new(ProvisionRequest {
                 container_reference,
                 vcpus: vcpus as i32,
                 memory_mb: memory_mb as i32,
+                scratch_disk_gb: disk_gb,
                 env: parsed_env,
                 cmd_args: args,
+                labels: parsed_labels,
+                restart_policy: restart,
+                volumes,
+                private_networks: networks,
+                io_limits: io_limits.io_limits(),
+                terminal: tty,
+                stdin: interactive,
+                registry_credentials: match (
+                    registry_username.zip(registry_password),
+                    registry_token,
+                    registry_credentials,
+                ) {
+                    (Some((username, password)), _, _) => {
+                        Some(Credentials::Basic(BasicAuth { username, password }))
+                    }
+                    (_, Some(token), _) => Some(Credentials::BearerToken(token)),
+                    (_, _, Some(name)) => Some(Credentials::Named(name)),
+                    _ => None,
+                }
+                .map(|credentials| RegistryCredentials {
+                    credentials: Some(credentials),
+                }),
             });
 
             let response = client.provision(request).await;
@@ -126,7 +483,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Ok(res) => {
                     let instance_id = res.into_inner().id;
                     info!("Provisioned instance with id {}", instance_id);
-                    if !dont_tail_logs {
+                    if interactive || tty {
+                        attach(&mut client, instance_id, tty).await;
+                    } else if !dont_tail_logs {
                         stream_logs(&mut client, instance_id).await;
                     }
                 }
@@ -144,8 +503,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Err(e) => error!("Failed to deprovision instance: {}", e),
             }
         }
-        Commands::Ls => {
-            let request = tonic::Request::new(Empty {});
+        Commands::Ls { selector } => {
+            let request = tonic::Request::new(ListInstancesRequest {
+                label_selector: selector.join(","),
+            });
             let response = client.list_instances(request).await;
             match response {
                 Ok(res) => {
@@ -157,6 +518,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Err(e) => error!("Failed to list instances: {}", e),
             }
         }
+        Commands::Inspect { instance_id } => {
+            let request = tonic::Request::new(InstanceId {
+                id: instance_id.clone(),
+            });
+            match client.inspect_instance(request).await {
+                Ok(res) => print_instance_info(res.into_inner()),
+                Err(e) => error!("Failed to inspect instance {}: {}", instance_id, e),
+            }
+        }
+        Commands::Stats { instance_id } => {
+            let request = tonic::Request::new(InstanceId {
+                id: instance_id.clone(),
+            });
+            match client.get_instance_stats(request).await {
+                Ok(res) => print_instance_stats(res.into_inner()),
+                Err(e) => error!("Failed to get stats of instance {}: {}", instance_id, e),
+            }
+        }
         Commands::Logs { instance_id, tail } => {
             if tail {
                 stream_logs(&mut client, instance_id.clone()).await;
@@ -169,18 +548,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .map(|response| {
                         let logs = response.into_inner().logs;
                         for log in logs {
-                            let ts = DateTime::from_timestamp(
-                                log.timestamp / 1000,
-                                (log.timestamp as u32 % 1000) * 1_000_000,
-                            )
-                            .unwrap()
-                            .with_timezone(&chrono::Local);
-                            println!(
-                                "[{}] {} - {}",
-                                log.log_type,
-                                ts.format("%Y-%m-%d %H:%M:%S%.3f"),
-                                log.message
-                            );
+                            print_log(log);
                         }
                     })
                     .map_err(|e| error!("Failed to get logs for instance {}: {}", instance_id, e))
@@ -191,28 +559,269 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             instance_id,
             guest_port,
             host_port,
+            protocol,
+            count,
+            host_ip,
         } => {
             let request = tonic::Request::new(PublishServicePortRequest {
                 id: instance_id.clone(),
                 guest_port: guest_port as i32,
                 host_port: host_port as i32,
+                protocol: protocol as i32,
+                port_count: count.map(|count| count as i32),
+                host_ip,
             });
             let response = client.publish_service_port(request).await;
             match response {
-                Ok(_) => info!(
-                    "Published port {} on instance {} to host port {}",
-                    guest_port, instance_id, host_port
+                Ok(response) => info!(
+                    "Published port {} on instance {} as {}",
+                    guest_port,
+                    instance_id,
+                    format_published_port(&response.into_inner())
                 ),
                 Err(e) => error!("Failed to publish port: {}", e),
             }
         }
-        Commands::Drain => {
-            if let Err(e) = client.drain(tonic::Request::new(Empty {})).await {
+        Commands::Unpub {
+            instance_id,
+            host_port,
+            protocol,
+            host_ip,
+        } => {
+            let request = tonic::Request::new(UnpublishServicePortRequest {
+                id: instance_id.clone(),
+                host_port: host_port as i32,
+                protocol: protocol as i32,
+                host_ip,
+            });
+            match client.unpublish_service_port(request).await {
+                Ok(_) => info!(
+                    "Unpublished host port {} of instance {}",
+                    host_port, instance_id
+                ),
+                Err(e) => error!("Failed to unpublish port: {}", e),
+            }
+        }
+        Commands::Ports { instance_id } => {
+            let request = tonic::Request::new(InstanceId {
+                id: instance_id.clone(),
+            });
+            match client.list_published_ports(request).await {
+                Ok(response) => {
+                    for port in response.into_inner().ports {
+                        println!("{}", format_published_port(&port));
+                    }
+                }
+                Err(e) => error!(
+                    "Failed to list published ports of instance {}: {}",
+                    instance_id, e
+                ),
+            }
+        }
+        Commands::Attach { instance_id, tty } => {
+            attach(&mut client, instance_id, tty).await;
+        }
+        Commands::Exec { instance_id, args } => {
+            let request = tonic::Request::new(ExecRequest {
+                id: instance_id.clone(),
+                cmd_args: args,
+            });
+            let response = client.exec(request).await;
+            match response {
+                Ok(stream) => {
+                    let mut stream = stream.into_inner();
+                    loop {
+                        match stream.message().await {
+                            Ok(Some(output)) => match output.output_type.as_str() {
+                                "stdout" => {
+                                    let mut stdout = std::io::stdout();
+                                    stdout.write_all(&output.data.unwrap_or_default())?;
+                                    stdout.flush()?;
+                                }
+                                "stderr" => {
+                                    let mut stderr = std::io::stderr();
+                                    stderr.write_all(&output.data.unwrap_or_default())?;
+                                    stderr.flush()?;
+                                }
+                                "exit" => {
+                                    std::process::exit(output.exit_code.unwrap_or(1));
+                                }
+                                other => error!("Unknown exec output type: {}", other),
+                            },
+                            Ok(None) => {
+                                error!("Exec stream ended without an exit code");
+                                std::process::exit(1);
+                            }
+                            Err(e) => {
+                                error!("Error receiving exec output: {}", e);
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to exec in instance {}: {}", instance_id, e),
+            }
+        }
+        Commands::Snapshot { instance_id, stop } => {
+            let request = tonic::Request::new(SnapshotInstanceRequest {
+                id: instance_id.clone(),
+                stop,
+            });
+            match client.snapshot_instance(request).await {
+                Ok(res) => println!("{}", res.into_inner().id),
+                Err(e) => error!("Failed to snapshot instance {}: {}", instance_id, e),
+            }
+        }
+        Commands::Restore { snapshot_id } => {
+            let request = tonic::Request::new(RestoreInstanceRequest {
+                snapshot_id: snapshot_id.clone(),
+            });
+            match client.restore_instance(request).await {
+                Ok(res) => println!("{}", res.into_inner().id),
+                Err(e) => error!("Failed to restore snapshot {}: {}", snapshot_id, e),
+            }
+        }
+        Commands::Snapshots => match client.list_snapshots(tonic::Request::new(Empty {})).await {
+            Ok(res) => {
+                for snapshot in res.into_inner().snapshots {
+                    let created_at = DateTime::from_timestamp_millis(snapshot.created_at_ms)
+                        .filter(|_| snapshot.created_at_ms > 0)
+                        .map(|ts| {
+                            ts.with_timezone(&chrono::Local)
+                                .format("%Y-%m-%d %H:%M:%S")
+                                .to_string()
+                        })
+                        .unwrap_or("-".to_string());
+                    println!(
+                        "{}\t{}\t{}",
+                        snapshot.id, created_at, snapshot.container_reference
+                    );
+                }
+            }
+            Err(e) => error!("Failed to list snapshots: {}", e),
+        },
+        Commands::RmSnapshot { snapshot_id } => {
+            let request = tonic::Request::new(SnapshotId {
+                id: snapshot_id.clone(),
+            });
+            match client.delete_snapshot(request).await {
+                Ok(_) => info!("Deleted snapshot {}", snapshot_id),
+                Err(e) => error!("Failed to delete snapshot {}: {}", snapshot_id, e),
+            }
+        }
+        Commands::Drain { selector } => {
+            let request = tonic::Request::new(DrainRequest {
+                label_selector: selector.join(","),
+            });
+            if let Err(e) = client.drain(request).await {
                 error!("Failed to drain: {}", e);
             } else {
                 info!("Drained node!");
             }
         }
+        Commands::Events { after_revision } => {
+            let request = tonic::Request::new(WatchEventsRequest { after_revision });
+            match client.watch_events(request).await {
+                Ok(stream) => {
+                    let mut stream = stream.into_inner();
+                    loop {
+                        match stream.message().await {
+                            Ok(Some(event)) => print_event(event),
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Error receiving event: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to watch events: {}", e),
+            }
+        }
+        Commands::Limits {
+            instance_id,
+            io_limits,
+        } => {
+            let request = tonic::Request::new(PatchInstanceLimitsRequest {
+                id: instance_id.clone(),
+                io_limits: io_limits.io_limits(),
+            });
+            match client.patch_instance_limits(request).await {
+                Ok(res) => print_io_limits(&res.into_inner()),
+                Err(e) => error!("Failed to patch limits of instance {}: {}", instance_id, e),
+            }
+        }
+        Commands::Balloon {
+            instance_id,
+            target_mb,
+        } => {
+            let request = tonic::Request::new(SetBalloonRequest {
+                id: instance_id.clone(),
+                target_mb,
+            });
+            match client.set_instance_balloon(request).await {
+                Ok(res) => print_balloon_stats(&res.into_inner()),
+                Err(e) => error!("Failed to set balloon of instance {}: {}", instance_id, e),
+            }
+        }
+        Commands::Volume(VolumeCommands::Create { name, size_mb }) => {
+            let request = tonic::Request::new(CreateVolumeRequest {
+                name: name.clone(),
+                size_mb,
+            });
+            match client.create_volume(request).await {
+                Ok(_) => info!("Created volume {}", name),
+                Err(e) => error!("Failed to create volume {}: {}", name, e),
+            }
+        }
+        Commands::Volume(VolumeCommands::Rm { name }) => {
+            let request = tonic::Request::new(VolumeName { name: name.clone() });
+            match client.delete_volume(request).await {
+                Ok(_) => info!("Deleted volume {}", name),
+                Err(e) => error!("Failed to delete volume {}: {}", name, e),
+            }
+        }
+        Commands::Capacity => match client
+            .get_node_capacity(tonic::Request::new(Empty {}))
+            .await
+        {
+            Ok(res) => {
+                let capacity = res.into_inner();
+                let total = capacity.total.unwrap_or_default();
+                let allocated = capacity.allocated.unwrap_or_default();
+                let free = capacity.free.unwrap_or_default();
+                println!("Instances: {}", capacity.instances);
+                println!("{:<10}{:>10}{:>12}{:>10}", "", "TOTAL", "ALLOCATED", "FREE");
+                println!(
+                    "{:<10}{:>10}{:>12}{:>10}",
+                    "vCPUs", total.vcpus, allocated.vcpus, free.vcpus
+                );
+                println!(
+                    "{:<10}{:>10}{:>12}{:>10}",
+                    "Memory MB", total.memory_mb, allocated.memory_mb, free.memory_mb
+                );
+                println!(
+                    "{:<10}{:>10}{:>12}{:>10}",
+                    "Disk GB", total.disk_gb, allocated.disk_gb, free.disk_gb
+                );
+            }
+            Err(e) => error!("Failed to get node capacity: {}", e),
+        },
+        Commands::Volume(VolumeCommands::Ls) => {
+            match client.list_volumes(tonic::Request::new(Empty {})).await {
+                Ok(res) => {
+                    for volume in res.into_inner().volumes {
+                        println!(
+                            "{}\t{} MB\t{}",
+                            volume.name,
+                            volume.size_mb,
+                            volume.attached_to.as_deref().unwrap_or("-")
+                        );
+                    }
+                }
+                Err(e) => error!("Failed to list volumes: {}", e),
+            }
+        }
     }
     Ok(())
 }
@@ -232,18 +841,7 @@ async fn stream_logs(
                 let next = stream.message().await;
                 match next {
                     Ok(Some(log_message)) => {
-                        let ts = DateTime::from_timestamp(
-                            log_message.timestamp / 1000,
-                            (log_message.timestamp as u32 % 1000) * 1_000_000,
-                        )
-                        .unwrap()
-                        .with_timezone(&chrono::Local);
-                        println!(
-                            "[{}] {} - {}",
-                            log_message.log_type,
-                            ts.format("%Y-%m-%d %H:%M:%S%.3f"),
-                            log_message.message
-                        );
+                        print_log(log_message);
                     }
                     Ok(None) => {
                         info!("No more logs for instance {}", instance_id);
@@ -259,3 +857,432 @@ async fn stream_logs(
         Err(e) => error!("Failed to get logs for instance {}: {}", instance_id, e),
     }
 }
+
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn enable() -> Option<Self> {
+        crossterm::terminal::enable_raw_mode()
+            .ok()
+            .map(|_| RawModeGuard)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+fn terminal_size() -> Option<TerminalSize> {
+    crossterm::terminal::size()
+        .ok()
+        .map(|(cols, rows)| TerminalSize {
+            rows: rows as u32,
+            cols: cols as u32,
+        })
+}
+
+async fn attach(
+    client: &mut NodeManagerClient<tonic::transport::Channel>,
+    instance_id: String,
+    tty: bool,
+) {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let _ = tx
+        .send(AttachInput {
+            id: instance_id.clone(),
+            resize: if tty { terminal_size() } else { None },
+            ..Default::default()
+        })
+        .await;
+
+    let mut stream = match client.attach(ReceiverStream::new(rx)).await {
+        Ok(stream) => stream.into_inner(),
+        Err(e) => {
+            error!("Failed to attach to instance {}: {}", instance_id, e);
+            return;
+        }
+    };
+
+    let stdin_tx = tx.clone();
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let mut buf = [0u8; 1024];
+        loop {
+            let input = match stdin.read(&mut buf).await {
+                Ok(0) | Err(_) => AttachInput {
+                    close_stdin: true,
+                    ..Default::default()
+                },
+                Ok(n) => AttachInput {
+                    stdin: Some(buf[..n].to_vec()),
+                    ..Default::default()
+                },
+            };
+            let closed = input.close_stdin;
+            if stdin_tx.send(input).await.is_err() || closed {
+                return;
+            }
+        }
+    });
+    if tty {
+        let resize_tx = tx.clone();
+        tokio::spawn(async move {
+            let mut window_change = match signal(SignalKind::window_change()) {
+                Ok(window_change) => window_change,
+                Err(_) => return,
+            };
+            while window_change.recv().await.is_some() {
+                let input = AttachInput {
+                    resize: terminal_size(),
+                    ..Default::default()
+                };
+                if resize_tx.send(input).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let result = {
+        let _raw_mode = if tty { RawModeGuard::enable() } else { None };
+        loop {
+            match stream.message().await {
+                Ok(Some(output)) => {
+                    let written = if output.output_type == "stderr" {
+                        let mut stderr = std::io::stderr();
+                        stderr.write_all(&output.data).and_then(|_| stderr.flush())
+                    } else {
+                        let mut stdout = std::io::stdout();
+                        stdout.write_all(&output.data).and_then(|_| stdout.flush())
+                    };
+                    if written.is_err() {
+                        break Ok(());
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        }
+    };
+    match result {
+        Ok(()) => info!("Detached from instance {}", instance_id),
+        Err(e) => error!("Error receiving attach output: {}", e),
+    }
+    // The blocking stdin reader would otherwise keep the runtime alive until the next input
+    std::process::exit(0);
+}
+
+fn print_instance_info(info: InstanceInfo) {
+    let created_at = DateTime::from_timestamp_millis(info.created_at_ms)
+        .map(|ts| {
+            ts.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let uptime = info.uptime_ms / 1000;
+    let ports = info
+        .published_ports
+        .iter()
+        .map(format_published_port)
+        .collect::<Vec<_>>();
+
+    println!("ID:          {}", info.id);
+    println!("Image:       {}", info.container_reference);
+    if !info.image_digest.is_empty() {
+        println!("Digest:      {}", info.image_digest);
+    }
+    println!("vCPUs:       {}", info.vcpus);
+    println!("Memory:      {} MB", info.memory_mb);
+    println!("Disk:        {} GB", info.scratch_disk_gb);
+    println!("Terminal:    {}", info.terminal);
+    println!("Stdin:       {}", info.stdin);
+    println!("IP address:  {}", info.ip_address);
+    println!("Gateway:     {}", info.gateway);
+    if !info.ipv6_address.is_empty() {
+        println!("IPv6:        {}", info.ipv6_address);
+        println!("Gateway v6:  {}", info.ipv6_gateway);
+    }
+    println!("TAP device:  {}", info.tap_device);
+    for network in &info.private_networks {
+        println!(
+            "Network:     {} {} via {} ({})",
+            network.network, network.ip_address, network.gateway, network.tap_device
+        );
+    }
+    println!("Ports:       {}", ports.join(", "));
+    for rule in &info.firewall_rules {
+        println!(
+            "Rule:        {} handle {}: {}",
+            rule.chain,
+            rule.handle,
+            rule.expressions.join(" ")
+        );
+    }
+    let mut labels = info
+        .labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>();
+    labels.sort();
+    println!("Labels:      {}", labels.join(", "));
+    let volumes = info
+        .volumes
+        .iter()
+        .map(|volume| {
+            format!(
+                "{}:{}{}",
+                volume.name,
+                volume.mount_path,
+                if volume.read_only { ":ro" } else { "" }
+            )
+        })
+        .collect::<Vec<_>>();
+    println!("Volumes:     {}", volumes.join(", "));
+    println!(
+        "State:       {}",
+        info.state.as_deref().unwrap_or("unknown")
+    );
+    println!("Created:     {}", created_at);
+    println!(
+        "Uptime:      {}h {}m {}s",
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60
+    );
+    println!("Restarts:    {}", info.restart_count);
+    if let Some(io_limits) = info.io_limits {
+        print_io_limits(&io_limits);
+    }
+    if let Some(exit_code) = info.last_exit_code {
+        println!("Exit code:   {}", exit_code);
+    }
+}
+
+fn print_balloon_stats(stats: &BalloonStats) {
+    const MB: u64 = 1024 * 1024;
+    println!(
+        "Balloon:     {} MB of {} MB taken",
+        stats.actual_mb, stats.target_mb
+    );
+    if let Some(available) = stats.available_memory_bytes {
+        println!("Available:   {} MB in the guest", available / MB);
+    }
+}
+
+fn print_io_limits(limits: &IoLimits) {
+    let rate = |bucket: &Option<TokenBucket>, unit: &str| {
+        bucket.as_ref().map(|bucket| {
+            format!(
+                "{} {}/s",
+                bucket.size * 1000 / bucket.refill_time_ms.max(1),
+                unit
+            )
+        })
+    };
+    let limiter = |limiter: &Option<RateLimiter>, ops_unit: &str| match limiter {
+        Some(limiter) => [rate(&limiter.bandwidth, "B"), rate(&limiter.ops, ops_unit)]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", "),
+        None => "unlimited".to_string(),
+    };
+    println!("Disk limit:  {}", limiter(&limits.drive, "requests"));
+    println!("Rx limit:    {}", limiter(&limits.network_rx, "packets"));
+    println!("Tx limit:    {}", limiter(&limits.network_tx, "packets"));
+}
+
+fn print_instance_stats(stats: InstanceStats) {
+    const MB: u64 = 1024 * 1024;
+    let sampled_at = DateTime::from_timestamp_millis(stats.timestamp_ms)
+        .map(|ts| {
+            ts.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default();
+
+    println!("ID:          {}", stats.id);
+    println!("Sampled:     {}", sampled_at);
+    println!(
+        "CPU:         {:.1}% ({} ms user, {} ms system)",
+        stats.cpu_percent,
+        stats.cpu_user_usec / 1000,
+        stats.cpu_system_usec / 1000
+    );
+    match stats.memory_limit_bytes {
+        Some(limit) => println!(
+            "Memory:      {} MB / {} MB",
+            stats.memory_bytes / MB,
+            limit / MB
+        ),
+        None => println!("Memory:      {} MB", stats.memory_bytes / MB),
+    }
+    println!(
+        "Disk:        {} MB / {} MB",
+        stats.disk_used_bytes / MB,
+        stats.disk_total_bytes / MB
+    );
+    println!(
+        "Disk I/O:    {} MB read, {} MB written",
+        stats.disk_read_bytes / MB,
+        stats.disk_write_bytes / MB
+    );
+    println!(
+        "Network:     {} MB ({} packets) received, {} MB ({} packets) sent",
+        stats.net_rx_bytes / MB,
+        stats.net_rx_packets,
+        stats.net_tx_bytes / MB,
+        stats.net_tx_packets
+    );
+    if let Some(balloon) = &stats.balloon {
+        print_balloon_stats(balloon);
+    }
+    if let Some(vmm) = stats.vmm {
+        println!(
+            "VM exits:    {} I/O, {} MMIO, {} vCPU failures",
+            vmm.vcpu_exits_io, vmm.vcpu_exits_mmio, vmm.vcpu_failures
+        );
+        println!(
+            "VM block:    {} MB read ({} requests), {} MB written ({} requests), {} failed, {} throttled",
+            vmm.block_read_bytes / MB,
+            vmm.block_read_count,
+            vmm.block_write_bytes / MB,
+            vmm.block_write_count,
+            vmm.block_failures,
+            vmm.block_throttled
+        );
+        println!(
+            "VM network:  {} MB ({} packets) received, {} MB ({} packets) sent, {} failed, {} throttled",
+            vmm.net_rx_bytes / MB,
+            vmm.net_rx_packets,
+            vmm.net_tx_bytes / MB,
+            vmm.net_tx_packets,
+            vmm.net_failures,
+            vmm.net_throttled
+        );
+        println!("VM vsock:    {} failures", vmm.vsock_failures);
+    }
+}
+
+fn print_event(event: InstanceEvent) {
+    let ts = DateTime::from_timestamp_millis(event.timestamp_ms)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local);
+    let description = match event.event {
+        Some(Event::Provisioned(provisioned)) => {
+            format!("provisioned {}", provisioned.container_reference)
+        }
+        Some(Event::StateChanged(changed)) => format!("state {}", changed.state),
+        Some(Event::Exited(exited)) => match exited.exit_code {
+            Some(exit_code) => format!("exited {} ({})", exited.reason, exit_code),
+            None => format!("exited {}", exited.reason),
+        },
+        Some(Event::Deprovisioned(_)) => "deprovisioned".to_string(),
+        Some(Event::Restarted(restarted)) => {
+            format!("restarted ({})", restarted.restart_count)
+        }
+        Some(Event::PortPublished(port)) => format!("published {}", format_published_port(&port)),
+        Some(Event::PortUnpublished(port)) => {
+            format!("unpublished {}", format_published_port(&port))
+        }
+        None => "unknown".to_string(),
+    };
+    println!(
+        "{} {} {} {}",
+        event.revision,
+        ts.format("%Y-%m-%d %H:%M:%S%.3f"),
+        event.instance_id,
+        description
+    );
+}
+
+fn parse_protocol(protocol: &str) -> Result<Protocol, String> {
+    match protocol {
+        "tcp" => Ok(Protocol::Tcp),
+        "udp" => Ok(Protocol::Udp),
+        "sctp" => Ok(Protocol::Sctp),
+        _ => Err(format!("Unknown protocol: {}", protocol)),
+    }
+}
+
+/// E.g. `8080->80/tcp` or `10.0.0.1:5000-5009->5000-5009/udp`.
+fn format_published_port(port: &PublishedPort) -> String {
+    let range = |first: i32| match port.port_count {
+        count if count > 1 => format!("{}-{}", first, first + count - 1),
+        _ => first.to_string(),
+    };
+    let host = match &port.host_ip {
+        Some(ip) if ip.contains(':') => format!("[{}]:{}", ip, range(port.host_port)),
+        Some(ip) => format!("{}:{}", ip, range(port.host_port)),
+        None => range(port.host_port),
+    };
+    format!(
+        "{}->{}/{}",
+        host,
+        range(port.guest_port),
+        port.protocol().as_str_name().to_lowercase()
+    )
+}
+
+fn parse_restart_policy(policy: &str) -> Result<RestartPolicy, String> {
+    let (mode, max_retries) = match policy.split_once(':') {
+        Some((mode, max_retries)) => (
+            mode,
+            Some(
+                max_retries
+                    .parse()
+                    .map_err(|_| format!("Invalid max retries: {}", max_retries))?,
+            ),
+        ),
+        None => (policy, None),
+    };
+    let mode = match mode {
+        "never" => RestartMode::Never,
+        "on-failure" => RestartMode::OnFailure,
+        "always" => RestartMode::Always,
+        _ => return Err(format!("Unknown restart policy: {}", mode)),
+    };
+    if max_retries.is_some() && mode != RestartMode::OnFailure {
+        return Err("Max retries are only supported with on-failure".to_string());
+    }
+    Ok(RestartPolicy {
+        mode: mode as i32,
+        max_retries,
+    })
+}
+
+fn print_log(log: LogMessage) {
+    let ts = DateTime::from_timestamp(
+        log.timestamp_ms / 1000,
+        (log.timestamp_ms % 1000) as u32 * 1_000_000,
+    )
+    .unwrap()
+    .with_timezone(&chrono::Local);
+    println!(
+        "[{}] {} - {}",
+        log.log_type,
+        ts.format("%Y-%m-%d %H:%M:%S%.3f"),
+        log.message.or(log.state).unwrap_or_default()
+    );
+}
+
+fn parse_volume_mount(mount: &str) -> Result<VolumeMount, String> {
+    let invalid = || format!("Invalid volume {}, expected NAME:PATH[:ro]", mount);
+    let (name, mount_path) = mount.split_once(':').ok_or_else(invalid)?;
+    let (mount_path, read_only) = match mount_path.rsplit_once(':') {
+        Some((mount_path, "ro")) => (mount_path, true),
+        Some((mount_path, "rw")) => (mount_path, false),
+        Some(_) => return Err(invalid()),
+        None => (mount_path, false),
+    };
+    Ok(VolumeMount {
+        name: name.to_string(),
+        mount_path: mount_path.to_string(),
+        read_only,
+    })
+}
@@ -0,0 +1,159 @@
+//! Resources of the node handed out to instances. Each of them can be overcommitted by its own ratio.
+
+use std::{fmt, ops::Add};
+
+use anyhow::{Context, Result};
+
+const GB: u64 = 1024 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resources {
+    pub vcpus: u32,
+    pub memory_mb: u64,
+    pub disk_gb: u64,
+}
+
+impl Add for Resources {
+    type Output = Resources;
+
+    fn add(self, other: Resources) -> Resources {
+        Resources {
+            vcpus: self.vcpus + other.vcpus,
+            memory_mb: self.memory_mb + other.memory_mb,
+            disk_gb: self.disk_gb + other.disk_gb,
+        }
+    }
+}
+
+impl Resources {
+    pub fn saturating_sub(self, other: Resources) -> Resources {
+        Resources {
+            vcpus: self.vcpus.saturating_sub(other.vcpus),
+            memory_mb: self.memory_mb.saturating_sub(other.memory_mb),
+            disk_gb: self.disk_gb.saturating_sub(other.disk_gb),
+        }
+    }
+
+    /// Checks that `requested` fits into what is left of these resources after `allocated`.
+    pub fn admit(
+        self,
+        allocated: Resources,
+        requested: Resources,
+    ) -> Result<(), InsufficientCapacity> {
+        let free = self.saturating_sub(allocated);
+        let exhausted = if requested.vcpus > free.vcpus {
+            Some(("vCPUs", requested.vcpus as u64, free.vcpus as u64))
+        } else if requested.memory_mb > free.memory_mb {
+            Some(("MB of memory", requested.memory_mb, free.memory_mb))
+        } else if requested.disk_gb > free.disk_gb {
+            Some(("GB of disk", requested.disk_gb, free.disk_gb))
+        } else {
+            None
+        };
+        match exhausted {
+            Some((resource, requested, free)) => Err(InsufficientCapacity(format!(
+                "Requested {} {}, {} free",
+                requested, resource, free
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A request which doesn't fit on the node, reported as `RESOURCE_EXHAUSTED`.
+#[derive(Debug)]
+pub struct InsufficientCapacity(pub String);
+
+impl fmt::Display for InsufficientCapacity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InsufficientCapacity {}
+
+/// Scales an amount of a resource by its overcommit ratio.
+pub fn overcommit(amount: u64, ratio: f64) -> u64 {
+    (amount as f64 * ratio) as u64
+}
+
+/// Checks that the sparse drives on a filesystem, including a new one of `requested_gb`, can grow to their full size
+/// by `unallocated_bytes` in total within `available_bytes` of free space times the overcommit ratio.
+pub fn admit_sparse_disk(
+    unallocated_bytes: u64,
+    available_bytes: u64,
+    ratio: f64,
+    requested_gb: u64,
+) -> Result<(), InsufficientCapacity> {
+    if unallocated_bytes as f64 > available_bytes as f64 * ratio {
+        return Err(InsufficientCapacity(format!(
+            "Not enough disk space for {} GB, {} GB available",
+            requested_gb,
+            available_bytes / GB
+        )));
+    }
+    Ok(())
+}
+
+pub fn host_vcpus() -> u32 {
+    std::thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(1)
+}
+
+pub fn host_memory_mb() -> Result<u64> {
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sysinfo(&mut info) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("Unable to get the host memory");
+    }
+    Ok(info.totalram as u64 * info.mem_unit as u64 / 1024 / 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_requests_within_free_resources() {
+        let total = Resources {
+            vcpus: 8,
+            memory_mb: 8192,
+            disk_gb: 100,
+        };
+        let allocated = Resources {
+            vcpus: 6,
+            memory_mb: 4096,
+            disk_gb: 90,
+        };
+        let request = |vcpus, memory_mb, disk_gb| Resources {
+            vcpus,
+            memory_mb,
+            disk_gb,
+        };
+        assert!(total.admit(allocated, request(2, 4096, 10)).is_ok());
+        assert_eq!(
+            total.admit(allocated, request(3, 1024, 8)).unwrap_err().0,
+            "Requested 3 vCPUs, 2 free"
+        );
+        assert!(total.admit(allocated, request(1, 5000, 8)).is_err());
+        assert!(total.admit(allocated, request(1, 1024, 11)).is_err());
+        // Allocated beyond the total after the configuration was lowered
+        assert!(total.admit(request(10, 0, 0), request(1, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn admits_sparse_disks_within_the_overcommit_ratio() {
+        assert!(admit_sparse_disk(10 * GB, 10 * GB, 1.0, 10).is_ok());
+        assert_eq!(
+            admit_sparse_disk(10 * GB + 1, 10 * GB, 1.0, 10)
+                .unwrap_err()
+                .0,
+            "Not enough disk space for 10 GB, 10 GB available"
+        );
+        // Drives which are mostly empty may promise more than the free space
+        assert!(admit_sparse_disk(20 * GB, 10 * GB, 2.0, 10).is_ok());
+        assert!(admit_sparse_disk(21 * GB, 10 * GB, 2.0, 10).is_err());
+        assert!(admit_sparse_disk(6 * GB, 10 * GB, 0.5, 1).is_err());
+        assert!(admit_sparse_disk(GB, 0, 4.0, 1).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Lifecycle events of all instances on the node, numbered by a revision so watchers can resume after a reconnect.
+//! Revisions are reserved on disk in blocks, a restarted nodemanager continues after the last reserved one
+//! instead of handing out the revisions of its predecessor again.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use circular_buffer::CircularBuffer;
+use log::error;
+use proto::node::{instance_event::Event, InstanceEvent};
+use tokio::sync::broadcast;
+use vmproto::guest::get_timestamp_ms;
+
+// Events kept around for watchers resuming from an older revision
+const EVENT_HISTORY: usize = 1024;
+const WATCHER_CAPACITY: usize = 256;
+const REVISION_BLOCK: u64 = 1024;
+
+pub type SharedEvent = Arc<InstanceEvent>;
+
+/// Persists the highest revision which may be handed out.
+pub type ReserveRevisions = Box<dyn Fn(u64) -> Result<()> + Send + Sync>;
+
+struct History {
+    revision: u64,
+    reserved: u64,
+    events: Box<CircularBuffer<EVENT_HISTORY, SharedEvent>>,
+}
+
+pub struct EventLog {
+    history: Mutex<History>,
+    tx: broadcast::Sender<SharedEvent>,
+    reserve: ReserveRevisions,
+}
+
+impl EventLog {
+    /// Starts after `reserved`, the highest revision reserved by a previous nodemanager.
+    pub fn new(reserved: u64, reserve: ReserveRevisions) -> Self {
+        let (tx, _) = broadcast::channel(WATCHER_CAPACITY);
+        Self {
+            history: Mutex::new(History {
+                revision: reserved,
+                reserved,
+                events: CircularBuffer::boxed(),
+            }),
+            tx,
+            reserve,
+        }
+    }
+
+    pub fn record(&self, instance_id: &str, event: Event) {
+        let mut history = self.history.lock().unwrap();
+        if history.revision >= history.reserved {
+            let reserved = history.revision + REVISION_BLOCK;
+            // The event is still recorded, only a restart could hand out its revision again
+            match (self.reserve)(reserved) {
+                Ok(()) => history.reserved = reserved,
+                Err(e) => error!("Failed to reserve event revisions: {:?}", e),
+            }
+        }
+        history.revision += 1;
+        let event = Arc::new(InstanceEvent {
+            revision: history.revision,
+            timestamp_ms: get_timestamp_ms() as i64,
+            instance_id: instance_id.to_string(),
+            event: Some(event),
+        });
+        history.events.push_back(event.clone());
+        // Fails if nobody is watching
+        let _ = self.tx.send(event);
+    }
+
+    /// Returns the buffered events after `after_revision` together with a subscription for new events,
+    /// `None` if some of the events after it are no longer buffered.
+    pub fn watch(
+        &self,
+        after_revision: Option<u64>,
+    ) -> Option<(Vec<SharedEvent>, broadcast::Receiver<SharedEvent>)> {
+        // Subscribed while holding the lock, so no event is missed or sent twice
+        let history = self.history.lock().unwrap();
+        let after_revision = after_revision.unwrap_or(history.revision);
+        let oldest_available = history.revision - history.events.len() as u64;
+        if after_revision < oldest_available || after_revision > history.revision {
+            return None;
+        }
+        let events = history
+            .events
+            .iter()
+            .filter(|event| event.revision > after_revision)
+            .cloned()
+            .collect();
+        Some((events, self.tx.subscribe()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::node::InstanceDeprovisioned;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn revisions(events: &[SharedEvent]) -> Vec<u64> {
+        events.iter().map(|event| event.revision).collect()
+    }
+
+    #[test]
+    fn resumes_after_revision() {
+        let log = EventLog::new(0, Box::new(|_| Ok(())));
+        for _ in 0..3 {
+            log.record("a", Event::Deprovisioned(InstanceDeprovisioned {}));
+        }
+
+        let (events, _) = log.watch(Some(1)).unwrap();
+        assert_eq!(revisions(&events), vec![2, 3]);
+        let (events, mut rx) = log.watch(None).unwrap();
+        assert!(events.is_empty());
+
+        log.record("a", Event::Deprovisioned(InstanceDeprovisioned {}));
+        assert_eq!(rx.try_recv().unwrap().revision, 4);
+        assert!(log.watch(Some(5)).is_none());
+    }
+
+    #[test]
+    fn rejects_revisions_no_longer_buffered() {
+        let log = EventLog::new(0, Box::new(|_| Ok(())));
+        for _ in 0..EVENT_HISTORY + 2 {
+            log.record("a", Event::Deprovisioned(InstanceDeprovisioned {}));
+        }
+        assert!(log.watch(Some(1)).is_none());
+        let (events, _) = log.watch(Some(2)).unwrap();
+        assert_eq!(events.len(), EVENT_HISTORY);
+        assert_eq!(events[0].revision, 3);
+    }
+
+    #[test]
+    fn continues_after_reserved_revisions() {
+        let reserved = Arc::new(AtomicU64::new(0));
+        let reserve = {
+            let reserved = reserved.clone();
+            Box::new(move |revision| {
+                reserved.store(revision, Ordering::SeqCst);
+                Ok(())
+            })
+        };
+        let log = EventLog::new(2048, reserve);
+        assert!(log.watch(Some(2000)).is_none());
+        log.record("a", Event::Deprovisioned(InstanceDeprovisioned {}));
+        assert_eq!(reserved.load(Ordering::SeqCst), 2048 + REVISION_BLOCK);
+
+        let (events, _) = log.watch(Some(2048)).unwrap();
+        assert_eq!(revisions(&events), vec![2049]);
+    }
+}
@@ -0,0 +1,629 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use log::{debug, info, trace};
+use oci_spec::{
+    distribution::Reference,
+    image::{Arch, Descriptor, ImageIndex, ImageManifest, Os},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+};
+use uuid::Uuid;
+use vmproto::registry::{parse_challenge, registry_url, token_request, TokenResponse};
+
+use crate::networking::cmd;
+
+const SUPPORTED_ARCH: Arch = Arch::Amd64;
+const CONCURRENT_LAYER_DOWNLOADS: usize = 5;
+const MANIFEST_MEDIA_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+// Headroom for the filesystem metadata on top of the extracted layers
+const IMAGE_FS_OVERHEAD_BYTES: u64 = 64 * 1024 * 1024;
+const IMAGE_FS_BLOCK_SIZE: u64 = 4096;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// An image filesystem in the cache and the digest of the manifest it was built from.
+pub struct CachedImage {
+    pub digest: String,
+    pub path: PathBuf,
+}
+
+/// Host side cache of container images, shared by all instances.
+/// Blobs are stored by digest and every image manifest gets a read-only ext4 filesystem with
+/// `manifest.json`, `image_config.json` and the layers extracted to `layers/<digest>`,
+/// which the instance mounts instead of pulling the image itself.
+pub struct ImageCache {
+    blobs: PathBuf,
+    images: PathBuf,
+    client: reqwest::Client,
+    building: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl ImageCache {
+    pub fn new(directory: &Path) -> Result<Self> {
+        let blobs = directory.join("blobs").join("sha256");
+        let images = directory.join("images");
+        std::fs::create_dir_all(&blobs)
+            .context(format!("Unable to create blob cache {}", blobs.display()))?;
+        std::fs::create_dir_all(&images)
+            .context(format!("Unable to create image cache {}", images.display()))?;
+        remove_leftovers(&blobs)?;
+        remove_leftovers(&images)?;
+        Ok(Self {
+            blobs,
+            images,
+            client: reqwest::Client::new(),
+            building: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the path to the filesystem of the image, pulling and building it if it is not cached yet.
+    /// The manifest is always fetched with `credentials`, so a cached private image is only used by those allowed to pull it.
+    pub async fn prepare(
+        &self,
+        reference: &str,
+        credentials: Option<&RegistryAuth>,
+    ) -> Result<CachedImage> {
+        let reference = Reference::try_from(reference.to_string())?;
+        let registry = Registry::connect(self.client.clone(), &reference, credentials).await?;
+        let (manifest, digest) = registry.get_manifest(&reference).await?;
+        let image_path = self
+            .images
+            .join(format!("{}.ext4", digest.replace(':', "")));
+
+        // Instances of the same image provisioned at once wait for a single build
+        let lock = self
+            .building
+            .lock()
+            .await
+            .entry(digest.clone())
+            .or_default()
+            .clone();
+        let res = {
+            let _building = lock.lock().await;
+            self.build(&registry, &reference, manifest, &digest, &image_path)
+                .await
+        };
+        // Only the map and this request still hold the lock if nobody else is waiting for the build
+        let mut building = self.building.lock().await;
+        if Arc::strong_count(&lock) == 2 {
+            building.remove(&digest);
+        }
+        drop(building);
+
+        res?;
+        Ok(CachedImage {
+            digest,
+            path: image_path,
+        })
+    }
+
+    async fn build(
+        &self,
+        registry: &Registry,
+        reference: &Reference,
+        manifest: ImageManifest,
+        digest: &str,
+        image_path: &Path,
+    ) -> Result<()> {
+        if image_path.exists() {
+            debug!("Using cached image {} for {}", digest, reference);
+            return Ok(());
+        }
+        info!("Pulling image {} ({}) into the cache", reference, digest);
+
+        let config = fetch_blob(registry, &self.blobs, manifest.config()).await?;
+        let layers = self.fetch_layers(registry, &manifest).await?;
+
+        let images = self.images.clone();
+        let dest = image_path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            build_image_fs(&images, &manifest, &config, &layers, &dest)
+        })
+        .await??;
+        info!("Cached image {} as {}", reference, image_path.display());
+        Ok(())
+    }
+
+    async fn fetch_layers(
+        &self,
+        registry: &Registry,
+        manifest: &ImageManifest,
+    ) -> Result<Vec<(String, PathBuf)>> {
+        let permits = Arc::new(Semaphore::new(CONCURRENT_LAYER_DOWNLOADS));
+        let mut downloads = JoinSet::new();
+        for (i, layer) in manifest.layers().iter().enumerate() {
+            let registry = registry.clone();
+            let blobs = self.blobs.clone();
+            let layer = layer.clone();
+            let permits = permits.clone();
+            downloads.spawn(async move {
+                let _permit = permits.acquire().await?;
+                let blob = fetch_blob(&registry, &blobs, &layer).await?;
+                Ok::<_, anyhow::Error>((i, layer.digest().to_string(), blob))
+            });
+        }
+
+        let mut layers = vec![None; manifest.layers().len()];
+        while let Some(res) = downloads.join_next().await {
+            let (i, digest, blob) = res??;
+            layers[i] = Some((digest, blob));
+        }
+        Ok(layers.into_iter().flatten().collect())
+    }
+}
+
+/// Credentials for pulling from a registry, configured by name in the `ManagerConfig` or passed with a provision request.
+/// Also handed to the instance, which pulls the image itself if it can't be cached.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistryAuth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+#[derive(Clone)]
+struct Registry {
+    client: reqwest::Client,
+    repository_url: String,
+    auth: Option<RegistryAuth>,
+}
+
+impl Registry {
+    /// Works out how to authenticate for pulling, following the `WWW-Authenticate` challenge of the registry.
+    async fn connect(
+        client: reqwest::Client,
+        reference: &Reference,
+        credentials: Option<&RegistryAuth>,
+    ) -> Result<Self> {
+        let registry_url = registry_url(reference.resolve_registry());
+        let mut registry = Self {
+            client,
+            repository_url: format!("{}/v2/{}", registry_url, reference.repository()),
+            auth: credentials.cloned(),
+        };
+        if let Some(RegistryAuth::Bearer { .. }) = credentials {
+            return Ok(registry);
+        }
+
+        let resp = registry
+            .client
+            .get(format!("{}/v2/", registry_url))
+            .send()
+            .await?;
+        if resp.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(registry);
+        }
+        let (scheme, params) = resp
+            .headers()
+            .get(reqwest::header::WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_challenge)
+            .ok_or(anyhow!(
+                "Registry {} sent no usable challenge",
+                registry_url
+            ))?;
+
+        match scheme.as_str() {
+            "basic" if credentials.is_some() => {}
+            "basic" => return Err(anyhow!("Registry {} requires credentials", registry_url)),
+            "bearer" => {
+                let token = registry.fetch_token(reference, &params).await?;
+                registry.auth = Some(RegistryAuth::Bearer { token });
+            }
+            _ => return Err(anyhow!("Unsupported authentication scheme {}", scheme)),
+        }
+        Ok(registry)
+    }
+
+    async fn fetch_token(
+        &self,
+        reference: &Reference,
+        params: &HashMap<String, String>,
+    ) -> Result<String> {
+        let (realm, query) = token_request(reference.repository(), params)
+            .ok_or(anyhow!("Bearer challenge without realm"))?;
+        let resp: TokenResponse = self
+            .authorize(self.client.get(realm).query(&query))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        resp.into_token()
+            .ok_or(anyhow!("No token in response from {}", realm))
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.auth {
+            Some(RegistryAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(RegistryAuth::Bearer { token }) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn get(&self, path: &str, accept: Option<&str>) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.repository_url, path);
+        trace!("Registry GET {}", url);
+        let mut request = self.authorize(self.client.get(&url));
+        if let Some(accept) = accept {
+            request = request.header(reqwest::header::ACCEPT, accept);
+        }
+        let resp = request.send().await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("Registry GET {} failed: {}", url, resp.status()));
+        }
+        Ok(resp)
+    }
+
+    /// Resolves the reference to the manifest for our platform, returned along with its digest.
+    /// The digest is computed from the manifest itself, so the registry can't pass one image off as another.
+    async fn get_manifest(&self, reference: &Reference) -> Result<(ImageManifest, String)> {
+        let tag = reference.digest().or(reference.tag()).unwrap_or("latest");
+        let body = self
+            .get(&format!("manifests/{}", tag), Some(MANIFEST_MEDIA_TYPES))
+            .await?
+            .bytes()
+            .await?;
+        let digest = manifest_digest(&body, reference.digest())?;
+        let data: serde_json::Value = serde_json::from_slice(&body)?;
+
+        if data.get("manifests").is_none() {
+            return Ok((ImageManifest::from_reader(&body[..])?, digest));
+        }
+
+        let index: ImageIndex = serde_json::from_value(data)?;
+        let compatible_manifest = index
+            .manifests()
+            .iter()
+            .find(|d| {
+                d.platform()
+                    .as_ref()
+                    .is_some_and(|p| *p.architecture() == SUPPORTED_ARCH && *p.os() == Os::Linux)
+            })
+            .ok_or(anyhow!("No compatible image available for {}", reference))?;
+        let expected = compatible_manifest.digest().to_string();
+        let body = self
+            .get(
+                &format!("manifests/{}", expected),
+                Some(MANIFEST_MEDIA_TYPES),
+            )
+            .await?
+            .bytes()
+            .await?;
+        let digest = manifest_digest(&body, Some(&expected))?;
+        Ok((ImageManifest::from_reader(&body[..])?, digest))
+    }
+}
+
+/// The digest of a manifest, which has to match `expected` if the manifest was referenced by digest.
+fn manifest_digest(body: &[u8], expected: Option<&str>) -> Result<String> {
+    let digest = format!("sha256:{:x}", Sha256::digest(body));
+    match expected {
+        Some(expected) if expected != digest => Err(anyhow!(
+            "Digest mismatch for manifest {}, got {}",
+            expected,
+            digest
+        )),
+        _ => Ok(digest),
+    }
+}
+
+/// Downloads a blob into the cache unless it is already there, verifying its digest.
+async fn fetch_blob(registry: &Registry, blobs: &Path, descriptor: &Descriptor) -> Result<PathBuf> {
+    let digest = descriptor.digest().to_string();
+    let hex = digest
+        .strip_prefix("sha256:")
+        .ok_or(anyhow!("Unsupported digest algorithm in {}", digest))?;
+    let path = blobs.join(hex);
+    if path.exists() {
+        trace!("Blob {} already cached", digest);
+        return Ok(path);
+    }
+
+    let partial = blobs.join(format!("{}.{}.partial", hex, Uuid::new_v4()));
+    let res = async {
+        let mut resp = registry.get(&format!("blobs/{}", digest), None).await?;
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = resp.chunk().await? {
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        if format!("{:x}", hasher.finalize()) != hex {
+            return Err(anyhow!("Digest mismatch for blob {}", digest));
+        }
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    res?;
+    debug!("Cached blob {}", digest);
+    Ok(path)
+}
+
+/// Removes downloads and builds which were interrupted by a restart of the nodemanager.
+fn remove_leftovers(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("partial") => std::fs::remove_file(&path)?,
+            Some("staging") => std::fs::remove_dir_all(&path)?,
+            _ => continue,
+        }
+        debug!("Removed leftover {}", path.display());
+    }
+    Ok(())
+}
+
+fn build_image_fs(
+    images: &Path,
+    manifest: &ImageManifest,
+    config: &Path,
+    layers: &[(String, PathBuf)],
+    dest: &Path,
+) -> Result<()> {
+    let build_id = Uuid::new_v4();
+    let staging = images.join(format!("{}.staging", build_id));
+    let partial = images.join(format!("{}.partial", build_id));
+
+    let res = (|| {
+        // Nobody else gets to the extracted files, e.g. setuid binaries of the image
+        std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+        for (digest, blob) in layers {
+            let layer_dir = staging.join("layers").join(digest.replace(':', ""));
+            std::fs::create_dir_all(&layer_dir)?;
+            extract_layer(blob, &layer_dir)
+                .with_context(|| format!("Unable to extract layer {}", digest))?;
+        }
+        manifest.to_file_pretty(staging.join("manifest.json"))?;
+        std::fs::copy(config, staging.join("image_config.json"))?;
+
+        let size = directory_size(&staging)? * 11 / 10 + IMAGE_FS_OVERHEAD_BYTES;
+        std::fs::File::create(&partial)?.set_len(size)?;
+        cmd(
+            "mkfs.ext4",
+            &[
+                "-q",
+                "-F",
+                "-O",
+                "^has_journal",
+                "-m",
+                "0",
+                "-d",
+                path_str(&staging)?,
+                path_str(&partial)?,
+            ],
+        )?;
+        // Linked read-only into every jail, so it has to be readable by all jailer uids
+        std::fs::set_permissions(&partial, std::fs::Permissions::from_mode(0o644))?;
+        std::fs::rename(&partial, dest)?;
+        Ok(())
+    })();
+
+    let _ = std::fs::remove_dir_all(&staging);
+    if res.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    res
+}
+
+/// Unpacks a layer in-process, refusing entries which would end up outside of `dest`.
+/// Ownership and permissions are kept for the guest, device nodes and FIFOs are left out.
+fn extract_layer(blob: &Path, dest: &Path) -> Result<()> {
+    let mut file = BufReader::new(File::open(blob)?);
+    let magic = file.fill_buf()?;
+    if magic.starts_with(&ZSTD_MAGIC) {
+        return Err(anyhow!("zstd compressed layers are not supported"));
+    }
+    let reader: Box<dyn Read> = if magic.starts_with(&GZIP_MAGIC) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_overwrite(true);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let link = entry.link_name()?.map(|link| link.into_owned());
+        let entry_type = entry.header().entry_type();
+        let escapes = |path: &Path| {
+            path.components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        };
+        // Symlinks may point anywhere, they are only followed inside the guest
+        if escapes(&path) || (entry_type.is_hard_link() && link.as_deref().is_some_and(escapes)) {
+            return Err(anyhow!("Entry {} leaves the layer", path.display()));
+        }
+        if entry_type.is_block_special()
+            || entry_type.is_character_special()
+            || entry_type.is_fifo()
+        {
+            debug!("Leaving out special file {}", path.display());
+            continue;
+        }
+        // Also refuses to write through symlinks of earlier entries which lead outside of `dest`
+        if !entry.unpack_in(dest)? {
+            return Err(anyhow!("Entry {} leaves the layer", path.display()));
+        }
+    }
+    Ok(())
+}
+
+fn directory_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += metadata.len().div_ceil(IMAGE_FS_BLOCK_SIZE).max(1) * IMAGE_FS_BLOCK_SIZE;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        }
+    }
+    Ok(size)
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or(anyhow!("Invalid path {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::fs::MetadataExt};
+
+    use flate2::{write::GzEncoder, Compression};
+    use tar::EntryType;
+
+    use super::*;
+
+    /// Builds a layer in memory. The names are written into the header as they are,
+    /// `tar::Builder` would refuse the ones leaving the layer.
+    fn layer(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, entry_type, link) in entries {
+            let data: &[u8] = if *entry_type == EntryType::Regular {
+                b"data"
+            } else {
+                b""
+            };
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o4755);
+            header.set_uid(1000);
+            header.set_gid(1000);
+            header.set_mtime(0);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Extracts the layer into a directory of its own, which is returned if it worked.
+    fn extract(layer: &[u8]) -> Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("layer-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let blob = dir.with_extension("tar");
+        std::fs::write(&blob, layer)?;
+        let res = extract_layer(&blob, &dir);
+        let _ = std::fs::remove_file(&blob);
+        match res {
+            Ok(()) => Ok(dir),
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                Err(e)
+            }
+        }
+    }
+
+    #[test]
+    fn extracts_layers() {
+        let entries = [
+            ("bin/", EntryType::Directory, ""),
+            ("bin/su", EntryType::Regular, ""),
+            ("bin/sudo", EntryType::Link, "bin/su"),
+            ("etc", EntryType::Symlink, "/etc"),
+            ("dev/null", EntryType::Char, ""),
+            ("dev/sda", EntryType::Block, ""),
+            ("run/fifo", EntryType::Fifo, ""),
+        ];
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::fast());
+        gzipped.write_all(&layer(&entries)).unwrap();
+
+        for layer in [layer(&entries), gzipped.finish().unwrap()] {
+            let dir = extract(&layer).unwrap();
+            let su = std::fs::metadata(dir.join("bin/su")).unwrap();
+            assert_eq!((su.uid(), su.mode() & 0o7777), (1000, 0o4755));
+            assert_eq!(
+                std::fs::metadata(dir.join("bin/sudo")).unwrap().ino(),
+                su.ino()
+            );
+            // Left for the guest to resolve
+            assert_eq!(
+                std::fs::read_link(dir.join("etc")).unwrap(),
+                Path::new("/etc")
+            );
+            assert!(!dir.join("dev/null").exists());
+            assert!(!dir.join("dev/sda").exists());
+            assert!(!dir.join("run/fifo").exists());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn refuses_entries_leaving_the_layer() {
+        let outside = std::env::temp_dir().join(format!("layer-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&outside).unwrap();
+        let outside_str = outside.to_str().unwrap();
+        let absolute = outside.join("escaped");
+        let layers = [
+            vec![("../escaped", EntryType::Regular, "")],
+            vec![("bin/../../escaped", EntryType::Regular, "")],
+            vec![(absolute.to_str().unwrap(), EntryType::Regular, "")],
+            vec![("passwd", EntryType::Link, "../../etc/passwd")],
+            vec![("passwd", EntryType::Link, "/etc/passwd")],
+            vec![
+                ("out", EntryType::Symlink, outside_str),
+                ("out/escaped", EntryType::Regular, ""),
+            ],
+            vec![
+                ("up", EntryType::Symlink, ".."),
+                ("up/escaped", EntryType::Regular, ""),
+            ],
+        ];
+        for entries in layers {
+            assert!(extract(&layer(&entries)).is_err(), "{:?}", entries[0]);
+        }
+        assert!(outside.read_dir().unwrap().next().is_none());
+        assert!(!std::env::temp_dir().join("escaped").exists());
+        std::fs::remove_dir(&outside).unwrap();
+    }
+
+    #[test]
+    fn refuses_zstd_layers() {
+        let mut layer = ZSTD_MAGIC.to_vec();
+        layer.extend_from_slice(&[0; 512]);
+        assert!(extract(&layer).is_err());
+    }
+
+    #[test]
+    fn verifies_manifest_digests() {
+        let body = br#"{"schemaVersion":2}"#;
+        let digest = format!("sha256:{:x}", Sha256::digest(body));
+        assert_eq!(manifest_digest(body, None).unwrap(), digest);
+        assert_eq!(manifest_digest(body, Some(&digest)).unwrap(), digest);
+
+        let other = format!("sha256:{:x}", Sha256::digest(b"{}"));
+        assert!(manifest_digest(body, Some(&other)).is_err());
+        assert!(manifest_digest(body, Some("sha256:")).is_err());
+    }
+}
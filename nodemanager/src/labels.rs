@@ -0,0 +1,182 @@
+//! Instance labels and selectors for filtering by them, with the syntax of Kubernetes label selectors:
+//! `env=prod,tier!=cache,region in (eu,us),!deprecated`.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+
+pub type Labels = BTreeMap<String, String>;
+
+/// Checks that label keys are non-empty and keys and values only contain characters without meaning in a selector.
+pub fn validate<'a>(labels: impl IntoIterator<Item = (&'a String, &'a String)>) -> Result<()> {
+    for (key, value) in labels {
+        if key.is_empty() || !is_valid(key) || !is_valid(value) {
+            return Err(anyhow!("Invalid label {}={}", key, value));
+        }
+    }
+    Ok(())
+}
+
+fn is_valid(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+#[derive(Debug, PartialEq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    NotExists(String),
+}
+
+impl Requirement {
+    fn parse(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid selector requirement {:?}", s);
+        let key = |k: &str| {
+            let k = k.trim();
+            match !k.is_empty() && is_valid(k) {
+                true => Ok(k.to_string()),
+                false => Err(invalid()),
+            }
+        };
+        let value = |v: &str| {
+            let v = v.trim();
+            match is_valid(v) {
+                true => Ok(v.to_string()),
+                false => Err(invalid()),
+            }
+        };
+
+        if let Some((left, values)) = s.split_once('(') {
+            let values = values.strip_suffix(')').ok_or_else(invalid)?;
+            let values = values.split(',').map(value).collect::<Result<Vec<_>>>()?;
+            let mut left = left.split_whitespace();
+            let (k, op) = (left.next().ok_or_else(invalid)?, left.next());
+            if left.next().is_some() {
+                return Err(invalid());
+            }
+            return match op {
+                Some("in") => Ok(Requirement::In(key(k)?, values)),
+                Some("notin") => Ok(Requirement::NotIn(key(k)?, values)),
+                _ => Err(invalid()),
+            };
+        }
+        if let Some((k, v)) = s.split_once("!=") {
+            return Ok(Requirement::NotEquals(key(k)?, value(v)?));
+        }
+        if let Some((k, v)) = s.split_once("==").or_else(|| s.split_once('=')) {
+            return Ok(Requirement::Equals(key(k)?, value(v)?));
+        }
+        match s.trim().strip_prefix('!') {
+            Some(k) => Ok(Requirement::NotExists(key(k)?)),
+            None => Ok(Requirement::Exists(key(s)?)),
+        }
+    }
+
+    fn matches(&self, labels: &Labels) -> bool {
+        match self {
+            Requirement::Equals(k, v) => labels.get(k) == Some(v),
+            Requirement::NotEquals(k, v) => labels.get(k) != Some(v),
+            Requirement::In(k, values) => labels.get(k).is_some_and(|v| values.contains(v)),
+            Requirement::NotIn(k, values) => labels.get(k).is_none_or(|v| !values.contains(v)),
+            Requirement::Exists(k) => labels.contains_key(k),
+            Requirement::NotExists(k) => !labels.contains_key(k),
+        }
+    }
+}
+
+/// Requirements which all have to match. The empty selector matches everything.
+#[derive(Debug, Default)]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+impl Selector {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut requirements = Vec::new();
+        let mut depth = 0;
+        let mut start = 0;
+        // Commas separate requirements, except inside the value list of `in` and `notin`
+        for (i, c) in s.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    requirements.push(Requirement::parse(&s[start..i])?);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        if !s[start..].trim().is_empty() || !requirements.is_empty() {
+            requirements.push(Requirement::parse(&s[start..])?);
+        }
+        Ok(Self { requirements })
+    }
+
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_requirements() {
+        let selector =
+            Selector::parse("env=prod, tier!=cache,region in (eu, us),team notin (a),owner,!old")
+                .unwrap();
+        assert_eq!(
+            selector.requirements,
+            vec![
+                Requirement::Equals("env".into(), "prod".into()),
+                Requirement::NotEquals("tier".into(), "cache".into()),
+                Requirement::In("region".into(), vec!["eu".into(), "us".into()]),
+                Requirement::NotIn("team".into(), vec!["a".into()]),
+                Requirement::Exists("owner".into()),
+                Requirement::NotExists("old".into()),
+            ]
+        );
+        assert!(Selector::parse("").unwrap().requirements.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        for selector in [
+            "env=prod,",
+            "=prod",
+            "region in eu",
+            "region within (eu)",
+            "a b",
+        ] {
+            assert!(Selector::parse(selector).is_err(), "{}", selector);
+        }
+    }
+
+    #[test]
+    fn matches_labels() {
+        let l = labels(&[("env", "prod"), ("region", "eu")]);
+        assert!(Selector::parse("").unwrap().matches(&l));
+        assert!(Selector::parse("env==prod,region in (eu,us)")
+            .unwrap()
+            .matches(&l));
+        assert!(Selector::parse("tier!=cache,team notin (a),!old")
+            .unwrap()
+            .matches(&l));
+        assert!(!Selector::parse("env=prod,region=us").unwrap().matches(&l));
+        assert!(!Selector::parse("tier").unwrap().matches(&l));
+        assert!(!Selector::parse("tier in (cache)").unwrap().matches(&l));
+    }
+}
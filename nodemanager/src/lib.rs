@@ -1,3 +1,12 @@
+pub mod capacity;
+pub mod events;
+pub mod images;
+pub mod labels;
 pub mod machine;
 pub mod manager;
+pub mod metrics;
 pub mod networking;
+pub mod state;
+#[cfg(test)]
+mod testing;
+pub mod volumes;
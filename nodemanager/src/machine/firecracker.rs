@@ -1,4 +1,6 @@
 use std::{
+    ffi::CString,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
     path::{Path, PathBuf},
     process::Stdio,
 };
@@ -7,13 +9,41 @@ use anyhow::{anyhow, Context, Result};
 use async_process::{Child, Command};
 use http_client_unix_domain_socket::{ClientUnix, Method};
 use log::{debug, trace};
-use serde::Serialize;
-use tokio::net::UnixListener;
-use uuid::Uuid;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::net::{unix::pipe, UnixListener};
 
-use crate::networking::TunTap;
+use crate::networking::{cmd, TunTap};
 
 // Firecracker types
+/// Holds `size` tokens, refilled completely over `refill_time` milliseconds. A bucket with either of them zero is disabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenBucket {
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub one_time_burst: Option<u64>, // Extra tokens at the start, not refilled
+    pub refill_time: u64,
+}
+
+/// Limits a device by bytes and operations (requests of drives, packets of network interfaces), unlimited if unset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimiter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<TokenBucket>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ops: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Buckets left out of a patch are kept by Firecracker, so unset ones are sent disabled instead.
+    fn replacing(limiter: Option<RateLimiter>) -> RateLimiter {
+        let limiter = limiter.unwrap_or_default();
+        RateLimiter {
+            bandwidth: Some(limiter.bandwidth.unwrap_or_default()),
+            ops: Some(limiter.ops.unwrap_or_default()),
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct Drive {
     // cache_type
@@ -23,17 +53,33 @@ struct Drive {
     is_root_device: bool,
     //partuuid
     path_on_host: String,
-    //rate_limiter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_limiter: Option<RateLimiter>,
     //socket
 }
 
+#[derive(Serialize)]
+struct PartialDrive {
+    drive_id: String,
+    rate_limiter: RateLimiter,
+}
+
 #[derive(Serialize)]
 struct NetworkInterface {
     //guest_mac
     host_dev_name: String,
     iface_id: String,
-    //rx_rate_limiter
-    //tx_rate_limiter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rx_rate_limiter: Option<RateLimiter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_rate_limiter: Option<RateLimiter>,
+}
+
+#[derive(Serialize)]
+struct PartialNetworkInterface {
+    iface_id: String,
+    rx_rate_limiter: RateLimiter,
+    tx_rate_limiter: RateLimiter,
 }
 
 #[derive(Serialize)]
@@ -71,11 +117,111 @@ struct MachineConfig {
     vcpu_count: u8, // Max 32
 }
 
+#[derive(Serialize)]
+enum VmState {
+    Paused,
+    Resumed,
+}
+
+#[derive(Serialize)]
+struct Vm {
+    state: VmState,
+}
+
+#[derive(Serialize)]
+enum SnapshotType {
+    Full,
+    // Diff,
+}
+
+#[derive(Serialize)]
+struct SnapshotCreateParams {
+    snapshot_type: SnapshotType,
+    snapshot_path: String,
+    mem_file_path: String,
+}
+
+#[derive(Serialize)]
+enum MemBackendType {
+    File,
+    // Uffd,
+}
+
+#[derive(Serialize)]
+struct MemBackend {
+    backend_type: MemBackendType,
+    backend_path: String,
+}
+
+#[derive(Serialize)]
+struct SnapshotLoadParams {
+    snapshot_path: String,
+    mem_backend: MemBackend,
+    enable_diff_snapshots: bool,
+    resume_vm: bool,
+}
+
+#[derive(Serialize)]
+struct Balloon {
+    amount_mib: u32,
+    deflate_on_oom: bool,
+    stats_polling_interval_s: u32,
+}
+
+#[derive(Serialize)]
+struct BalloonUpdate {
+    amount_mib: u32,
+}
+
+/// Reported by the balloon driver of the guest, the memory fields are in bytes.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct BalloonStats {
+    pub target_mib: u32,
+    pub actual_mib: u32, // Taken from the guest so far, the balloon inflates gradually
+    #[serde(default)]
+    pub free_memory: Option<u64>,
+    #[serde(default)]
+    pub available_memory: Option<u64>,
+    #[serde(default)]
+    pub total_memory: Option<u64>,
+}
+
+// How often the guest reports balloon statistics
+const BALLOON_STATS_INTERVAL_S: u32 = 5;
+
+#[derive(Serialize)]
+enum LogLevel {
+    // Error,
+    Warning,
+    // Info,
+    // Debug,
+}
+
+#[derive(Serialize)]
+struct Logger {
+    log_path: String,
+    level: LogLevel,
+    show_level: bool,
+    show_log_origin: bool,
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    metrics_path: String,
+}
+
+// FIFOs in the jail root, read by `VmmOutput`
+const LOGGER_FIFO: &str = "logger.fifo";
+const METRICS_FIFO: &str = "metrics.fifo";
+
+const SNAPSHOT_STATE_FILE: &str = "snapshot.vmstate";
+const SNAPSHOT_MEMORY_FILE: &str = "snapshot.mem";
+
 #[derive(Serialize)]
 enum InstanceAction {
     InstanceStart,
     SendCtrlAltDel,
-    // FlushMetrics,
+    FlushMetrics,
 }
 
 #[derive(Serialize)]
@@ -83,23 +229,29 @@ struct InstanceActionInfo {
     action_type: InstanceAction,
 }
 
+const JAILER_BASE_DIRECTORY: &str = "/srv/jailer";
+const API_SOCKET_PATH: &str = "run/firecracker.socket";
+
 pub struct JailedCracker {
     uuid: String,
     root_path: PathBuf,
-    proc: Child,
+    proc: Option<Child>, // Not a child of this process when reattached
+    pid: u32,
     uid: u32,
     api_client: ClientUnix,
 }
 
 impl JailedCracker {
+    /// Starts firecracker in a new jail named `uuid`, the jail of an earlier instance with that id must be gone.
     pub async fn spawn(
         jailer_bin: &Path,
         firecracker_bin: &Path,
+        uuid: &str,
         uid_offset: u16,
         mmds_json: Option<&str>,
         null_pipe_stdio: bool,
     ) -> Result<Self> {
-        let uuid: String = Uuid::new_v4().to_string();
+        let uuid = uuid.to_string();
         debug!("Starting jailed firecracker instance with id {}", uuid);
 
         let mut cmd = Command::new(jailer_bin);
@@ -122,11 +274,7 @@ impl JailedCracker {
         }
         cmd.stdin(Stdio::null());
 
-        let fc_bin = firecracker_bin
-            .file_name()
-            .ok_or(anyhow::Error::msg("Unable to get firecracker binary name"))?;
-        let root_path = Path::new("/srv/jailer/")
-            .join(fc_bin)
+        let root_path = Self::jails_directory(firecracker_bin)?
             .join(&uuid)
             .join("root");
 
@@ -145,7 +293,7 @@ impl JailedCracker {
 
         // Wait for jailer to start firecracker and create socket, max 1ms
         for _ in 0..20 {
-            let socket_path = root_path.join("run").join("firecracker.socket");
+            let socket_path = root_path.join(API_SOCKET_PATH);
             if socket_path.exists() {
                 break;
             }
@@ -153,24 +301,111 @@ impl JailedCracker {
             tokio::time::sleep(std::time::Duration::from_micros(50)).await;
         }
 
-        let api_client = ClientUnix::try_new(
-            &root_path
-                .join("run")
-                .join("firecracker.socket")
-                .to_str()
-                .ok_or(anyhow!("Unable to get path to API socket."))?,
-        )
-        .await?;
+        let api_client = connect_api(&root_path).await?;
 
         Ok(Self {
             uuid,
             root_path,
-            proc: cmd,
+            pid: cmd.id(),
+            proc: Some(cmd),
             uid,
             api_client,
         })
     }
 
+    /// Takes over a firecracker instance spawned by an earlier nodemanager process, which must still be running.
+    pub async fn reattach(
+        firecracker_bin: &Path,
+        uid_offset: u16,
+        uuid: &str,
+        pid: u32,
+    ) -> Result<Self> {
+        debug!(
+            "Reattaching to jailed firecracker instance with id {}",
+            uuid
+        );
+        if !is_jailed_process(pid, uuid) {
+            return Err(anyhow!("Firecracker instance {} is not running", uuid));
+        }
+        let root_path = Self::jails_directory(firecracker_bin)?
+            .join(uuid)
+            .join("root");
+        let api_client = connect_api(&root_path).await?;
+
+        Ok(Self {
+            uuid: uuid.to_string(),
+            root_path,
+            proc: None,
+            pid,
+            uid: 10000 + uid_offset as u32,
+            api_client,
+        })
+    }
+
+    /// The directory the jailer creates the jails of all instances in.
+    fn jails_directory(firecracker_bin: &Path) -> Result<PathBuf> {
+        let fc_bin = firecracker_bin
+            .file_name()
+            .ok_or(anyhow::Error::msg("Unable to get firecracker binary name"))?;
+        Ok(Path::new(JAILER_BASE_DIRECTORY).join(fc_bin))
+    }
+
+    /// The size of the filesystem the jails are created on and the bytes available on it to unprivileged users.
+    pub fn jails_filesystem_bytes(firecracker_bin: &Path) -> Result<(u64, u64)> {
+        let dir = Self::jails_directory(firecracker_bin)?;
+        std::fs::create_dir_all(&dir)?;
+        let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_dir.as_ptr(), &mut stat) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("Unable to get filesystem stats of {:?}", dir));
+        }
+        Ok((
+            stat.f_blocks as u64 * stat.f_frsize as u64,
+            stat.f_bavail as u64 * stat.f_frsize as u64,
+        ))
+    }
+
+    /// Lists the ids of all instances with a jail on disk, running or not.
+    pub fn list_jails(firecracker_bin: &Path) -> Result<Vec<String>> {
+        let dir = Self::jails_directory(firecracker_bin)?;
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(format!("Unable to list jails in {:?}", dir)),
+        };
+        let mut ids = Vec::new();
+        for entry in entries {
+            ids.push(entry?.file_name().to_string_lossy().to_string());
+        }
+        Ok(ids)
+    }
+
+    /// Kills the instance of a jail nobody is attached to, if it is still running, and removes the jail.
+    pub fn remove_jail(firecracker_bin: &Path, uuid: &str) -> Result<()> {
+        let jail_path = Self::jails_directory(firecracker_bin)?.join(uuid);
+        // The jailer records the pid of firecracker next to it in the jail root
+        let pid_file = jail_path.join("root").join(format!(
+            "{}.pid",
+            firecracker_bin
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        ));
+        if let Some(pid) = std::fs::read_to_string(pid_file)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+        {
+            kill_jailed_process(pid, uuid);
+        }
+        std::fs::remove_dir_all(&jail_path)?;
+        Ok(())
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
     pub fn uuid(&self) -> &str {
         &self.uuid
     }
@@ -189,7 +424,10 @@ impl JailedCracker {
     }
 
     pub fn kill(&mut self) -> Result<()> {
-        self.proc.kill()?;
+        match self.proc.as_mut() {
+            Some(proc) => proc.kill()?,
+            None => kill_jailed_process(self.pid, &self.uuid),
+        }
         Ok(())
     }
 
@@ -212,13 +450,20 @@ impl JailedCracker {
             is_read_only: true,
             is_root_device: true,
             path_on_host: "/root.fs".into(),
+            rate_limiter: None,
         };
 
         self.request_with_json("/drives/rootfs", Method::PUT, &drive)
             .await
     }
 
-    pub async fn create_drive(&mut self, size_gb: u64, drive_id: &str) -> Result<()> {
+    /// Creates an empty sparse drive, only taking up space on the host once the guest writes to it.
+    pub async fn create_drive(
+        &mut self,
+        size_gb: u64,
+        drive_id: &str,
+        rate_limiter: Option<RateLimiter>,
+    ) -> Result<()> {
         let fp = self.root_path.join(format!("{}.fs", drive_id));
         trace!("Creating drive {} with size {}GB", drive_id, size_gb);
         let f = std::fs::File::create(&fp)?;
@@ -232,6 +477,7 @@ impl JailedCracker {
             is_read_only: false,
             is_root_device: false,
             path_on_host: format!("/{}.fs", drive_id),
+            rate_limiter,
         };
 
         self.request_with_json(
@@ -242,8 +488,84 @@ impl JailedCracker {
         .await
     }
 
-    pub async fn set_eth_tap(&mut self, tap: &TunTap) -> Result<()> {
-        self.add_network_interface("eth0", tap.name()).await?;
+    /// The size of a drive created with `create_drive` and the bytes actually allocated for it on the host.
+    pub fn drive_usage(&self, drive_id: &str) -> Result<(u64, u64)> {
+        let metadata = std::fs::metadata(self.root_path.join(format!("{}.fs", drive_id)))?;
+        Ok((metadata.len(), metadata.blocks() * 512))
+    }
+
+    /// Attaches a read-only drive shared with other machines, linked into the jail instead of copied.
+    pub async fn add_shared_drive(&mut self, path: &Path, drive_id: &str) -> Result<()> {
+        let name = format!("{}.fs", drive_id);
+        self.link_file(path, &name)?;
+
+        trace!("Putting shared drive {} in firecracker", drive_id);
+        let drive_config = Drive {
+            drive_id: drive_id.into(),
+            is_read_only: true,
+            is_root_device: false,
+            path_on_host: format!("/{}", name),
+            rate_limiter: None,
+        };
+
+        self.request_with_json(
+            format!("/drives/{}", drive_id).as_str(),
+            Method::PUT,
+            &drive_config,
+        )
+        .await
+    }
+
+    /// Attaches a volume, which has to stay the same file to keep its data, so it can't be copied into the jail.
+    pub async fn add_volume(
+        &mut self,
+        path: &Path,
+        drive_id: &str,
+        read_only: bool,
+        rate_limiter: Option<RateLimiter>,
+    ) -> Result<()> {
+        let name = format!("{}.fs", drive_id);
+        self.link_volume(path, &name)?;
+
+        trace!("Putting volume {} in firecracker", drive_id);
+        let drive_config = Drive {
+            drive_id: drive_id.into(),
+            is_read_only: read_only,
+            is_root_device: false,
+            path_on_host: format!("/{}", name),
+            rate_limiter,
+        };
+
+        self.request_with_json(
+            format!("/drives/{}", drive_id).as_str(),
+            Method::PUT,
+            &drive_config,
+        )
+        .await
+    }
+
+    /// Hard links a volume into the jail, owned by the firecracker UID.
+    pub fn link_volume(&self, src: &Path, name: &str) -> Result<()> {
+        let dest = self.root_path.join(name);
+        trace!("Linking volume {:?} into the jail as {:?}", src, dest);
+        std::fs::hard_link(src, &dest).with_context(|| {
+            format!(
+                "Unable to link volume {:?} into the jail, it has to be on the same filesystem",
+                src
+            )
+        })?;
+        std::os::unix::fs::chown(&dest, Some(self.uid), Some(self.uid))?;
+        Ok(())
+    }
+
+    pub async fn set_eth_tap(
+        &mut self,
+        tap: &TunTap,
+        rx_rate_limiter: Option<RateLimiter>,
+        tx_rate_limiter: Option<RateLimiter>,
+    ) -> Result<()> {
+        self.add_network_interface("eth0", tap.name(), rx_rate_limiter, tx_rate_limiter)
+            .await?;
         self.config_mmds("eth0").await?;
         Ok(())
     }
@@ -252,6 +574,8 @@ impl JailedCracker {
         &mut self,
         guest_name: &str,
         host_dev_name: &str,
+        rx_rate_limiter: Option<RateLimiter>,
+        tx_rate_limiter: Option<RateLimiter>,
     ) -> Result<()> {
         trace!(
             "Adding {} as network interface {} to firecracker",
@@ -261,6 +585,8 @@ impl JailedCracker {
         let interface = NetworkInterface {
             host_dev_name: host_dev_name.into(),
             iface_id: guest_name.into(),
+            rx_rate_limiter,
+            tx_rate_limiter,
         };
         self.request_with_json(
             format!("/network-interfaces/{}", guest_name).as_str(),
@@ -270,6 +596,238 @@ impl JailedCracker {
         .await
     }
 
+    /// Replaces the rate limiter of a drive of the running VM, `None` removes it.
+    pub async fn update_drive_rate_limiter(
+        &mut self,
+        drive_id: &str,
+        rate_limiter: Option<RateLimiter>,
+    ) -> Result<()> {
+        trace!("Updating rate limiter of drive {}", drive_id);
+        self.request_with_json(
+            format!("/drives/{}", drive_id).as_str(),
+            Method::PATCH,
+            &PartialDrive {
+                drive_id: drive_id.into(),
+                rate_limiter: RateLimiter::replacing(rate_limiter),
+            },
+        )
+        .await
+    }
+
+    /// Replaces the rate limiters of a network interface of the running VM, `None` removes them.
+    pub async fn update_network_rate_limiters(
+        &mut self,
+        guest_name: &str,
+        rx_rate_limiter: Option<RateLimiter>,
+        tx_rate_limiter: Option<RateLimiter>,
+    ) -> Result<()> {
+        trace!("Updating rate limiters of network interface {}", guest_name);
+        self.request_with_json(
+            format!("/network-interfaces/{}", guest_name).as_str(),
+            Method::PATCH,
+            &PartialNetworkInterface {
+                iface_id: guest_name.into(),
+                rx_rate_limiter: RateLimiter::replacing(rx_rate_limiter),
+                tx_rate_limiter: RateLimiter::replacing(tx_rate_limiter),
+            },
+        )
+        .await
+    }
+
+    /// Copies a file out of the jail root, e.g. a drive of a paused VM.
+    /// Holes are kept, drives are sparse and mostly empty.
+    pub fn export_file(&self, name: &str, dest: &Path) -> Result<()> {
+        let src = self.root_path.join(name);
+        trace!("Copying {:?} out of the jail to {:?}", src, dest);
+        sparse_copy(&src, dest)
+    }
+
+    /// Copies a file into the jail root, owned by the firecracker UID.
+    pub fn import_file(&self, src: &Path, name: &str) -> Result<()> {
+        let dest = self.root_path.join(name);
+        trace!("Copying {:?} into the jail as {:?}", src, dest);
+        sparse_copy(src, &dest)?;
+        std::os::unix::fs::chown(&dest, Some(self.uid), Some(self.uid))?;
+        Ok(())
+    }
+
+    /// Hard links a file into the jail, falling back to a copy across filesystems.
+    /// The file is not chowned, it has to be readable by the jailer uid already.
+    pub fn link_file(&self, src: &Path, name: &str) -> Result<()> {
+        let dest = self.root_path.join(name);
+        trace!("Linking {:?} into the jail as {:?}", src, dest);
+        if let Err(e) = std::fs::hard_link(src, &dest) {
+            debug!("Unable to hard link {:?} ({}), copying instead", src, e);
+            sparse_copy(src, &dest)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the Firecracker log to a FIFO in the jail and returns its read end. Must be configured before the VM starts.
+    pub async fn set_logger(&mut self) -> Result<pipe::Receiver> {
+        let fifo = self.create_fifo(LOGGER_FIFO)?;
+        trace!("Setting firecracker logger to {}", LOGGER_FIFO);
+        self.request_with_json(
+            "/logger",
+            Method::PUT,
+            &Logger {
+                log_path: format!("/{}", LOGGER_FIFO),
+                level: LogLevel::Warning,
+                show_level: true,
+                show_log_origin: false,
+            },
+        )
+        .await?;
+        Ok(fifo)
+    }
+
+    /// Sends the Firecracker metrics to a FIFO in the jail and returns its read end. Must be configured before the VM starts.
+    pub async fn set_metrics(&mut self) -> Result<pipe::Receiver> {
+        let fifo = self.create_fifo(METRICS_FIFO)?;
+        trace!("Setting firecracker metrics to {}", METRICS_FIFO);
+        self.request_with_json(
+            "/metrics",
+            Method::PUT,
+            &Metrics {
+                metrics_path: format!("/{}", METRICS_FIFO),
+            },
+        )
+        .await?;
+        Ok(fifo)
+    }
+
+    /// Opens the logger and metrics FIFOs again, after reattaching to a running instance.
+    pub fn open_output_fifos(&self) -> Result<(pipe::Receiver, pipe::Receiver)> {
+        Ok((self.open_fifo(LOGGER_FIFO)?, self.open_fifo(METRICS_FIFO)?))
+    }
+
+    /// Makes Firecracker write its metrics now instead of at the next 60 second interval.
+    pub async fn flush_metrics(&mut self) -> Result<()> {
+        self.request_with_json(
+            "/actions",
+            Method::PUT,
+            &InstanceActionInfo {
+                action_type: InstanceAction::FlushMetrics,
+            },
+        )
+        .await
+    }
+
+    fn create_fifo(&self, name: &str) -> Result<pipe::Receiver> {
+        let path = self.root_path.join(name);
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("Unable to create FIFO {:?}", path));
+        }
+        std::os::unix::fs::chown(&path, Some(self.uid), Some(self.uid))?;
+        self.open_fifo(name)
+    }
+
+    /// Opened for writing as well, so reading doesn't end before Firecracker opened it or after it exited.
+    fn open_fifo(&self, name: &str) -> Result<pipe::Receiver> {
+        let path = self.root_path.join(name);
+        pipe::OpenOptions::new()
+            .read_write(true)
+            .open_receiver(&path)
+            .with_context(|| format!("Unable to open FIFO {:?}", path))
+    }
+
+    /// Adds a deflated balloon device, which keeps the memory it took when the guest runs out.
+    pub async fn set_balloon(&mut self) -> Result<()> {
+        trace!("Adding balloon device to firecracker");
+        self.request_with_json(
+            "/balloon",
+            Method::PUT,
+            &Balloon {
+                amount_mib: 0,
+                deflate_on_oom: false,
+                stats_polling_interval_s: BALLOON_STATS_INTERVAL_S,
+            },
+        )
+        .await
+    }
+
+    /// Sets how much memory the balloon should take from the guest of the running VM.
+    pub async fn update_balloon(&mut self, amount_mib: u32) -> Result<()> {
+        debug!(
+            "Setting balloon of firecracker instance {} to {} MiB",
+            self.uuid, amount_mib
+        );
+        self.request_with_json("/balloon", Method::PATCH, &BalloonUpdate { amount_mib })
+            .await
+    }
+
+    pub async fn balloon_stats(&mut self) -> Result<BalloonStats> {
+        self.get_json("/balloon/statistics").await
+    }
+
+    pub async fn pause(&mut self) -> Result<()> {
+        debug!("Pausing firecracker instance {}", self.uuid);
+        self.request_with_json(
+            "/vm",
+            Method::PATCH,
+            &Vm {
+                state: VmState::Paused,
+            },
+        )
+        .await
+    }
+
+    pub async fn resume(&mut self) -> Result<()> {
+        debug!("Resuming firecracker instance {}", self.uuid);
+        self.request_with_json(
+            "/vm",
+            Method::PATCH,
+            &Vm {
+                state: VmState::Resumed,
+            },
+        )
+        .await
+    }
+
+    /// Creates a full snapshot of the paused VM and moves the state and memory files to `dest_dir`.
+    pub async fn create_snapshot(&mut self, dest_dir: &Path) -> Result<()> {
+        debug!("Creating snapshot of firecracker instance {}", self.uuid);
+        self.request_with_json(
+            "/snapshot/create",
+            Method::PUT,
+            &SnapshotCreateParams {
+                snapshot_type: SnapshotType::Full,
+                snapshot_path: format!("/{}", SNAPSHOT_STATE_FILE),
+                mem_file_path: format!("/{}", SNAPSHOT_MEMORY_FILE),
+            },
+        )
+        .await?;
+        for file in [SNAPSHOT_STATE_FILE, SNAPSHOT_MEMORY_FILE] {
+            self.export_file(file, &dest_dir.join(file))?;
+            std::fs::remove_file(self.root_path.join(file))?;
+        }
+        Ok(())
+    }
+
+    /// Loads and resumes a snapshot from `src_dir`. Must be called on a fresh, unconfigured VM.
+    pub async fn load_snapshot(&mut self, src_dir: &Path) -> Result<()> {
+        for file in [SNAPSHOT_STATE_FILE, SNAPSHOT_MEMORY_FILE] {
+            self.import_file(&src_dir.join(file), file)?;
+        }
+        debug!("Loading snapshot into firecracker instance {}", self.uuid);
+        self.request_with_json(
+            "/snapshot/load",
+            Method::PUT,
+            &SnapshotLoadParams {
+                snapshot_path: format!("/{}", SNAPSHOT_STATE_FILE),
+                mem_backend: MemBackend {
+                    backend_type: MemBackendType::File,
+                    backend_path: format!("/{}", SNAPSHOT_MEMORY_FILE),
+                },
+                enable_diff_snapshots: false,
+                resume_vm: true,
+            },
+        )
+        .await
+    }
+
     async fn add_host_vsock(&mut self, guest_cid: u32) -> Result<PathBuf> {
         trace!("Adding vsock with guest CID {}", guest_cid);
         let vsock = Vsock {
@@ -283,8 +841,16 @@ impl JailedCracker {
 
     pub async fn open_vsock_listener(&mut self, port: u32) -> Result<UnixListener> {
         let _ = self.add_host_vsock(u32::MAX).await?;
+        self.bind_vsock_listener(port)
+    }
+
+    /// Binds the host side listener for guest initiated connections on `port`.
+    /// Unlike `open_vsock_listener` it does not configure the vsock device, as when it is restored from a snapshot.
+    pub fn bind_vsock_listener(&mut self, port: u32) -> Result<UnixListener> {
         let vsock_path = self.root_path.join(format!("run/v.sock_{}", port));
         trace!("Opening vsock listener on path {:?}", vsock_path);
+        // Left behind by a previous listener if the nodemanager was restarted
+        let _ = std::fs::remove_file(&vsock_path);
         let listener = UnixListener::bind(&vsock_path)
             .with_context(|| format!("Unable to bind vsock listener on {:?}", vsock_path))?;
         // Chown the vsock path to the firecracker UID
@@ -304,6 +870,11 @@ impl JailedCracker {
             .await
     }
 
+    /// Merges `patch` into the MMDS data store, `null` values remove keys.
+    pub async fn patch_mmds(&mut self, patch: &serde_json::Value) -> Result<()> {
+        self.request_with_json("/mmds", Method::PATCH, patch).await
+    }
+
     pub async fn set_boot(&mut self, kernel_img: &Path, boot_args: &str) -> Result<()> {
         let dest = self.root_path.join("kernel.img");
         //TODO: Mount this?
@@ -372,4 +943,60 @@ impl JailedCracker {
             }
         }
     }
+
+    async fn get_json<T: DeserializeOwned>(&mut self, route: &str) -> Result<T> {
+        match self
+            .api_client
+            .send_request(route, Method::GET, &[("Host", "localhost")], None)
+            .await
+        {
+            Err(e) => Err(anyhow!("Firecracker API request failed: {}", e)),
+            Ok((status_code, body)) => {
+                if !status_code.is_success() {
+                    return Err(anyhow!(
+                        "Firecracker API request failed with status code: {}",
+                        status_code
+                    ));
+                }
+                serde_json::from_slice(&body)
+                    .with_context(|| format!("Unable to parse FC response from {}", route))
+            }
+        }
+    }
+}
+
+async fn connect_api(root_path: &Path) -> Result<ClientUnix> {
+    Ok(ClientUnix::try_new(
+        root_path
+            .join(API_SOCKET_PATH)
+            .to_str()
+            .ok_or(anyhow!("Unable to get path to API socket."))?,
+    )
+    .await?)
+}
+
+/// Whether `pid` is the firecracker process of the jail `uuid`, and not a reused pid.
+fn is_jailed_process(pid: u32, uuid: &str) -> bool {
+    std::fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| cmdline.split(|b| *b == 0).any(|arg| arg == uuid.as_bytes()))
+        .unwrap_or(false)
+}
+
+fn kill_jailed_process(pid: u32, uuid: &str) {
+    if is_jailed_process(pid, uuid) {
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+}
+
+fn sparse_copy(src: &Path, dest: &Path) -> Result<()> {
+    cmd(
+        "cp",
+        &[
+            "--sparse=always",
+            src.to_str().ok_or(anyhow!("Invalid path {:?}", src))?,
+            dest.to_str().ok_or(anyhow!("Invalid path {:?}", dest))?,
+        ],
+    )
 }
@@ -1,34 +1,288 @@
-use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
+    capacity::Resources,
+    images::RegistryAuth,
+    labels::Labels,
     machine::{
         firecracker,
-        vsock::{MachineExit, MachineLog},
+        vmm::VmmOutput,
+        vsock::{ExecEvent, MachineExit, MachineLog},
     },
     networking::NetworkStack,
+    volumes::VolumeMount,
 };
 
-use super::{firecracker::JailedCracker, vsock::MachineCommunicator};
+use super::{
+    firecracker::{BalloonStats, JailedCracker, RateLimiter, TokenBucket},
+    vsock::MachineCommunicator,
+};
 use anyhow::Result;
-use log::trace;
+use log::{debug, trace};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+use uuid::Uuid;
+use vmproto::guest::{get_timestamp_ms, InitVmState};
+
+// Drives as named inside the jail by `JailedCracker`
+const SNAPSHOT_DRIVE_FILES: &[&str] = &["root.fs", "drive0.fs"];
+const SNAPSHOT_METADATA_FILE: &str = "snapshot.json";
+
+// The guest retries connecting for 30 seconds, see `HostCommunication::reconnect` in the instance
+const GUEST_REATTACH_TIMEOUT: Duration = Duration::from_secs(35);
+
+const SCRATCH_DRIVE_ID: &str = "drive0";
+// Size of the scratch drive of machines recorded before it was configurable
+const LEGACY_SCRATCH_DRIVE_GB: u64 = 8;
+
+const CACHED_IMAGE_DRIVE_ID: &str = "image";
+// Attached after the rootfs (vda) and the scratch drive (vdb)
+const CACHED_IMAGE_GUEST_DEVICE: &str = "/dev/vdc";
+// Volumes are attached last, as `volume0`, `volume1`, ...
+const VOLUME_DRIVE_PREFIX: &str = "volume";
 
 pub struct Machine {
-    vm: JailedCracker,
+    uuid: String,
+    created_at_ms: u64,
+    restart_count: u32,
+    booted_at_ms: u64,
+    restart_streak: u32, // Restarts in a row, each after a short run
+    vm: Mutex<JailedCracker>,
     comm: Option<(Arc<Mutex<MachineCommunicator>>, tokio::task::JoinHandle<()>)>,
+    vmm_output: Option<VmmOutput>, // Missing when reattached to a machine started without it
     network: Mutex<NetworkStack>,
+    config: MachineConfig,
+    overrides: ContainerOverrides, // Kept to boot the container the same way on a restart
+    vsock_port: u32,
+    boot_timings: BootTimings,
+    reclaimed_mb: AtomicU64, // Memory taken back by the balloon, as of its latest statistics
+}
+
+/// How long the phases of booting a machine took, zero for machines which were restored or reattached.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BootTimings {
+    pub jailer_spawn: Duration,
+    pub configure: Duration, // Requests to the Firecracker API up to starting the VM
+    pub vsock_accept: Duration,
 }
 
+/// Everything besides the files needed to bring a snapshotted machine back.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotMetadata {
+    pub config: MachineConfig,
+    #[serde(default)]
+    pub overrides: ContainerOverrides,
+    pub vsock_port: u32,
+    pub network_slot: u16, // The guest network is configured at boot, so the same slot must be reused
+    #[serde(default)]
+    pub created_at_ms: u64, // 0 for snapshots taken before it was recorded
+}
+
+/// What is needed to reattach to a running machine after the nodemanager was restarted.
+#[derive(Serialize, Deserialize)]
+pub struct MachineState {
+    pub uuid: String,
+    #[serde(default)]
+    pub created_at_ms: u64,
+    #[serde(default)]
+    pub restart_count: u32,
+    #[serde(default)]
+    pub booted_at_ms: u64,
+    #[serde(default)]
+    pub restart_streak: u32,
+    pub pid: u32,
+    pub config: MachineConfig,
+    #[serde(default)]
+    pub overrides: ContainerOverrides,
+    pub vsock_port: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MachineConfig {
     pub container_reference: String,
     pub vcpu_count: u8,
     pub mem_size_mb: u32,
+    pub terminal: bool,
+    pub stdin: bool,
+    #[serde(default)]
+    pub cached_image: Option<PathBuf>, // Filesystem from the `ImageCache`, the guest pulls the image itself without it
+    #[serde(default)]
+    pub image_digest: Option<String>, // Manifest digest of the cached image
+    #[serde(default)]
+    pub labels: Labels,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub volumes: Vec<VolumeMount>,
+    #[serde(default = "legacy_scratch_drive_gb")]
+    pub scratch_disk_gb: u64,
+    #[serde(default)]
+    pub scratch_filesystem: ScratchFilesystem,
+    #[serde(default)]
+    pub io_limits: IoLimits,
+    #[serde(default)]
+    pub balloon: bool, // Machines recorded before the balloon device have none
+    #[serde(default)]
+    pub private_networks: Vec<String>, // Attached as eth1, eth2, ... in this order
+}
+
+impl MachineConfig {
+    pub fn resources(&self) -> Resources {
+        Resources {
+            vcpus: self.vcpu_count as u32,
+            memory_mb: self.mem_size_mb as u64,
+            disk_gb: self.scratch_disk_gb,
+        }
+    }
+}
+
+fn legacy_scratch_drive_gb() -> u64 {
+    LEGACY_SCRATCH_DRIVE_GB
+}
+
+/// How the guest formats and mounts its scratch drive, which holds the container bundle.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScratchFilesystem {
+    pub fs_type: String,            // Any type mke2fs supports: ext2, ext3 or ext4
+    pub features: Vec<String>,      // Passed to mke2fs with -O
+    pub mount_options: Vec<String>, // Passed to mount with -o
+}
+
+impl Default for ScratchFilesystem {
+    fn default() -> Self {
+        Self {
+            fs_type: "ext4".to_string(),
+            features: vec!["^has_journal".to_string()],
+            mount_options: Vec::new(),
+        }
+    }
+}
+
+impl ScratchFilesystem {
+    const FS_TYPES: [&'static str; 3] = ["ext2", "ext3", "ext4"];
+
+    pub fn validate(&self) -> Result<()> {
+        if !Self::FS_TYPES.contains(&self.fs_type.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Unsupported scratch filesystem {}, expected one of {}",
+                self.fs_type,
+                Self::FS_TYPES.join(", ")
+            ));
+        }
+        // The guest joins them with commas into a single argument of mke2fs and mount
+        for option in self.features.iter().chain(&self.mount_options) {
+            if option.is_empty()
+                || option.starts_with('-')
+                || option.contains(|c: char| c == ',' || c.is_whitespace() || c.is_control())
+            {
+                return Err(anyhow::anyhow!(
+                    "Invalid scratch filesystem option {:?}",
+                    option
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rate limits of the devices of a machine, unlimited if unset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IoLimits {
+    pub drive: Option<RateLimiter>, // The scratch drive and every volume get a limiter each
+    pub network_rx: Option<RateLimiter>,
+    pub network_tx: Option<RateLimiter>,
+}
+
+impl IoLimits {
+    pub fn as_proto(&self) -> proto::node::IoLimits {
+        let limiter = |limiter: Option<RateLimiter>| {
+            limiter.map(|limiter| proto::node::RateLimiter {
+                bandwidth: limiter.bandwidth.map(token_bucket_message),
+                ops: limiter.ops.map(token_bucket_message),
+            })
+        };
+        proto::node::IoLimits {
+            drive: limiter(self.drive),
+            network_rx: limiter(self.network_rx),
+            network_tx: limiter(self.network_tx),
+        }
+    }
+}
+
+pub fn balloon_message(stats: BalloonStats) -> proto::node::BalloonStats {
+    proto::node::BalloonStats {
+        target_mb: stats.target_mib,
+        actual_mb: stats.actual_mib,
+        free_memory_bytes: stats.free_memory,
+        available_memory_bytes: stats.available_memory,
+        total_memory_bytes: stats.total_memory,
+    }
+}
+
+fn token_bucket_message(bucket: TokenBucket) -> proto::node::TokenBucket {
+    proto::node::TokenBucket {
+        size: bucket.size,
+        refill_time_ms: bucket.refill_time,
+        one_time_burst: bucket.one_time_burst,
+    }
 }
 
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct ContainerOverrides {
     pub cmd_args: Option<Vec<String>>,
     pub env: Option<BTreeMap<String, String>>,
+    #[serde(skip)] // Not written to disk, the image is usually cached anyway
+    pub registry_auth: Option<RegistryAuth>,
+}
+
+/// Whether a machine is booted again once its container exited on its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure {
+        max_retries: Option<u32>, // Without limit if unset
+    },
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn should_restart(&self, exit: MachineExit, restart_count: u32) -> bool {
+        // Only requested by the host when the machine is removed anyway
+        if exit == MachineExit::GracefulShutdown {
+            return false;
+        }
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure { max_retries } => {
+                exit != MachineExit::ContainerExited(0)
+                    && max_retries.is_none_or(|max_retries| restart_count < max_retries)
+            }
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// The restart streak a run continues, which starts over once a run lasted `stable_after`.
+fn continued_restart_streak(restart_streak: u32, uptime: Duration, stable_after: Duration) -> u32 {
+    if uptime >= stable_after {
+        return 0;
+    }
+    restart_streak
 }
 
 #[derive(Deserialize)]
@@ -45,6 +299,50 @@ impl Machine {
         config: MachineConfig,
         network_stack: NetworkStack,
         overrides: ContainerOverrides,
+    ) -> Result<(Self, tokio::sync::oneshot::Receiver<MachineExit>)> {
+        let uuid = Uuid::new_v4().to_string();
+        Self::boot(
+            fc_config,
+            uuid,
+            get_timestamp_ms(),
+            0,
+            0,
+            config,
+            network_stack,
+            overrides,
+        )
+        .await
+    }
+
+    /// Boots a machine which exited again under the same id and with the same network, after it was shut down.
+    pub async fn restart(
+        fc_config: &FirecrackerConfig,
+        state: MachineState,
+        network_stack: NetworkStack,
+    ) -> Result<(Self, tokio::sync::oneshot::Receiver<MachineExit>)> {
+        Self::boot(
+            fc_config,
+            state.uuid,
+            state.created_at_ms,
+            state.restart_count + 1,
+            state.restart_streak + 1,
+            state.config,
+            network_stack,
+            state.overrides,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)] // Only shared by new and restart
+    async fn boot(
+        fc_config: &FirecrackerConfig,
+        uuid: String,
+        created_at_ms: u64,
+        restart_count: u32,
+        restart_streak: u32,
+        config: MachineConfig,
+        network_stack: NetworkStack,
+        overrides: ContainerOverrides,
     ) -> Result<(Self, tokio::sync::oneshot::Receiver<MachineExit>)> {
         #[derive(Serialize)]
         struct Config {
@@ -52,6 +350,40 @@ impl Machine {
             cmd_args: Option<Vec<String>>,
             env: Option<BTreeMap<String, String>>,
             vsock_port: u32,
+            terminal: bool,
+            stdin: bool,
+            image_drive: Option<&'static str>,
+            registry_auth: Option<RegistryAuth>,
+            volumes: Vec<Volume>,
+            scratch_filesystem: ScratchFilesystem,
+            ipv6: Option<Ipv6>,
+            private_networks: Vec<PrivateNetwork>,
+        }
+
+        #[derive(Serialize)]
+        struct Volume {
+            name: String,
+            device: String,
+            mount_path: String,
+            read_only: bool,
+        }
+
+        // The kernel `ip=` argument only configures IPv4, the guest sets up IPv6 itself
+        #[derive(Serialize)]
+        struct Ipv6 {
+            address: String,
+            prefix_len: u8,
+            gateway: String,
+        }
+
+        #[derive(Serialize)]
+        struct PrivateNetwork {
+            name: String,
+            interface: String,
+            address: String,
+            prefix_len: u8,
+            gateway: String,
+            subnet: String, // Routed through the gateway
         }
 
         #[derive(Serialize)]
@@ -68,37 +400,73 @@ impl Machine {
 
         let metadata = Metadata {
             container: Config {
-                image: config.container_reference,
-                cmd_args: overrides.cmd_args,
-                env: overrides.env,
+                image: config.container_reference.clone(),
+                cmd_args: overrides.cmd_args.clone(),
+                env: overrides.env.clone(),
                 vsock_port,
+                terminal: config.terminal,
+                stdin: config.stdin,
+                image_drive: config
+                    .cached_image
+                    .as_ref()
+                    .map(|_| CACHED_IMAGE_GUEST_DEVICE),
+                registry_auth: overrides.registry_auth.clone(),
+                volumes: config
+                    .volumes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, volume)| Volume {
+                        name: volume.name.clone(),
+                        device: volume_guest_device(&config, i),
+                        mount_path: volume.mount_path.clone(),
+                        read_only: volume.read_only,
+                    })
+                    .collect(),
+                scratch_filesystem: config.scratch_filesystem.clone(),
+                ipv6: network_stack.ipv6().map(|ipv6| Ipv6 {
+                    address: ipv6.addr.to_string(),
+                    prefix_len: ipv6.prefix_len,
+                    gateway: ipv6.gateway.to_string(),
+                }),
+                private_networks: network_stack
+                    .private_networks()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, attachment)| PrivateNetwork {
+                        name: attachment.network().to_string(),
+                        interface: private_network_interface(i),
+                        address: attachment.ipv4_addr().to_string(),
+                        prefix_len: 30,
+                        gateway: attachment.gateway().to_string(),
+                        subnet: attachment.subnet().to_string(),
+                    })
+                    .collect(),
             },
         };
+        let has_registry_auth = metadata.container.registry_auth.is_some();
 
         let metadata = serde_json::to_string(&Latest { latest: metadata })?;
 
-        // DEBUG_MACHINE_OUT flag is set
-        let debug_machine_out = std::env::var("DEBUG_MACHINE_OUT").is_ok();
-
-        if debug_machine_out {
-            log::warn!(
-                "Debugging machine output enabled, this should NEVER be used in production!"
-            );
-        } else {
-            log::debug!("Debugging machine output disabled");
-        }
+        let debug_machine_out = debug_machine_out();
 
+        let started = Instant::now();
         let mut vm = firecracker::JailedCracker::spawn(
             &fc_config.jailer_binary,
             &fc_config.firecracker_binary,
+            &uuid,
             0,
             Some(&metadata),
             !debug_machine_out,
         )
         .await?;
+        let jailer_spawned = Instant::now();
 
+        let vmm_output = VmmOutput::spawn(&uuid, vm.set_logger().await?, vm.set_metrics().await?);
         vm.set_machine_config(config.vcpu_count, config.mem_size_mb)
             .await?;
+        if config.balloon {
+            vm.set_balloon().await?;
+        }
 
         let console_arg = match debug_machine_out {
             true => "console=ttyS0",
@@ -114,46 +482,591 @@ impl Machine {
         trace!("Setting kernel boot args: {}", boot_args);
         vm.set_boot(&fc_config.kernel_image, &boot_args).await?;
         vm.set_rootfs(&fc_config.rootfs).await?;
-        vm.create_drive(8, "drive0").await?;
-        vm.set_eth_tap(network_stack.nic()).await?;
+        vm.create_drive(
+            config.scratch_disk_gb,
+            SCRATCH_DRIVE_ID,
+            config.io_limits.drive,
+        )
+        .await?;
+        if let Some(cached_image) = &config.cached_image {
+            vm.add_shared_drive(cached_image, CACHED_IMAGE_DRIVE_ID)
+                .await?;
+        }
+        for (i, volume) in config.volumes.iter().enumerate() {
+            vm.add_volume(
+                &volume.path,
+                &format!("{}{}", VOLUME_DRIVE_PREFIX, i),
+                volume.read_only,
+                config.io_limits.drive,
+            )
+            .await?;
+        }
+        vm.set_eth_tap(
+            network_stack.nic(),
+            config.io_limits.network_rx,
+            config.io_limits.network_tx,
+        )
+        .await?;
+        // The guest names the interfaces in the order they are added, each one is limited separately
+        for (i, attachment) in network_stack.private_networks().iter().enumerate() {
+            vm.add_network_interface(
+                &private_network_interface(i),
+                attachment.nic().name(),
+                config.io_limits.network_rx,
+                config.io_limits.network_tx,
+            )
+            .await?;
+        }
 
         let listener = vm.open_vsock_listener(vsock_port).await?;
 
+        vm.start_vm().await?;
+        let configured = Instant::now();
+
+        let stream = accept_guest(&listener, Duration::from_millis(500)).await?;
+        let boot_timings = BootTimings {
+            jailer_spawn: jailer_spawned - started,
+            configure: configured - jailer_spawned,
+            vsock_accept: configured.elapsed(),
+        };
+
+        // The guest has read its config before connecting, the container must not find the credentials in MMDS
+        if has_registry_auth {
+            vm.patch_mmds(&serde_json::json!({
+                "latest": { "container": { "registry_auth": null } }
+            }))
+            .await?;
+        }
+
         let mut machine = Self {
-            vm,
+            uuid,
+            created_at_ms,
+            restart_count,
+            booted_at_ms: get_timestamp_ms(),
+            restart_streak,
+            vm: Mutex::new(vm),
             network: Mutex::new(network_stack),
             comm: None,
+            vmm_output: Some(vmm_output),
+            config,
+            overrides,
+            vsock_port,
+            boot_timings,
+            reclaimed_mb: AtomicU64::new(0),
         };
-        machine.vm.start_vm().await?;
 
-        let connection = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+        machine.comm = Some(MachineCommunicator::spawn(listener, stream, stop_tx).await);
+
+        Ok((machine, stop_rx))
+    }
+
+    /// Brings back a machine from a snapshot taken with `Machine::snapshot`, as a new instance.
+    pub async fn restore(
+        fc_config: &FirecrackerConfig,
+        snapshot_dir: &Path,
+        metadata: SnapshotMetadata,
+        network_stack: NetworkStack,
+    ) -> Result<(Self, tokio::sync::oneshot::Receiver<MachineExit>)> {
+        let mut vm = firecracker::JailedCracker::spawn(
+            &fc_config.jailer_binary,
+            &fc_config.firecracker_binary,
+            &Uuid::new_v4().to_string(),
+            0,
+            None,
+            !debug_machine_out(),
+        )
+        .await?;
 
-        let stream = match connection {
-            Ok(Ok((stream, _))) => stream,
-            Ok(Err(e)) => {
-                log::error!("Failed to accept vsock connection: {}", e);
-                return Err(e.into());
+        let uuid = vm.uuid().to_string();
+        let restored = async {
+            let vmm_output =
+                VmmOutput::spawn(&uuid, vm.set_logger().await?, vm.set_metrics().await?);
+            for file in SNAPSHOT_DRIVE_FILES {
+                vm.import_file(&snapshot_dir.join(file), file)?;
+            }
+            if let Some(cached_image) = &metadata.config.cached_image {
+                vm.link_file(cached_image, &format!("{}.fs", CACHED_IMAGE_DRIVE_ID))?;
             }
-            Err(_) => {
-                log::error!("Timeout accepting vsock connection");
-                return Err(anyhow::anyhow!("Timeout accepting vsock connection"));
+            for (i, volume) in metadata.config.volumes.iter().enumerate() {
+                vm.link_volume(&volume.path, &format!("{}{}.fs", VOLUME_DRIVE_PREFIX, i))?;
+            }
+            let listener = vm.bind_vsock_listener(metadata.vsock_port)?;
+            vm.load_snapshot(snapshot_dir).await?;
+            // The vsock transport is reset on load, the guest reconnects once it notices
+            let stream = accept_guest(&listener, Duration::from_secs(5)).await?;
+            Ok::<_, anyhow::Error>((listener, stream, vmm_output))
+        }
+        .await;
+        let (listener, stream, vmm_output) = match restored {
+            Ok(restored) => restored,
+            Err(e) => {
+                let _ = vm.cleanup();
+                return Err(e);
             }
         };
 
+        let mut machine = Self {
+            uuid,
+            created_at_ms: get_timestamp_ms(),
+            restart_count: 0,
+            booted_at_ms: get_timestamp_ms(),
+            restart_streak: 0,
+            vm: Mutex::new(vm),
+            network: Mutex::new(network_stack),
+            comm: None,
+            vmm_output: Some(vmm_output),
+            config: metadata.config,
+            overrides: metadata.overrides,
+            vsock_port: metadata.vsock_port,
+            boot_timings: BootTimings::default(),
+            reclaimed_mb: AtomicU64::new(0),
+        };
+
         let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
-        machine.comm = Some(MachineCommunicator::spawn(stream, stop_tx).await);
+        machine.comm = Some(MachineCommunicator::spawn(listener, stream, stop_tx).await);
 
         Ok((machine, stop_rx))
     }
 
+    /// Takes over a machine started by an earlier nodemanager process. The guest reconnects once it notices it lost the host.
+    pub async fn reattach(
+        fc_config: &FirecrackerConfig,
+        state: MachineState,
+        network_stack: NetworkStack,
+    ) -> Result<(Self, tokio::sync::oneshot::Receiver<MachineExit>)> {
+        let mut vm = firecracker::JailedCracker::reattach(
+            &fc_config.firecracker_binary,
+            0,
+            &state.uuid,
+            state.pid,
+        )
+        .await?;
+
+        let reattached = async {
+            let listener = vm.bind_vsock_listener(state.vsock_port)?;
+            let stream = accept_guest(&listener, GUEST_REATTACH_TIMEOUT).await?;
+            Ok::<_, anyhow::Error>((listener, stream))
+        }
+        .await;
+        let (listener, stream) = match reattached {
+            Ok(reattached) => reattached,
+            Err(e) => {
+                let _ = vm.cleanup();
+                return Err(e);
+            }
+        };
+        let vmm_output = match vm.open_output_fifos() {
+            Ok((logger, metrics)) => Some(VmmOutput::spawn(&state.uuid, logger, metrics)),
+            Err(e) => {
+                debug!("No firecracker output to read for {}: {:?}", state.uuid, e);
+                None
+            }
+        };
+
+        let mut machine = Self {
+            uuid: vm.uuid().to_string(),
+            created_at_ms: state.created_at_ms,
+            restart_count: state.restart_count,
+            booted_at_ms: state.booted_at_ms,
+            restart_streak: state.restart_streak,
+            vm: Mutex::new(vm),
+            network: Mutex::new(network_stack),
+            comm: None,
+            vmm_output,
+            config: state.config,
+            overrides: state.overrides,
+            vsock_port: state.vsock_port,
+            boot_timings: BootTimings::default(),
+            reclaimed_mb: AtomicU64::new(0),
+        };
+
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+        machine.comm = Some(MachineCommunicator::spawn(listener, stream, stop_tx).await);
+
+        Ok((machine, stop_rx))
+    }
+
+    /// Kills and removes the machines with a jail on disk which are not `in_use`, e.g. left over after a crash.
+    pub fn remove_orphaned_jails(
+        fc_config: &FirecrackerConfig,
+        in_use: impl Fn(&str) -> bool,
+    ) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for uuid in firecracker::JailedCracker::list_jails(&fc_config.firecracker_binary)? {
+            if in_use(&uuid) {
+                continue;
+            }
+            firecracker::JailedCracker::remove_jail(&fc_config.firecracker_binary, &uuid)?;
+            removed.push(uuid);
+        }
+        Ok(removed)
+    }
+
+    pub async fn state(&self) -> MachineState {
+        MachineState {
+            uuid: self.uuid.clone(),
+            created_at_ms: self.created_at_ms,
+            restart_count: self.restart_count,
+            booted_at_ms: self.booted_at_ms,
+            restart_streak: self.restart_streak,
+            pid: self.vm.lock().await.pid(),
+            config: self.config.clone(),
+            overrides: self.overrides.clone(),
+            vsock_port: self.vsock_port,
+        }
+    }
+
+    /// Fails for snapshots which are still being written, the metadata comes last.
+    pub fn read_snapshot_metadata(snapshot_dir: &Path) -> Result<SnapshotMetadata> {
+        let metadata = std::fs::read_to_string(snapshot_dir.join(SNAPSHOT_METADATA_FILE))?;
+        Ok(serde_json::from_str(&metadata)?)
+    }
+
+    /// Pauses the machine and writes a full snapshot with its drives to `dest_dir`.
+    /// The machine is left paused unless `resume` is set or the snapshot fails.
+    pub async fn snapshot(&self, dest_dir: &Path, resume: bool) -> Result<()> {
+        let mut vm = self.vm.lock().await;
+        vm.pause().await?;
+
+        let res = async {
+            std::fs::create_dir_all(dest_dir)?;
+            vm.create_snapshot(dest_dir).await?;
+            for file in SNAPSHOT_DRIVE_FILES {
+                vm.export_file(file, &dest_dir.join(file))?;
+            }
+            let metadata = SnapshotMetadata {
+                config: self.config.clone(),
+                overrides: self.overrides.clone(),
+                vsock_port: self.vsock_port,
+                network_slot: self.network.lock().await.slot_id(),
+                created_at_ms: get_timestamp_ms(),
+            };
+            std::fs::write(
+                dest_dir.join(SNAPSHOT_METADATA_FILE),
+                serde_json::to_string(&metadata)?,
+            )?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        if resume || res.is_err() {
+            vm.resume().await?;
+        }
+        res
+    }
+
     pub fn uuid(&self) -> &str {
-        self.vm.uuid()
+        &self.uuid
+    }
+
+    pub async fn inspect(&self) -> proto::node::InstanceInfo {
+        let network = self.network.lock().await;
+        let (state, exit) = match self.comm.as_ref() {
+            Some((comm, _)) => {
+                let comm = comm.lock().await;
+                (comm.state(), comm.exit())
+            }
+            None => (None, None),
+        };
+        proto::node::InstanceInfo {
+            id: self.uuid.clone(),
+            container_reference: self.config.container_reference.clone(),
+            image_digest: self.config.image_digest.clone().unwrap_or_default(),
+            vcpus: self.config.vcpu_count as i32,
+            memory_mb: self.config.mem_size_mb as i32,
+            terminal: self.config.terminal,
+            stdin: self.config.stdin,
+            ip_address: network.ipv4_addr().to_string(),
+            gateway: network.gateway().to_string(),
+            ipv6_address: network
+                .ipv6()
+                .map(|ipv6| format!("{}/{}", ipv6.addr, ipv6.prefix_len))
+                .unwrap_or_default(),
+            ipv6_gateway: network
+                .ipv6()
+                .map(|ipv6| ipv6.gateway.to_string())
+                .unwrap_or_default(),
+            tap_device: network.nic().name().to_string(),
+            private_networks: network
+                .private_networks()
+                .iter()
+                .map(|attachment| proto::node::PrivateNetworkInterface {
+                    network: attachment.network().to_string(),
+                    ip_address: attachment.ipv4_addr().to_string(),
+                    gateway: attachment.gateway().to_string(),
+                    tap_device: attachment.nic().name().to_string(),
+                })
+                .collect(),
+            published_ports: network
+                .published_ports()
+                .iter()
+                .map(|port| port.as_proto())
+                .collect(),
+            firewall_rules: match network.rules() {
+                Ok(rules) => rules.iter().map(|rule| rule.as_proto()).collect(),
+                Err(e) => {
+                    log::warn!(
+                        "Unable to list the firewall rules of {}: {:?}",
+                        self.uuid,
+                        e
+                    );
+                    Vec::new()
+                }
+            },
+            state: state.map(|(state, _)| state.as_str().to_string()),
+            created_at_ms: self.created_at_ms as i64,
+            uptime_ms: get_timestamp_ms().saturating_sub(self.booted_at_ms) as i64,
+            last_exit_code: exit.and_then(|exit| exit.exit_code()),
+            labels: self.config.labels.clone().into_iter().collect(),
+            restart_count: self.restart_count,
+            volumes: self
+                .config
+                .volumes
+                .iter()
+                .map(|volume| proto::node::VolumeMount {
+                    name: volume.name.clone(),
+                    mount_path: volume.mount_path.clone(),
+                    read_only: volume.read_only,
+                })
+                .collect(),
+            scratch_disk_gb: self.config.scratch_disk_gb,
+            io_limits: Some(self.config.io_limits.as_proto()),
+        }
+    }
+
+    /// The latest resource usage reported by the guest, `None` until the first sample arrived.
+    /// Includes the metrics of Firecracker, which are flushed first.
+    pub async fn stats(&self) -> Option<proto::node::InstanceStats> {
+        let (usage, previous) = self.comm.as_ref()?.0.lock().await.resource_usage()?;
+        // 100 for every fully used vCPU, like top
+        let cpu_percent = previous
+            .filter(|previous| usage.timestamp_ms > previous.timestamp_ms)
+            .map(|previous| {
+                let cpu_usec = usage.cpu_usage_usec.saturating_sub(previous.cpu_usage_usec);
+                let elapsed_usec = (usage.timestamp_ms - previous.timestamp_ms) * 1000;
+                cpu_usec as f64 / elapsed_usec as f64 * 100.0
+            })
+            .unwrap_or_default();
+        let balloon = match self.balloon_stats().await {
+            Ok(stats) => stats.map(balloon_message),
+            Err(e) => {
+                debug!("Unable to get balloon statistics of {}: {:?}", self.uuid, e);
+                None
+            }
+        };
+        let vmm = match self.vmm_output.as_ref() {
+            Some(vmm_output) => {
+                let flushed = vmm_output.flushed();
+                match self.vm.lock().await.flush_metrics().await {
+                    Ok(()) => flushed.await,
+                    Err(e) => debug!("Unable to flush metrics of {}: {:?}", self.uuid, e),
+                }
+                let metrics = vmm_output.metrics();
+                Some(proto::node::VmmStats {
+                    vcpu_exits_io: metrics.vcpu_exits_io,
+                    vcpu_exits_mmio: metrics.vcpu_exits_mmio,
+                    vcpu_failures: metrics.vcpu_failures,
+                    block_read_bytes: metrics.block_read_bytes,
+                    block_write_bytes: metrics.block_write_bytes,
+                    block_read_count: metrics.block_read_count,
+                    block_write_count: metrics.block_write_count,
+                    block_failures: metrics.block_failures,
+                    block_throttled: metrics.block_throttled,
+                    net_rx_bytes: metrics.net_rx_bytes,
+                    net_tx_bytes: metrics.net_tx_bytes,
+                    net_rx_packets: metrics.net_rx_packets,
+                    net_tx_packets: metrics.net_tx_packets,
+                    net_failures: metrics.net_failures,
+                    net_throttled: metrics.net_throttled,
+                    vsock_failures: metrics.vsock_failures,
+                })
+            }
+            None => None,
+        };
+        Some(proto::node::InstanceStats {
+            id: self.uuid.clone(),
+            timestamp_ms: usage.timestamp_ms as i64,
+            cpu_usage_usec: usage.cpu_usage_usec,
+            cpu_user_usec: usage.cpu_user_usec,
+            cpu_system_usec: usage.cpu_system_usec,
+            cpu_percent,
+            memory_bytes: usage.memory_bytes,
+            memory_limit_bytes: usage.memory_limit_bytes,
+            disk_read_bytes: usage.disk_read_bytes,
+            disk_write_bytes: usage.disk_write_bytes,
+            disk_used_bytes: usage.disk_used_bytes,
+            disk_total_bytes: usage.disk_total_bytes,
+            net_rx_bytes: usage.net_rx_bytes,
+            net_rx_packets: usage.net_rx_packets,
+            net_tx_bytes: usage.net_tx_bytes,
+            net_tx_packets: usage.net_tx_packets,
+            vmm,
+            balloon,
+        })
+    }
+
+    pub fn io_limits(&self) -> IoLimits {
+        self.config.io_limits
+    }
+
+    /// Applies new rate limits to the devices of the running VM, only those which changed are updated.
+    /// If a device can't be updated, the devices updated before it get the previous limits back.
+    pub async fn set_io_limits(&mut self, limits: IoLimits) -> Result<()> {
+        let current = self.config.io_limits;
+        let mut devices = Vec::new();
+        if limits.drive != current.drive {
+            devices.push(LimitedDevice::Drive(SCRATCH_DRIVE_ID.to_string()));
+            devices.extend(
+                (0..self.config.volumes.len())
+                    .map(|i| LimitedDevice::Drive(format!("{}{}", VOLUME_DRIVE_PREFIX, i))),
+            );
+        }
+        if (limits.network_rx, limits.network_tx) != (current.network_rx, current.network_tx) {
+            devices.push(LimitedDevice::NetworkInterface("eth0".to_string()));
+            devices.extend(
+                (0..self.config.private_networks.len())
+                    .map(|i| LimitedDevice::NetworkInterface(private_network_interface(i))),
+            );
+        }
+
+        let vm = self.vm.get_mut();
+        for (i, device) in devices.iter().enumerate() {
+            if let Err(e) = device.update(vm, &limits).await {
+                for device in &devices[..i] {
+                    if let Err(e) = device.update(vm, &current).await {
+                        log::error!("Unable to restore the rate limits of {:?}: {:?}", device, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        self.config.io_limits = limits;
+        Ok(())
+    }
+
+    pub fn container_reference(&self) -> &str {
+        &self.config.container_reference
+    }
+
+    pub fn labels(&self) -> &Labels {
+        &self.config.labels
+    }
+
+    pub fn volumes(&self) -> &[VolumeMount] {
+        &self.config.volumes
+    }
+
+    /// Bytes the scratch drive can still grow by on the host, as it starts out as a sparse file.
+    pub async fn unallocated_scratch_bytes(&self) -> Result<u64> {
+        let (size, allocated) = self.vm.lock().await.drive_usage(SCRATCH_DRIVE_ID)?;
+        Ok(size.saturating_sub(allocated))
+    }
+
+    /// The size of the filesystem holding the scratch drives on the host and the bytes available on it.
+    pub fn scratch_space(fc_config: &FirecrackerConfig) -> Result<(u64, u64)> {
+        JailedCracker::jails_filesystem_bytes(&fc_config.firecracker_binary)
+    }
+
+    /// The resources held by the machine, without the memory its balloon took back from the guest.
+    pub fn resources(&self) -> Resources {
+        let mut resources = self.config.resources();
+        resources.memory_mb = resources
+            .memory_mb
+            .saturating_sub(self.reclaimed_memory_mb());
+        resources
+    }
+
+    pub fn reclaimed_memory_mb(&self) -> u64 {
+        self.reclaimed_mb.load(Ordering::Relaxed)
+    }
+
+    /// The statistics of the balloon, `None` for machines without one.
+    pub async fn balloon_stats(&self) -> Result<Option<BalloonStats>> {
+        if !self.config.balloon {
+            return Ok(None);
+        }
+        let stats = self.vm.lock().await.balloon_stats().await?;
+        self.reclaimed_mb
+            .store(stats.actual_mib as u64, Ordering::Relaxed);
+        Ok(Some(stats))
+    }
+
+    /// Updates the memory taken back by the balloon, unless the VM is busy, e.g. being snapshotted.
+    pub async fn refresh_reclaimed_memory(&self) {
+        if !self.config.balloon {
+            return;
+        }
+        let Ok(mut vm) = self.vm.try_lock() else {
+            return;
+        };
+        match vm.balloon_stats().await {
+            Ok(stats) => self
+                .reclaimed_mb
+                .store(stats.actual_mib as u64, Ordering::Relaxed),
+            Err(e) => debug!("Unable to get balloon statistics of {}: {:?}", self.uuid, e),
+        }
+    }
+
+    /// Inflates or deflates the balloon to take `target_mb` of memory from the guest.
+    pub async fn set_balloon(&self, target_mb: u32) -> Result<BalloonStats> {
+        if !self.config.balloon {
+            return Err(anyhow::anyhow!("The machine has no balloon device"));
+        }
+        let mut vm = self.vm.lock().await;
+        vm.update_balloon(target_mb).await?;
+        let stats = vm.balloon_stats().await?;
+        self.reclaimed_mb
+            .store(stats.actual_mib as u64, Ordering::Relaxed);
+        Ok(stats)
+    }
+
+    pub fn memory_mb(&self) -> u32 {
+        self.config.mem_size_mb
+    }
+
+    pub fn restart_policy(&self) -> RestartPolicy {
+        self.config.restart_policy
+    }
+
+    pub fn boot_timings(&self) -> BootTimings {
+        self.boot_timings
+    }
+
+    pub async fn log_subscriber_count(&self) -> usize {
+        match self.comm.as_ref() {
+            Some((comm, _)) => comm.lock().await.log_subscriber_count(),
+            None => 0,
+        }
+    }
+
+    pub fn restart_count(&self) -> u32 {
+        self.restart_count
+    }
+
+    /// The restarts in a row leading up to the current run, none once it ran for `stable_after`.
+    pub fn restart_streak(&self, stable_after: Duration) -> u32 {
+        let uptime = Duration::from_millis(get_timestamp_ms().saturating_sub(self.booted_at_ms));
+        continued_restart_streak(self.restart_streak, uptime, stable_after)
     }
 
     pub fn network(&self) -> &Mutex<NetworkStack> {
         &self.network
     }
 
+    /// Whether the container runs with a terminal, meaning its output is raw terminal output rather than logs.
+    pub fn terminal(&self) -> bool {
+        self.config.terminal
+    }
+
+    pub fn communicator(&self) -> Result<Arc<Mutex<MachineCommunicator>>> {
+        let comm = self
+            .comm
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Communication never initialized"))?;
+        Ok(comm.0.clone())
+    }
+
     async fn _shutdown_gracefully(&mut self, timeout: Duration) -> Result<(), anyhow::Error> {
         let (comm, jh) = self
             .comm
@@ -182,7 +1095,7 @@ impl Machine {
                 );
             }
         }
-        let _ = self.vm.cleanup();
+        let _ = self.vm.into_inner().cleanup();
         self.network.into_inner()
     }
 
@@ -200,6 +1113,19 @@ impl Machine {
         Ok((handler.clone_buffer_with_state(), handler.subscribe_log()))
     }
 
+    pub async fn take_state_changes(
+        &self,
+    ) -> Result<tokio::sync::mpsc::UnboundedReceiver<InitVmState>> {
+        let comm = self
+            .comm
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Communication never initialized"))?;
+        let mut handler = comm.0.lock().await;
+        handler
+            .take_state_changes()
+            .ok_or(anyhow::anyhow!("State changes already taken"))
+    }
+
     pub async fn get_logs(&self) -> Result<Vec<Arc<MachineLog>>> {
         let comm = self
             .comm
@@ -208,4 +1134,156 @@ impl Machine {
         let handler = comm.0.lock().await;
         Ok(handler.clone_buffer_with_state())
     }
+
+    pub async fn exec(&self, args: Vec<String>) -> Result<tokio::sync::mpsc::Receiver<ExecEvent>> {
+        let comm = self
+            .comm
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Communication never initialized"))?;
+        let mut handler = comm.0.lock().await;
+        Ok(handler.exec(args).await?)
+    }
+}
+
+/// The block device of a volume in the guest, after the rootfs, the scratch drive and the cached image.
+fn volume_guest_device(config: &MachineConfig, index: usize) -> String {
+    let first = if config.cached_image.is_some() { 3 } else { 2 };
+    format!("/dev/vd{}", (b'a' + (first + index) as u8) as char)
+}
+
+/// A device of a running VM with rate limits.
+#[derive(Debug)]
+enum LimitedDevice {
+    Drive(String),
+    NetworkInterface(String), // The guest name
+}
+
+impl LimitedDevice {
+    async fn update(&self, vm: &mut JailedCracker, limits: &IoLimits) -> Result<()> {
+        match self {
+            LimitedDevice::Drive(id) => vm.update_drive_rate_limiter(id, limits.drive).await,
+            LimitedDevice::NetworkInterface(name) => {
+                vm.update_network_rate_limiters(name, limits.network_rx, limits.network_tx)
+                    .await
+            }
+        }
+    }
+}
+
+/// The guest interface of a private network, after eth0 of the network stack.
+fn private_network_interface(index: usize) -> String {
+    format!("eth{}", index + 1)
+}
+
+fn debug_machine_out() -> bool {
+    // DEBUG_MACHINE_OUT flag is set
+    let debug_machine_out = std::env::var("DEBUG_MACHINE_OUT").is_ok();
+
+    if debug_machine_out {
+        log::warn!("Debugging machine output enabled, this should NEVER be used in production!");
+    } else {
+        log::debug!("Debugging machine output disabled");
+    }
+    debug_machine_out
+}
+
+async fn accept_guest(listener: &UnixListener, timeout: Duration) -> Result<UnixStream> {
+    let connection = tokio::time::timeout(timeout, listener.accept()).await;
+
+    match connection {
+        Ok(Ok((stream, _))) => Ok(stream),
+        Ok(Err(e)) => {
+            log::error!("Failed to accept vsock connection: {}", e);
+            Err(e.into())
+        }
+        Err(_) => {
+            log::error!("Timeout accepting vsock connection");
+            Err(anyhow::anyhow!("Timeout accepting vsock connection"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_scratch_filesystems() {
+        ScratchFilesystem::default().validate().unwrap();
+        let fs = |fs_type: &str, features: &[&str], mount_options: &[&str]| ScratchFilesystem {
+            fs_type: fs_type.to_string(),
+            features: features.iter().map(|s| s.to_string()).collect(),
+            mount_options: mount_options.iter().map(|s| s.to_string()).collect(),
+        };
+        fs("ext2", &[], &[]).validate().unwrap();
+        fs(
+            "ext3",
+            &["^has_journal", "metadata_csum"],
+            &["noatime", "commit=60"],
+        )
+        .validate()
+        .unwrap();
+
+        assert!(fs("xfs", &[], &[]).validate().is_err());
+        assert!(fs("", &[], &[]).validate().is_err());
+        for option in ["", "noatime,dev", "-F", "no atime", "noatime\n"] {
+            assert!(
+                fs("ext4", &[option], &[]).validate().is_err(),
+                "{:?}",
+                option
+            );
+            assert!(
+                fs("ext4", &[], &[option]).validate().is_err(),
+                "{:?}",
+                option
+            );
+        }
+    }
+
+    #[test]
+    fn restart_policies() {
+        let failed = MachineExit::ContainerExited(1);
+        let succeeded = MachineExit::ContainerExited(0);
+
+        assert!(!RestartPolicy::Never.should_restart(failed, 0));
+        assert!(!RestartPolicy::Never.should_restart(MachineExit::Unknown, 0));
+
+        let on_failure = RestartPolicy::OnFailure {
+            max_retries: Some(2),
+        };
+        assert!(on_failure.should_restart(failed, 0));
+        assert!(on_failure.should_restart(MachineExit::FailedToPullContainerImage, 1));
+        assert!(on_failure.should_restart(MachineExit::FailedToMountVolume, 1));
+        assert!(!on_failure.should_restart(failed, 2));
+        assert!(!on_failure.should_restart(succeeded, 0));
+        let unlimited = RestartPolicy::OnFailure { max_retries: None };
+        assert!(unlimited.should_restart(MachineExit::Unknown, 1000));
+        assert!(!unlimited.should_restart(succeeded, 0));
+
+        assert!(RestartPolicy::Always.should_restart(succeeded, 1000));
+        assert!(RestartPolicy::Always.should_restart(failed, 0));
+
+        // Shutdowns requested by the host are never restarted
+        for policy in [on_failure, unlimited, RestartPolicy::Always] {
+            assert!(!policy.should_restart(MachineExit::GracefulShutdown, 0));
+        }
+    }
+
+    #[test]
+    fn restart_streak_starts_over_after_stable_runs() {
+        let stable_after = Duration::from_secs(600);
+        assert_eq!(
+            continued_restart_streak(3, Duration::from_secs(10), stable_after),
+            3
+        );
+        assert_eq!(
+            continued_restart_streak(3, Duration::from_millis(599_999), stable_after),
+            3
+        );
+        assert_eq!(continued_restart_streak(3, stable_after, stable_after), 0);
+        assert_eq!(
+            continued_restart_streak(3, Duration::from_secs(3600), stable_after),
+            0
+        );
+    }
 }
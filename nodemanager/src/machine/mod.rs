@@ -1,6 +1,11 @@
 mod firecracker;
 mod machine;
+mod vmm;
 mod vsock;
-pub use machine::Machine;
-pub use machine::{ContainerOverrides, FirecrackerConfig, MachineConfig};
-pub use vsock::{MachineExit, MachineLog};
+pub use firecracker::{BalloonStats, RateLimiter, TokenBucket};
+pub use machine::{balloon_message, Machine};
+pub use machine::{
+    BootTimings, ContainerOverrides, FirecrackerConfig, IoLimits, MachineConfig, MachineState,
+    RestartPolicy, ScratchFilesystem, SnapshotMetadata,
+};
+pub use vsock::{ExecEvent, MachineCommunicator, MachineExit, MachineLog};
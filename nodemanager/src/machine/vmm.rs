@@ -0,0 +1,210 @@
+//! Log and metrics output of Firecracker, read from FIFOs in the jail.
+
+use std::time::Duration;
+
+use log::{trace, warn};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::unix::pipe,
+    sync::watch,
+    task::JoinHandle,
+};
+
+// Firecracker writes the metrics every 60 seconds on its own, a flush is requested to get them sooner
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Counters of the VMM since the output was first read, summed up from the deltas of every flush.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VmmMetrics {
+    pub vcpu_exits_io: u64,
+    pub vcpu_exits_mmio: u64,
+    pub vcpu_failures: u64,
+    pub block_read_bytes: u64, // Over all drives
+    pub block_write_bytes: u64,
+    pub block_read_count: u64,
+    pub block_write_count: u64,
+    pub block_failures: u64,
+    pub block_throttled: u64, // Requests delayed by a rate limiter
+    pub net_rx_bytes: u64,
+    pub net_tx_bytes: u64,
+    pub net_rx_packets: u64,
+    pub net_tx_packets: u64,
+    pub net_failures: u64,
+    pub net_throttled: u64,
+    pub vsock_failures: u64,
+}
+
+// The parts of a metrics line we care about, every field is the change since the previous flush
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Flush {
+    vcpu: VcpuFlush,
+    block: BlockFlush, // Aggregated over all drives, `block_<drive_id>` holds the single ones
+    net: NetFlush,
+    vsock: VsockFlush,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct VcpuFlush {
+    exit_io_in: u64,
+    exit_io_out: u64,
+    exit_mmio_read: u64,
+    exit_mmio_write: u64,
+    failures: u64,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct BlockFlush {
+    read_bytes: u64,
+    write_bytes: u64,
+    read_count: u64,
+    write_count: u64,
+    execute_fails: u64,
+    invalid_reqs_count: u64,
+    rate_limiter_throttled_events: u64,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct NetFlush {
+    rx_bytes_count: u64,
+    tx_bytes_count: u64,
+    rx_packets_count: u64,
+    tx_packets_count: u64,
+    rx_fails: u64,
+    tx_fails: u64,
+    rx_rate_limiter_throttled: u64,
+    tx_rate_limiter_throttled: u64,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct VsockFlush {
+    activate_fails: u64,
+    cfg_fails: u64,
+    rx_queue_event_fails: u64,
+    tx_queue_event_fails: u64,
+    ev_queue_event_fails: u64,
+    muxer_event_fails: u64,
+    conn_event_fails: u64,
+    rx_read_fails: u64,
+    tx_write_fails: u64,
+    tx_flush_fails: u64,
+}
+
+impl VmmMetrics {
+    fn add(&mut self, flush: &Flush) {
+        let (vcpu, block, net, vsock) = (&flush.vcpu, &flush.block, &flush.net, &flush.vsock);
+        self.vcpu_exits_io += vcpu.exit_io_in + vcpu.exit_io_out;
+        self.vcpu_exits_mmio += vcpu.exit_mmio_read + vcpu.exit_mmio_write;
+        self.vcpu_failures += vcpu.failures;
+        self.block_read_bytes += block.read_bytes;
+        self.block_write_bytes += block.write_bytes;
+        self.block_read_count += block.read_count;
+        self.block_write_count += block.write_count;
+        self.block_failures += block.execute_fails + block.invalid_reqs_count;
+        self.block_throttled += block.rate_limiter_throttled_events;
+        self.net_rx_bytes += net.rx_bytes_count;
+        self.net_tx_bytes += net.tx_bytes_count;
+        self.net_rx_packets += net.rx_packets_count;
+        self.net_tx_packets += net.tx_packets_count;
+        self.net_failures += net.rx_fails + net.tx_fails;
+        self.net_throttled += net.rx_rate_limiter_throttled + net.tx_rate_limiter_throttled;
+        self.vsock_failures += vsock.activate_fails
+            + vsock.cfg_fails
+            + vsock.rx_queue_event_fails
+            + vsock.tx_queue_event_fails
+            + vsock.ev_queue_event_fails
+            + vsock.muxer_event_fails
+            + vsock.conn_event_fails
+            + vsock.rx_read_fails
+            + vsock.tx_write_fails
+            + vsock.tx_flush_fails;
+    }
+}
+
+/// Forwards the Firecracker log to ours and keeps the metrics, until dropped.
+pub struct VmmOutput {
+    metrics: watch::Receiver<VmmMetrics>,
+    readers: [JoinHandle<()>; 2],
+}
+
+impl VmmOutput {
+    pub fn spawn(uuid: &str, logger: pipe::Receiver, metrics: pipe::Receiver) -> Self {
+        let (metrics_tx, metrics_rx) = watch::channel(VmmMetrics::default());
+
+        let log_uuid = uuid.to_string();
+        let log_reader = tokio::spawn(async move {
+            let mut lines = BufReader::new(logger).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                warn!("Firecracker {}: {}", log_uuid, line);
+            }
+        });
+
+        let metrics_uuid = uuid.to_string();
+        let metrics_reader = tokio::spawn(async move {
+            let mut lines = BufReader::new(metrics).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                match serde_json::from_str::<Flush>(&line) {
+                    Ok(flush) => metrics_tx.send_modify(|metrics| metrics.add(&flush)),
+                    Err(e) => trace!("Invalid metrics of firecracker {}: {}", metrics_uuid, e),
+                }
+            }
+        });
+
+        Self {
+            metrics: metrics_rx,
+            readers: [log_reader, metrics_reader],
+        }
+    }
+
+    /// Resolves once the metrics of a flush requested after this call were read, or after a short timeout.
+    pub fn flushed(&self) -> impl std::future::Future<Output = ()> + 'static {
+        let mut updated = self.metrics.clone();
+        updated.mark_unchanged();
+        async move {
+            let _ = tokio::time::timeout(FLUSH_TIMEOUT, updated.changed()).await;
+        }
+    }
+
+    pub fn metrics(&self) -> VmmMetrics {
+        *self.metrics.borrow()
+    }
+}
+
+impl Drop for VmmOutput {
+    fn drop(&mut self) {
+        for reader in &self.readers {
+            reader.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_up_flushes() {
+        let line = r#"{"utc_timestamp_ms":1,"vcpu":{"exit_io_in":2,"exit_io_out":3,"exit_mmio_read":4,"exit_mmio_write":5,"failures":0},
+            "block":{"read_bytes":4096,"write_bytes":512,"read_count":1,"write_count":1,"execute_fails":1,"rate_limiter_throttled_events":2},
+            "block_rootfs":{"read_bytes":4096},"net":{"rx_bytes_count":100,"tx_bytes_count":50,"rx_packets_count":2,"tx_packets_count":1,"tx_fails":1},
+            "vsock":{"rx_read_fails":1,"conn_event_fails":2},"api_server":{"process_startup_time_us":10}}"#;
+        let flush = serde_json::from_str::<Flush>(line).unwrap();
+
+        let mut metrics = VmmMetrics::default();
+        metrics.add(&flush);
+        metrics.add(&flush);
+        assert_eq!(metrics.vcpu_exits_io, 10);
+        assert_eq!(metrics.vcpu_exits_mmio, 18);
+        assert_eq!(metrics.block_read_bytes, 8192);
+        assert_eq!(metrics.block_failures, 2);
+        assert_eq!(metrics.block_throttled, 4);
+        assert_eq!(metrics.net_rx_bytes, 200);
+        assert_eq!(metrics.net_failures, 2);
+        assert_eq!(metrics.vsock_failures, 6);
+    }
+}
@@ -1,20 +1,28 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use circular_buffer::CircularBuffer;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{
         unix::{OwnedReadHalf, OwnedWriteHalf},
-        UnixStream,
+        UnixListener, UnixStream,
     },
     sync::{
-        mpsc::{Receiver, Sender},
+        mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender},
         Mutex,
     },
 };
-use vmproto::guest::{GuestExitCode, InitVmState, LogMessage};
+use vmproto::{
+    guest::{ExecOutputType, GuestExitCode, InitVmState, LogMessage, ResourceUsage},
+    host::{ExecRequest, MAX_HOST_PACKET_SIZE, MAX_STDIN_CHUNK_SIZE},
+};
 
 const MAX_LINES_IN_BUFFER: usize = 256;
+const MAX_TERMINAL_CHUNKS_IN_BUFFER: usize = 64;
+// How long the guest gets to reconnect after losing the connection, e.g. from a vsock reset by a snapshot
+const GUEST_RECONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+pub type TerminalOutput = Arc<Vec<u8>>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MachineExit {
@@ -22,6 +30,7 @@ pub enum MachineExit {
     ContainerExited(i32),
     GracefulShutdown,
     FailedToPullContainerImage,
+    FailedToMountVolume,
 }
 
 pub enum MachineLog {
@@ -48,12 +57,55 @@ impl MachineLog {
     }
 }
 
+pub enum ExecEvent {
+    Output(ExecOutputType, Vec<u8>),
+    Exited(i32),
+}
+
+impl ExecEvent {
+    pub fn as_proto_exec_output(&self) -> proto::node::ExecOutput {
+        match self {
+            ExecEvent::Output(output_type, data) => proto::node::ExecOutput {
+                output_type: output_type.as_str().to_string(),
+                data: Some(data.clone()),
+                exit_code: None,
+            },
+            ExecEvent::Exited(exit_code) => proto::node::ExecOutput {
+                output_type: "exit".to_string(),
+                data: None,
+                exit_code: Some(*exit_code),
+            },
+        }
+    }
+}
+
+impl MachineExit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineExit::Unknown => "unknown",
+            MachineExit::ContainerExited(_) => "container_exited",
+            MachineExit::GracefulShutdown => "graceful_shutdown",
+            MachineExit::FailedToPullContainerImage => "failed_to_pull_container_image",
+            MachineExit::FailedToMountVolume => "failed_to_mount_volume",
+        }
+    }
+
+    /// The exit code of the container, if it exited on its own.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            MachineExit::ContainerExited(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
 impl From<GuestExitCode> for MachineExit {
     fn from(code: GuestExitCode) -> Self {
         match code {
             GuestExitCode::GracefulShutdown => MachineExit::GracefulShutdown,
             GuestExitCode::FailedToPullContainerImage => MachineExit::FailedToPullContainerImage,
             GuestExitCode::ContainerExited(code) => MachineExit::ContainerExited(code),
+            GuestExitCode::FailedToMountVolume => MachineExit::FailedToMountVolume,
         }
     }
 }
@@ -63,23 +115,48 @@ pub struct MachineCommunicator {
     log_subscribers: Vec<Sender<Arc<MachineLog>>>,
     log_buffer: CircularBuffer<MAX_LINES_IN_BUFFER, Arc<MachineLog>>,
     state: Option<(InitVmState, u64)>,
+    state_tx: UnboundedSender<InitVmState>,
+    state_rx: Option<UnboundedReceiver<InitVmState>>, // Until taken by `take_state_changes`
+    exit: Option<MachineExit>,
+    exec_subscribers: HashMap<u32, Sender<ExecEvent>>,
+    next_exec_id: u32,
+    terminal_subscribers: Vec<Sender<TerminalOutput>>,
+    terminal_buffer: CircularBuffer<MAX_TERMINAL_CHUNKS_IN_BUFFER, TerminalOutput>,
+    usage: Option<ResourceUsage>,
+    previous_usage: Option<ResourceUsage>, // To tell the CPU usage between the latest two samples
 }
 
 impl MachineCommunicator {
     pub async fn spawn(
+        listener: UnixListener,
         stream: UnixStream,
         stop_handler: tokio::sync::oneshot::Sender<MachineExit>,
     ) -> (Arc<Mutex<MachineCommunicator>>, tokio::task::JoinHandle<()>) {
         let (read, write) = stream.into_split();
+        let (state_tx, state_rx) = tokio::sync::mpsc::unbounded_channel();
 
         let handler = Arc::new(Mutex::new(MachineCommunicator {
             log_subscribers: Vec::new(),
             log_buffer: CircularBuffer::new(),
             stream: write,
             state: None,
+            state_tx,
+            state_rx: Some(state_rx),
+            exit: None,
+            exec_subscribers: HashMap::new(),
+            next_exec_id: 0,
+            terminal_subscribers: Vec::new(),
+            terminal_buffer: CircularBuffer::new(),
+            usage: None,
+            previous_usage: None,
         }));
 
-        let jh = tokio::spawn(packet_handler(read, handler.clone(), stop_handler));
+        let jh = tokio::spawn(packet_handler(
+            listener,
+            read,
+            handler.clone(),
+            stop_handler,
+        ));
 
         (handler, jh)
     }
@@ -109,6 +186,19 @@ impl MachineCommunicator {
         }
     }
 
+    /// The latest resource usage reported by the guest and the sample before it.
+    pub fn resource_usage(&self) -> Option<(ResourceUsage, Option<ResourceUsage>)> {
+        self.usage.map(|usage| (usage, self.previous_usage))
+    }
+
+    /// Subscribers which are still listening, closed ones are only removed on the next log.
+    pub fn log_subscriber_count(&self) -> usize {
+        self.log_subscribers
+            .iter()
+            .filter(|tx| !tx.is_closed())
+            .count()
+    }
+
     pub fn subscribe_log(&mut self) -> Receiver<Arc<MachineLog>> {
         let (tx, rx) = tokio::sync::mpsc::channel(128);
         self.log_subscribers.push(tx);
@@ -148,12 +238,49 @@ impl MachineCommunicator {
             .collect()
     }
 
+    /// The latest state reported by the guest and when, `None` once it exited.
+    pub fn state(&self) -> Option<(InitVmState, u64)> {
+        self.state
+    }
+
+    pub fn exit(&self) -> Option<MachineExit> {
+        self.exit
+    }
+
+    /// Every state reported by the guest from the start, can only be taken once.
+    pub fn take_state_changes(&mut self) -> Option<UnboundedReceiver<InitVmState>> {
+        self.state_rx.take()
+    }
+
+    fn push_terminal_output(&mut self, data: Vec<u8>) {
+        let data = Arc::new(data);
+        self.terminal_buffer.push_back(data.clone());
+        self.terminal_subscribers
+            .retain(|tx| tx.try_send(data.clone()).is_ok());
+    }
+
+    /// Returns the recently buffered terminal output together with a subscription for new output.
+    pub fn subscribe_terminal(&mut self) -> (Vec<TerminalOutput>, Receiver<TerminalOutput>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        self.terminal_subscribers.push(tx);
+        (self.terminal_buffer.iter().cloned().collect(), rx)
+    }
+
     fn drop_subscribers(&mut self) {
         self.log_subscribers.clear();
+        self.exec_subscribers.clear();
+        self.terminal_subscribers.clear();
     }
 
     pub async fn write(&mut self, packet: vmproto::host::HostPacket) -> Result<(), std::io::Error> {
         let data = vmproto::host::serialize_host_packet(&packet);
+        // The guest would drop the connection over it
+        if data.len() > MAX_HOST_PACKET_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Host packet of {} bytes is too large", data.len()),
+            ));
+        }
         let len = (data.len() as u32).to_be_bytes();
         self.stream.write_all(&len).await?;
         self.stream.write_all(&data).await?;
@@ -164,9 +291,46 @@ impl MachineCommunicator {
     pub async fn send_shutdown(&mut self) -> Result<(), std::io::Error> {
         self.write(vmproto::host::HostPacket::Shutdown).await
     }
+
+    pub async fn send_stdin(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        for chunk in data.chunks(MAX_STDIN_CHUNK_SIZE) {
+            self.write(vmproto::host::HostPacket::Stdin(chunk.to_vec()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn close_stdin(&mut self) -> Result<(), std::io::Error> {
+        self.write(vmproto::host::HostPacket::CloseStdin).await
+    }
+
+    pub async fn resize_terminal(&mut self, rows: u16, cols: u16) -> Result<(), std::io::Error> {
+        self.write(vmproto::host::HostPacket::ResizeTerminal((rows, cols)))
+            .await
+    }
+
+    pub async fn exec(&mut self, args: Vec<String>) -> Result<Receiver<ExecEvent>, std::io::Error> {
+        let exec_id = self.next_exec_id;
+        self.next_exec_id = self.next_exec_id.wrapping_add(1);
+
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        self.exec_subscribers.insert(exec_id, tx);
+        if let Err(e) = self
+            .write(vmproto::host::HostPacket::Exec(ExecRequest {
+                exec_id,
+                args,
+            }))
+            .await
+        {
+            self.exec_subscribers.remove(&exec_id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
 }
 
 async fn packet_handler(
+    listener: UnixListener,
     mut stream: OwnedReadHalf,
     handler: Arc<Mutex<MachineCommunicator>>,
     stop_handler: tokio::sync::oneshot::Sender<MachineExit>,
@@ -191,20 +355,58 @@ async fn packet_handler(
                     vmproto::guest::GuestPacket::VmState((state, timestamp_ms)) => {
                         log::trace!("Received VM state packet: {:?}", state);
                         handler.state = Some((state, timestamp_ms));
+                        let _ = handler.state_tx.send(state);
                         handler
                             .push_log(MachineLog::State(state, timestamp_ms))
                             .await;
                     }
+                    vmproto::guest::GuestPacket::TerminalOutput(data) => {
+                        handler.push_terminal_output(data);
+                    }
+                    vmproto::guest::GuestPacket::Metrics(usage) => {
+                        handler.previous_usage = handler.usage.replace(usage);
+                    }
+                    vmproto::guest::GuestPacket::ExecOutput(output) => {
+                        // A client which doesn't keep up loses its exec instead of stalling the machine
+                        let exec_id = output.exec_id;
+                        let event = ExecEvent::Output(output.output_type, output.data);
+                        if let Some(tx) = handler.exec_subscribers.get(&exec_id) {
+                            if tx.try_send(event).is_err() {
+                                log::debug!(
+                                    "Dropping output of exec {}, its buffer is full",
+                                    exec_id
+                                );
+                                handler.exec_subscribers.remove(&exec_id);
+                            }
+                        }
+                    }
+                    vmproto::guest::GuestPacket::ExecExited((exec_id, exit_code)) => {
+                        log::debug!("Exec {} exited with code: {}", exec_id, exit_code);
+                        if let Some(tx) = handler.exec_subscribers.remove(&exec_id) {
+                            let _ = tx.try_send(ExecEvent::Exited(exit_code));
+                        }
+                    }
                 }
                 log::trace!("Packet handled");
             }
             Err(_) => {
-                break;
+                match tokio::time::timeout(GUEST_RECONNECT_TIMEOUT, listener.accept()).await {
+                    Ok(Ok((new_stream, _))) => {
+                        log::debug!("Guest reconnected to vsock");
+                        let (read, write) = new_stream.into_split();
+                        stream = read;
+                        handler.lock().await.stream = write;
+                    }
+                    _ => break,
+                }
             }
         }
     }
     log::trace!("Packet handler loop exited, shutting down");
-    let _ = handler.try_lock().map(|mut h| h.drop_subscribers());
+    let _ = handler.try_lock().map(|mut h| {
+        h.exit = Some(exit);
+        h.drop_subscribers()
+    });
     let _ = stop_handler.send(exit);
     log::trace!("Packet handler stopped");
 }
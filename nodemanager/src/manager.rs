@@ -1,8 +1,12 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Result;
 use hmac::Mac;
@@ -11,18 +15,53 @@ use log::error;
 use log::info;
 use log::warn;
 use proto::auth as proto_auth;
+use proto::node::instance_event::Event;
 use proto::node::node_manager_server::NodeManager as NodeManagerService;
 use proto::node::node_manager_server::NodeManagerServer as NodeManagerServiceServer;
+use proto::node::registry_credentials::Credentials;
+use proto::node::restart_policy::Mode as RestartMode;
 use proto::node::AllLogs;
+use proto::node::AttachInput;
+use proto::node::AttachOutput;
+use proto::node::CreateVolumeRequest;
 use proto::node::DeprovisionRequest;
+use proto::node::DrainRequest;
 use proto::node::Empty;
+use proto::node::ExecOutput;
+use proto::node::ExecRequest;
+use proto::node::InstanceDeprovisioned;
+use proto::node::InstanceEvent;
+use proto::node::InstanceExited;
 use proto::node::InstanceId;
+use proto::node::InstanceInfo;
 use proto::node::InstanceList;
+use proto::node::InstanceProvisioned;
+use proto::node::InstanceRestarted;
+use proto::node::InstanceStateChanged;
+use proto::node::InstanceStats;
+use proto::node::ListInstancesRequest;
 use proto::node::LogMessage;
+use proto::node::NodeCapacity;
+use proto::node::PatchInstanceLimitsRequest;
 use proto::node::ProvisionRequest;
 use proto::node::ProvisionResponse;
 use proto::node::PublishServicePortRequest;
+use proto::node::PublishedPort as PublishedPortMessage;
+use proto::node::PublishedPortList;
+use proto::node::RegistryCredentials;
+use proto::node::RestoreInstanceRequest;
+use proto::node::SetBalloonRequest;
+use proto::node::SnapshotId;
+use proto::node::SnapshotInfo;
+use proto::node::SnapshotInstanceRequest;
+use proto::node::SnapshotList;
+use proto::node::UnpublishServicePortRequest;
+use proto::node::Volume;
+use proto::node::VolumeList;
+use proto::node::VolumeName;
+use proto::node::WatchEventsRequest;
 use serde::Deserialize;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
@@ -32,27 +71,193 @@ use tonic::metadata::MetadataMap;
 use tonic::Request;
 use tonic::Response;
 use tonic::Status;
+use tonic::Streaming;
+use vmproto::guest::InitVmState;
+use vmproto::guest::LogMessageType;
+use vmproto::host::serialize_host_packet;
+use vmproto::host::HostPacket;
+use vmproto::host::MAX_HOST_PACKET_SIZE;
 
 use hmac::Hmac;
 use sha2::Sha256;
 use uuid::Uuid;
 
+use crate::capacity;
+use crate::capacity::InsufficientCapacity;
+use crate::capacity::Resources;
+use crate::events::EventLog;
+use crate::images::ImageCache;
+use crate::images::RegistryAuth;
+use crate::labels;
+use crate::labels::Selector;
 use crate::machine;
+use crate::machine::ExecEvent;
 use crate::machine::Machine;
+use crate::machine::MachineCommunicator;
 use crate::machine::MachineExit;
-use crate::networking::NetworkManager;
+use crate::machine::MachineLog;
+use crate::machine::RestartPolicy;
+use crate::machine::SnapshotMetadata;
+use crate::metrics;
+use crate::metrics::Metrics;
+use crate::networking::{
+    Ipv6Config, NetworkManager, PortConflict, PrivateNetworkConfig, Protocol, PublishedPort,
+};
+use crate::state::InstanceRecord;
+use crate::state::StateStore;
+use crate::volumes;
+use crate::volumes::VolumeMount;
+use crate::volumes::VolumeStore;
+
+// Firecracker supports up to 32 vCPUs
+const MAX_VCPUS: i32 = 32;
+const MIN_MEMORY_MB: i32 = 128;
+
+// Drives beyond the rootfs, scratch drive and cached image
+const MAX_VOLUMES_PER_INSTANCE: usize = 8;
+
+// Interfaces beyond eth0
+const MAX_PRIVATE_NETWORKS_PER_INSTANCE: usize = 4;
+
+// Doubled with every restart of an instance
+const RESTART_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(300);
+// Instances which ran this long start over at the initial backoff
+const RESTART_STABLE_AFTER: Duration = Duration::from_secs(600);
+
+const GB: u64 = 1024 * 1024 * 1024;
+
+/// How long to wait before restarting a machine which exited after `restart_streak` restarts in a row.
+fn restart_backoff(restart_streak: u32) -> Duration {
+    RESTART_BACKOFF_INITIAL
+        .saturating_mul(1 << restart_streak.min(16))
+        .min(RESTART_BACKOFF_MAX)
+}
+
+fn default_snapshot_directory() -> PathBuf {
+    PathBuf::from("/srv/snapshots")
+}
+
+fn default_image_cache_directory() -> PathBuf {
+    PathBuf::from("/srv/images")
+}
+
+fn default_state_directory() -> PathBuf {
+    PathBuf::from("/srv/state")
+}
+
+fn default_volume_directory() -> PathBuf {
+    PathBuf::from("/srv/volumes")
+}
+
+fn default_scratch_disk_gb() -> u64 {
+    8
+}
+
+fn default_max_scratch_disk_gb() -> u64 {
+    64
+}
+
+fn default_max_volume_size_mb() -> u64 {
+    64 * 1024
+}
+
+fn default_cpu_overcommit_ratio() -> f64 {
+    4.0
+}
+
+fn default_memory_overcommit_ratio() -> f64 {
+    1.0
+}
+
+fn default_disk_overcommit_ratio() -> f64 {
+    2.0
+}
+
+fn default_allocatable_host_ports() -> RangeInclusive<u16> {
+    30000..=32767
+}
 
 #[derive(Deserialize)]
 pub struct ManagerConfig {
     pub firecracker_config: machine::FirecrackerConfig,
     pub public_network_interface: String,
     pub service_network_interface: String,
+    #[serde(default = "default_snapshot_directory")]
+    pub snapshot_directory: PathBuf,
+    #[serde(default = "default_image_cache_directory")]
+    pub image_cache_directory: PathBuf,
+    #[serde(default = "default_state_directory")]
+    pub state_directory: PathBuf,
+    #[serde(default = "default_volume_directory")]
+    pub volume_directory: PathBuf, // Has to be on the same filesystem as the jails
+    #[serde(default = "default_scratch_disk_gb")]
+    pub default_scratch_disk_gb: u64,
+    #[serde(default = "default_max_scratch_disk_gb")]
+    pub max_scratch_disk_gb: u64,
+    #[serde(default = "default_max_volume_size_mb")]
+    pub max_volume_size_mb: u64,
+    #[serde(default)]
+    pub allocatable_vcpus: Option<u32>, // All CPUs of the host if unset
+    #[serde(default)]
+    pub allocatable_memory_mb: Option<u64>, // All memory of the host if unset
+    #[serde(default)]
+    pub allocatable_disk_gb: Option<u64>, // The size of the filesystem of the jails if unset
+    #[serde(default = "default_cpu_overcommit_ratio")]
+    pub cpu_overcommit_ratio: f64,
+    #[serde(default = "default_memory_overcommit_ratio")]
+    pub memory_overcommit_ratio: f64,
+    // Scratch disks and volumes are sparse, their unallocated space may also add up to this multiple of the free space on the host
+    #[serde(default = "default_disk_overcommit_ratio")]
+    pub disk_overcommit_ratio: f64,
+    #[serde(default)]
+    pub scratch_filesystem: machine::ScratchFilesystem,
+    #[serde(default)]
+    pub default_io_limits: machine::IoLimits, // For instances which don't request their own limits
+    #[serde(default)]
+    pub registry_credentials: HashMap<String, RegistryAuth>, // Usable by name in provision requests
+    #[serde(default)]
+    pub metrics_address: Option<SocketAddr>, // Prometheus metrics aren't served if unset
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Config>, // Guests only get IPv4 addresses if unset
+    // Host ports are picked from here for publications which don't ask for specific ones
+    #[serde(default = "default_allocatable_host_ports")]
+    pub allocatable_host_ports: RangeInclusive<u16>,
+    #[serde(default)]
+    pub private_networks: HashMap<String, PrivateNetworkConfig>, // Joinable by name in provision requests
+}
+
+impl ManagerConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.default_scratch_disk_gb > self.max_scratch_disk_gb {
+            return Err(anyhow::anyhow!(
+                "The default scratch disk size exceeds the maximum of {} GB",
+                self.max_scratch_disk_gb
+            ));
+        }
+        self.scratch_filesystem.validate()?;
+        for ratio in [
+            self.cpu_overcommit_ratio,
+            self.memory_overcommit_ratio,
+            self.disk_overcommit_ratio,
+        ] {
+            if ratio.is_nan() || ratio <= 0.0 {
+                return Err(anyhow::anyhow!("Invalid overcommit ratio {}", ratio));
+            }
+        }
+        Ok(())
+    }
 }
 
 struct InnerNodeManager {
     config: ManagerConfig,
     machines: RwLock<HashMap<String, Machine>>,
     network: Mutex<NetworkManager>,
+    images: ImageCache,
+    state: StateStore,
+    volumes: VolumeStore,
+    events: EventLog,
+    metrics: Metrics,
     machine_state_subscription: Option<mpsc::Sender<(Uuid, MachineExit)>>,
 }
 
@@ -60,25 +265,79 @@ impl InnerNodeManager {
     async fn _provision(
         &self,
         request: ProvisionRequest,
+        registry_auth: Option<RegistryAuth>,
         self_clone: Arc<InnerNodeManager>, // Self reference for cleanup
     ) -> anyhow::Result<String> {
+        // Pulled before locking the machines, this can take a while
+        let pull_started = Instant::now();
+        let cached_image = match self
+            .images
+            .prepare(&request.container_reference, registry_auth.as_ref())
+            .await
+        {
+            Ok(image) => Some(image),
+            Err(e) => {
+                warn!(
+                    "Unable to cache image {}, the instance pulls it instead: {:?}",
+                    &request.container_reference, e
+                );
+                None
+            }
+        };
+
+        self.metrics.record_image_pull(pull_started.elapsed());
+
         let mut machines = self.machines.write().await;
+        // Checked again now that no other machine can take the volumes or the resources
+        let volumes = self.resolve_volumes(&machines, &request.volumes)?;
 
         let machine_config = machine::MachineConfig {
             container_reference: request.container_reference,
             vcpu_count: request.vcpus as u8,
             mem_size_mb: request.memory_mb as u32,
+            terminal: request.terminal,
+            stdin: request.stdin,
+            image_digest: cached_image.as_ref().map(|image| image.digest.clone()),
+            labels: request.labels.into_iter().collect(),
+            restart_policy: restart_policy(request.restart_policy),
+            volumes,
+            scratch_disk_gb: request
+                .scratch_disk_gb
+                .unwrap_or(self.config.default_scratch_disk_gb),
+            scratch_filesystem: self.config.scratch_filesystem.clone(),
+            io_limits: patch_io_limits(
+                self.config.default_io_limits,
+                request.io_limits.unwrap_or_default(),
+            ),
+            balloon: true,
+            cached_image: cached_image.map(|image| image.path),
+            private_networks: request.private_networks,
         };
+        self.check_capacity(&machines, machine_config.resources())
+            .await?;
         let mut network_stack = self.network.lock().await.provision_stack()?;
         debug!(
             "Network stack provision with local ip: {}",
             network_stack.ipv4_addr()
         );
-        network_stack.setup_public_nat(&self.config.public_network_interface)?;
+        let network_slot = network_stack.slot_id();
+        let mut network = self.network.lock().await;
+        let rules = network_stack
+            .setup_public_nat(&self.config.public_network_interface)
+            .and_then(|_| {
+                network
+                    .attach_private_networks(&mut network_stack, &machine_config.private_networks)
+            });
+        if let Err(e) = rules {
+            network.reclaim(network_stack);
+            return Err(e);
+        }
+        drop(network);
 
         let mut overrides = machine::ContainerOverrides {
             cmd_args: None,
             env: None,
+            registry_auth,
         };
 
         if request.cmd_args.len() > 0 {
@@ -88,25 +347,279 @@ impl InnerNodeManager {
             overrides.env = Some(request.env.into_iter().collect());
         }
 
-        let (machine, machine_stop_rx) = Machine::new(
+        let (machine, machine_stop_rx) = match Machine::new(
             &self.config.firecracker_config,
             machine_config,
             network_stack,
             overrides,
         )
-        .await?;
+        .await
+        {
+            Ok(provisioned) => provisioned,
+            Err(e) => {
+                self.network.lock().await.reclaim_slot(network_slot);
+                return Err(e);
+            }
+        };
         let uuid = machine.uuid().to_string();
         info!("Provisioned node {} ", &uuid);
+        self.metrics.record_boot(machine.boot_timings());
+        self.persist(&machine).await;
+        let state_rx = machine.take_state_changes().await?;
+        self.record_provisioned(&machine);
         machines.insert(uuid.clone(), machine);
+        Self::spawn_cleanup_task(self_clone, uuid.clone(), machine_stop_rx, state_rx);
+        Ok(uuid)
+    }
+
+    /// Looks up the volumes to attach, which must exist and not be attached to another machine.
+    fn resolve_volumes(
+        &self,
+        machines: &HashMap<String, Machine>,
+        mounts: &[proto::node::VolumeMount],
+    ) -> anyhow::Result<Vec<VolumeMount>> {
+        let mut volumes = Vec::with_capacity(mounts.len());
+        for mount in mounts {
+            let volume = self
+                .volumes
+                .get(&mount.name)?
+                .ok_or(anyhow::anyhow!("Volume {} does not exist", mount.name))?;
+            if let Some(id) = volume_user(machines, &mount.name) {
+                return Err(anyhow::anyhow!(
+                    "Volume {} is attached to instance {}",
+                    mount.name,
+                    id
+                ));
+            }
+            volumes.push(VolumeMount {
+                name: volume.name,
+                path: volume.path,
+                mount_path: mount.mount_path.clone(),
+                read_only: mount.read_only,
+            });
+        }
+        Ok(volumes)
+    }
+
+    /// The resources which can be handed out to machines in total, after overcommitting.
+    fn total_capacity(&self) -> anyhow::Result<Resources> {
+        let vcpus = self
+            .config
+            .allocatable_vcpus
+            .unwrap_or_else(capacity::host_vcpus);
+        let memory_mb = match self.config.allocatable_memory_mb {
+            Some(memory_mb) => memory_mb,
+            None => capacity::host_memory_mb()?,
+        };
+        let disk_gb = match self.config.allocatable_disk_gb {
+            Some(disk_gb) => disk_gb,
+            None => Machine::scratch_space(&self.config.firecracker_config)?.0 / GB,
+        };
+        Ok(Resources {
+            vcpus: capacity::overcommit(vcpus as u64, self.config.cpu_overcommit_ratio) as u32,
+            memory_mb: capacity::overcommit(memory_mb, self.config.memory_overcommit_ratio),
+            disk_gb: capacity::overcommit(disk_gb, self.config.disk_overcommit_ratio),
+        })
+    }
+
+    /// Updates the gauges, which are read from the machines on every scrape, and encodes all metrics.
+    async fn gather_metrics(&self) -> String {
+        {
+            let machines = self.machines.read().await;
+            let mut log_subscribers = 0;
+            for machine in machines.values() {
+                log_subscribers += machine.log_subscriber_count().await;
+            }
+            self.metrics.running_instances.set(machines.len() as i64);
+            self.metrics.log_subscribers.set(log_subscribers as i64);
+        }
+        self.metrics
+            .network_slots_in_use
+            .set(self.network.lock().await.slots_in_use() as i64);
+        self.metrics.encode()
+    }
 
-        let uuid_clone = uuid.clone();
+    /// Checks that another machine with `requested` resources fits on the node. Its scratch disk also has to be
+    /// able to grow to its full size, along with all others, within the overcommit ratio of the free space.
+    async fn check_capacity(
+        &self,
+        machines: &HashMap<String, Machine>,
+        requested: Resources,
+    ) -> anyhow::Result<()> {
+        refresh_reclaimed_memory(machines).await;
+        let (volume_bytes, volume_allocated_bytes) = self.volumes.disk_usage()?;
+        self.total_capacity()?.admit(
+            allocated_resources(machines) + volume_resources(volume_bytes),
+            requested,
+        )?;
+
+        // Volumes are sparse as well and on the same filesystem
+        let mut unallocated =
+            requested.disk_gb * GB + volume_bytes.saturating_sub(volume_allocated_bytes);
+        for machine in machines.values() {
+            unallocated += machine.unallocated_scratch_bytes().await?;
+        }
+        let (_, available) = Machine::scratch_space(&self.config.firecracker_config)?;
+        capacity::admit_sparse_disk(
+            unallocated,
+            available,
+            self.config.disk_overcommit_ratio,
+            requested.disk_gb,
+        )?;
+        Ok(())
+    }
 
-        // Cleanup task
+    /// Records the state changes of a machine until it stops, then cleans it up.
+    fn spawn_cleanup_task(
+        self_clone: Arc<InnerNodeManager>,
+        uuid: String,
+        mut machine_stop_rx: tokio::sync::oneshot::Receiver<MachineExit>,
+        mut state_rx: mpsc::UnboundedReceiver<InitVmState>,
+    ) {
         tokio::spawn(async move {
-            if let Ok(exit_code) = machine_stop_rx.await {
-                let _ = self_clone.handle_machine_exit(&uuid_clone, exit_code).await;
+            let stopped = loop {
+                tokio::select! {
+                    biased;
+                    Some(state) = state_rx.recv() => self_clone.record_state_change(&uuid, state),
+                    stopped = &mut machine_stop_rx => break stopped,
+                }
+            };
+            // States reported right before the exit
+            while let Ok(state) = state_rx.try_recv() {
+                self_clone.record_state_change(&uuid, state);
+            }
+            if let Ok(exit_code) = stopped {
+                self_clone.metrics.record_exit(exit_code);
+                let _ = self_clone
+                    .handle_machine_exit(&uuid, exit_code, self_clone.clone())
+                    .await;
             }
         });
+    }
+
+    async fn _snapshot(&self, id: &str, stop: bool) -> anyhow::Result<Option<String>> {
+        let snapshot_id = Uuid::new_v4().to_string();
+        let snapshot_dir = self.config.snapshot_directory.join(&snapshot_id);
+        {
+            let machines = self.machines.read().await;
+            let machine = match machines.get(id) {
+                Some(machine) => machine,
+                None => return Ok(None),
+            };
+            info!("Snapshotting node {} as {}", id, &snapshot_id);
+            if let Err(e) = machine.snapshot(&snapshot_dir, !stop).await {
+                let _ = std::fs::remove_dir_all(&snapshot_dir);
+                return Err(e);
+            }
+        }
+        if stop {
+            // The machine is left paused, so there is no point in a graceful shutdown
+            self._deprovision(id, None).await?;
+        }
+        Ok(Some(snapshot_id))
+    }
+
+    /// The complete snapshots on the node, by id.
+    fn _list_snapshots(&self) -> anyhow::Result<Vec<(String, SnapshotMetadata)>> {
+        let entries = match std::fs::read_dir(&self.config.snapshot_directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let id = entry.file_name().to_string_lossy().to_string();
+            if Uuid::parse_str(&id).is_err() {
+                continue;
+            }
+            match Machine::read_snapshot_metadata(&entry.path()) {
+                Ok(metadata) => snapshots.push((id, metadata)),
+                Err(e) => debug!("Skipping incomplete snapshot {}: {:?}", id, e),
+            }
+        }
+        snapshots.sort_by_key(|(_, metadata)| metadata.created_at_ms);
+        Ok(snapshots)
+    }
+
+    /// Removes a snapshot and its files, returns whether it existed.
+    fn _delete_snapshot(&self, snapshot_id: &str) -> anyhow::Result<bool> {
+        match std::fs::remove_dir_all(self.config.snapshot_directory.join(snapshot_id)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn _restore(
+        &self,
+        snapshot_id: &str,
+        self_clone: Arc<InnerNodeManager>, // Self reference for cleanup
+    ) -> anyhow::Result<String> {
+        let snapshot_dir = self.config.snapshot_directory.join(snapshot_id);
+        let metadata = Machine::read_snapshot_metadata(&snapshot_dir)?;
+
+        let mut machines = self.machines.write().await;
+        self.check_capacity(&machines, metadata.config.resources())
+            .await?;
+        for volume in &metadata.config.volumes {
+            if let Some(id) = volume_user(&machines, &volume.name) {
+                return Err(RestoreConflict(format!(
+                    "Volume {} is attached to instance {}",
+                    volume.name, id
+                ))
+                .into());
+            }
+        }
+        // The guest keeps the addresses it had when it was snapshotted
+        let network_slot = metadata.network_slot;
+        if self.network.lock().await.slot_in_use(network_slot) {
+            return Err(RestoreConflict(
+                "The network of the snapshot is in use, the snapshotted instance has to be deprovisioned first"
+                    .to_string(),
+            )
+            .into());
+        }
+        let mut network_stack = self
+            .network
+            .lock()
+            .await
+            .provision_stack_in_slot(network_slot)?;
+        let mut network = self.network.lock().await;
+        let rules = network_stack
+            .setup_public_nat(&self.config.public_network_interface)
+            .and_then(|_| {
+                network
+                    .attach_private_networks(&mut network_stack, &metadata.config.private_networks)
+            })
+            .and_then(|_| network_stack.verify_rules());
+        if let Err(e) = rules {
+            network.reclaim(network_stack);
+            return Err(e);
+        }
+        drop(network);
+
+        let (machine, machine_stop_rx) = match Machine::restore(
+            &self.config.firecracker_config,
+            &snapshot_dir,
+            metadata,
+            network_stack,
+        )
+        .await
+        {
+            Ok(restored) => restored,
+            Err(e) => {
+                self.network.lock().await.reclaim_slot(network_slot);
+                return Err(e);
+            }
+        };
+        let uuid = machine.uuid().to_string();
+        info!("Restored node {} from snapshot {}", &uuid, snapshot_id);
+        self.persist(&machine).await;
+        let state_rx = machine.take_state_changes().await?;
+        self.record_provisioned(&machine);
+        machines.insert(uuid.clone(), machine);
+        Self::spawn_cleanup_task(self_clone, uuid.clone(), machine_stop_rx, state_rx);
         Ok(uuid)
     }
 
@@ -118,16 +631,45 @@ impl InnerNodeManager {
         let mut machines = self.machines.write().await;
         if let Some(machine) = machines.remove(id) {
             info!("Deprovisioning node {}", id);
+            let started = Instant::now();
             let mut network = self.network.lock().await;
             let network_stack = machine.shutdown(graceful_timeout).await;
+            self.metrics.record_deprovision(started.elapsed());
             network.reclaim(network_stack);
+            self.forget(id);
+            self.record_deprovisioned(id);
         } else {
             debug!("Requested deprovisioning of missing machine with id {}", id);
         }
         Ok(())
     }
 
-    async fn handle_machine_exit(&self, id: &str, exit_code: MachineExit) -> anyhow::Result<()> {
+    async fn handle_machine_exit(
+        &self,
+        id: &str,
+        exit_code: MachineExit,
+        self_clone: Arc<InnerNodeManager>, // Self reference for cleanup after a restart
+    ) -> anyhow::Result<()> {
+        let restart_streak = {
+            let machines = self.machines.read().await;
+            // Machines being deprovisioned are already gone, they didn't exit on their own
+            match machines.get(id) {
+                Some(machine) => {
+                    self.events.record(
+                        id,
+                        Event::Exited(InstanceExited {
+                            reason: exit_code.as_str().to_string(),
+                            exit_code: exit_code.exit_code(),
+                        }),
+                    );
+                    machine
+                        .restart_policy()
+                        .should_restart(exit_code, machine.restart_count())
+                        .then(|| machine.restart_streak(RESTART_STABLE_AFTER))
+                }
+                None => None,
+            }
+        };
         if let Some(tx) = self.machine_state_subscription.as_ref() {
             let _ = tx
                 .send((
@@ -136,130 +678,680 @@ impl InnerNodeManager {
                 ))
                 .await;
         }
+        if let Some(restart_streak) = restart_streak {
+            let backoff = restart_backoff(restart_streak);
+            info!("Restarting node {} in {:?}", id, backoff);
+            tokio::time::sleep(backoff).await;
+            return self._restart(id, restart_streak, self_clone).await;
+        }
         self._deprovision(id, None).await
     }
 
-    async fn _drain(&self) -> anyhow::Result<()> {
+    /// Boots an exited machine again, keeping its id, network slot and published ports.
+    /// `restart_streak` is the one the machine exited with, the new run continues it.
+    async fn _restart(
+        &self,
+        id: &str,
+        restart_streak: u32,
+        self_clone: Arc<InnerNodeManager>,
+    ) -> anyhow::Result<()> {
         let mut machines = self.machines.write().await;
-        let mut network_manager = self.network.lock().await;
-        for (_id, machine) in machines.drain() {
-            network_manager.reclaim(machine.shutdown(Some(Duration::from_secs(3))).await);
-        }
+        let Some(machine) = machines.remove(id) else {
+            debug!("Node {} was deprovisioned before it was restarted", id);
+            return Ok(());
+        };
+        let mut state = machine.state().await;
+        state.restart_streak = restart_streak;
+        let network_stack = machine.shutdown(None).await;
+        let network_slot = network_stack.slot_id();
+
+        let (machine, machine_stop_rx) =
+            match Machine::restart(&self.config.firecracker_config, state, network_stack).await {
+                Ok(restarted) => restarted,
+                Err(e) => {
+                    error!("Failed to restart node {}, removing it: {:?}", id, e);
+                    self.network.lock().await.reclaim_slot(network_slot);
+                    self.forget(id);
+                    self.record_deprovisioned(id);
+                    return Err(e);
+                }
+            };
+        info!(
+            "Restarted node {}, restart count {}",
+            id,
+            machine.restart_count()
+        );
+        self.persist(&machine).await;
+        let state_rx = machine.take_state_changes().await?;
+        self.events.record(
+            id,
+            Event::Restarted(InstanceRestarted {
+                restart_count: machine.restart_count(),
+            }),
+        );
+        machines.insert(id.to_string(), machine);
+        Self::spawn_cleanup_task(self_clone, id.to_string(), machine_stop_rx, state_rx);
         Ok(())
     }
-}
 
-pub struct NodeManager {
-    inner: Arc<InnerNodeManager>,
-    authentication_secret: Option<Hmac<Sha256>>,
-}
+    /// Reattaches to the machines which survived a restart and cleans up everything else left behind.
+    async fn _recover(&self, self_clone: Arc<InnerNodeManager>) -> anyhow::Result<()> {
+        let mut machines = self.machines.write().await;
+        for record in self.state.load_all()? {
+            let uuid = record.machine.uuid.clone();
+            match self.reattach(record).await {
+                Ok((machine, machine_stop_rx, state_rx)) => {
+                    info!("Reattached to node {}", &uuid);
+                    machines.insert(uuid.clone(), machine);
+                    Self::spawn_cleanup_task(self_clone.clone(), uuid, machine_stop_rx, state_rx);
+                }
+                Err(e) => {
+                    warn!("Unable to reattach to node {}, removing it: {:?}", &uuid, e);
+                    self.forget(&uuid);
+                }
+            }
+        }
 
-impl NodeManager {
-    pub async fn new(
-        config: ManagerConfig,
-        authentication_secret: Option<&[u8]>,
-        machine_state_subscription: Option<mpsc::Sender<(Uuid, MachineExit)>>,
-    ) -> Result<(
-        Self,
-        tokio::sync::oneshot::Sender<tokio::sync::oneshot::Sender<()>>,
+        for tap in self.network.lock().await.remove_orphaned_taps()? {
+            info!("Removed orphaned TAP device {}", tap);
+        }
+        for uuid in Machine::remove_orphaned_jails(&self.config.firecracker_config, |uuid| {
+            machines.contains_key(uuid)
+        })? {
+            info!("Removed orphaned node {}", uuid);
+        }
+        Ok(())
+    }
+
+    async fn reattach(
+        &self,
+        record: InstanceRecord,
+    ) -> anyhow::Result<(
+        Machine,
+        tokio::sync::oneshot::Receiver<MachineExit>,
+        mpsc::UnboundedReceiver<InitVmState>,
     )> {
-        let inner = Arc::new(InnerNodeManager {
-            machines: RwLock::new(HashMap::new()),
-            config,
-            network: Mutex::new(NetworkManager::new()?),
-            machine_state_subscription,
-        });
-        let (shutdown_tx, shutdown_rx) =
-            tokio::sync::oneshot::channel::<tokio::sync::oneshot::Sender<()>>();
+        let network_slot = record.network_slot;
+        let mut network = self.network.lock().await;
+        let mut network_stack = network.adopt_stack_in_slot(network_slot)?;
+        // The chains are recreated empty, so the rules are added again
+        let mut rules = network_stack
+            .setup_public_nat(&self.config.public_network_interface)
+            .and_then(|_| {
+                network.adopt_private_networks(
+                    &mut network_stack,
+                    &record.machine.config.private_networks,
+                )
+            });
+        for port in record.published_ports {
+            rules = rules.and_then(|_| {
+                network
+                    .publish_port(
+                        &mut network_stack,
+                        &self.config.service_network_interface,
+                        port,
+                    )
+                    .map(|_| ())
+            });
+        }
+        let rules = rules.and_then(|_| network_stack.verify_rules());
+        if let Err(e) = rules {
+            network.reclaim(network_stack);
+            return Err(e);
+        }
+        drop(network);
 
-        let inner_clone = inner.clone();
-        tokio::spawn(async move {
-            if let Ok(finished) = shutdown_rx.await {
-                warn!("Received shutdown signal, draining all machines");
-                inner_clone._drain().await.unwrap_or_else(|e| {
-                    error!("Failed to drain node manager: {}", e);
-                });
-                let _ = finished.send(());
+        match Machine::reattach(
+            &self.config.firecracker_config,
+            record.machine,
+            network_stack,
+        )
+        .await
+        {
+            Ok((machine, machine_stop_rx)) => {
+                let state_rx = machine.take_state_changes().await?;
+                Ok((machine, machine_stop_rx, state_rx))
             }
-        });
-
-        Ok((
-            NodeManager {
-                inner,
-                authentication_secret: authentication_secret
-                    .map(|s| Hmac::new_from_slice(s).expect("Inavlid secret key.")),
-            },
-            shutdown_tx,
-        ))
+            Err(e) => {
+                self.network.lock().await.reclaim_slot(network_slot);
+                Err(e)
+            }
+        }
     }
 
-    fn validate_auth(
-        &self,
-        metadata: &MetadataMap,
-        expected_audience: Option<&str>,
-    ) -> Result<(), Status> {
-        let secret = match self.authentication_secret {
-            Some(ref secret) => secret,
-            None => return Ok(()),
+    /// Writes the current state of a machine to disk, so it can be reattached after a restart.
+    async fn persist(&self, machine: &Machine) {
+        let machine_state = machine.state().await;
+        let network = machine.network().lock().await;
+        let record = InstanceRecord {
+            machine: machine_state,
+            network_slot: network.slot_id(),
+            published_ports: network.published_ports(),
         };
-        if !proto_auth::validate_authentication(
-            metadata
-                .get("auth")
-                .and_then(|v| v.to_str().ok())
-                .unwrap_or(""),
-            secret,
-            expected_audience,
-        ) {
-            return Err(Status::unauthenticated("Invalid authentication token"));
+        drop(network);
+        if let Err(e) = self.state.save(&record) {
+            error!(
+                "Failed to persist state of node {}: {:?}",
+                machine.uuid(),
+                e
+            );
         }
-        Ok(())
     }
-}
 
-#[tonic::async_trait]
-impl NodeManagerService for NodeManager {
-    async fn provision(
-        &self,
-        request: Request<ProvisionRequest>,
-    ) -> Result<Response<ProvisionResponse>, Status> {
-        self.validate_auth(request.metadata(), None)?;
-        let request = request.into_inner();
-        debug!("Provisioning machine with request: {:?}", request);
-        let id = self
-            .inner
-            ._provision(request, self.inner.clone())
-            .await
-            .map_err(|e| {
-                error!("Failed to provision machine: {}", e);
-                Status::internal("Failed to provision machine")
-            })?;
-        Ok(Response::new(ProvisionResponse { id }))
+    fn forget(&self, id: &str) {
+        if let Err(e) = self.state.remove(id) {
+            error!("Failed to remove persisted state of node {}: {:?}", id, e);
+        }
     }
 
-    async fn deprovision(
-        &self,
-        request: Request<DeprovisionRequest>,
-    ) -> Result<Response<Empty>, Status> {
-        self.validate_auth(request.metadata(), Some(&request.get_ref().instance_id))?;
-        let request = request.into_inner();
+    fn record_provisioned(&self, machine: &Machine) {
+        self.events.record(
+            machine.uuid(),
+            Event::Provisioned(InstanceProvisioned {
+                container_reference: machine.container_reference().to_string(),
+                labels: machine.labels().clone().into_iter().collect(),
+            }),
+        );
+    }
 
-        let timeout;
-        if request.timeout_millis > 0 {
-            timeout = Some(Duration::from_millis(request.timeout_millis as u64));
-        } else {
-            timeout = None;
+    fn record_state_change(&self, id: &str, state: InitVmState) {
+        self.events.record(
+            id,
+            Event::StateChanged(InstanceStateChanged {
+                state: state.as_str().to_string(),
+            }),
+        );
+    }
+
+    fn record_deprovisioned(&self, id: &str) {
+        self.events
+            .record(id, Event::Deprovisioned(InstanceDeprovisioned {}));
+    }
+
+    /// Shuts down all machines, or only those matching `selector`.
+    async fn _drain(&self, selector: Option<&Selector>) -> anyhow::Result<()> {
+        let mut machines = self.machines.write().await;
+        let mut network_manager = self.network.lock().await;
+        let ids = machines
+            .iter()
+            .filter(|(_, machine)| selector.is_none_or(|s| s.matches(machine.labels())))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in ids {
+            let machine = machines.remove(&id).expect("Selected machine exists");
+            network_manager.reclaim(machine.shutdown(Some(Duration::from_secs(3))).await);
+            self.forget(&id);
+            self.record_deprovisioned(&id);
         }
-        self.inner
-            ._deprovision(&request.instance_id, timeout)
-            .await
-            .map_err(|e| {
-                error!("Failed to deprovision machine: {}", e);
-                Status::internal("Failed to deprovision machine")
-            })?;
-        Ok(Response::new(Empty {}))
+        Ok(())
     }
+}
 
-    type StreamLogsStream = Pin<Box<dyn Stream<Item = Result<LogMessage, Status>> + Send>>;
+/// The id of the machine a volume is attached to.
+fn volume_user<'a>(machines: &'a HashMap<String, Machine>, name: &str) -> Option<&'a str> {
+    machines
+        .iter()
+        .find(|(_, machine)| machine.volumes().iter().any(|volume| volume.name == name))
+        .map(|(id, _)| id.as_str())
+}
+
+fn validate_volume_mounts(mounts: &[proto::node::VolumeMount]) -> Result<(), String> {
+    if mounts.len() > MAX_VOLUMES_PER_INSTANCE {
+        return Err(format!(
+            "At most {} volumes can be attached",
+            MAX_VOLUMES_PER_INSTANCE
+        ));
+    }
+    for (i, mount) in mounts.iter().enumerate() {
+        if !volumes::is_valid_mount_path(&mount.mount_path) {
+            return Err(format!("Invalid mount path {}", mount.mount_path));
+        }
+        if mounts[..i]
+            .iter()
+            .any(|other| other.name == mount.name || other.mount_path == mount.mount_path)
+        {
+            return Err(format!("Volume {} is mounted twice", mount.name));
+        }
+    }
+    Ok(())
+}
+
+fn validate_exec_args(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err("No command given".to_string());
+    }
+    // The exec id doesn't matter for the size, the largest one is the worst case
+    let packet = HostPacket::Exec(vmproto::host::ExecRequest {
+        exec_id: u32::MAX,
+        args: args.to_vec(),
+    });
+    if serialize_host_packet(&packet).len() > MAX_HOST_PACKET_SIZE {
+        return Err(format!(
+            "The command exceeds the limit of {} bytes",
+            MAX_HOST_PACKET_SIZE
+        ));
+    }
+    Ok(())
+}
+
+fn validate_private_networks(
+    names: &[String],
+    configured: &HashMap<String, PrivateNetworkConfig>,
+) -> Result<(), String> {
+    if names.len() > MAX_PRIVATE_NETWORKS_PER_INSTANCE {
+        return Err(format!(
+            "At most {} private networks can be joined",
+            MAX_PRIVATE_NETWORKS_PER_INSTANCE
+        ));
+    }
+    for (i, name) in names.iter().enumerate() {
+        if !configured.contains_key(name) {
+            return Err(format!("Unknown private network {}", name));
+        }
+        if names[..i].contains(name) {
+            return Err(format!("Private network {} is joined twice", name));
+        }
+    }
+    Ok(())
+}
+
+/// Updates the memory the balloons of all machines took back, so it counts as free.
+async fn refresh_reclaimed_memory(machines: &HashMap<String, Machine>) {
+    for machine in machines.values() {
+        machine.refresh_reclaimed_memory().await;
+    }
+}
+
+/// The resources handed out to all machines.
+fn allocated_resources(machines: &HashMap<String, Machine>) -> Resources {
+    machines
+        .values()
+        .fold(Resources::default(), |allocated, machine| {
+            allocated + machine.resources()
+        })
+}
+
+/// The disk taken by volumes, rounded up to whole GB like the scratch disks.
+fn volume_resources(volume_bytes: u64) -> Resources {
+    Resources {
+        disk_gb: volume_bytes.div_ceil(GB),
+        ..Default::default()
+    }
+}
+
+fn resources_message(resources: Resources) -> proto::node::Resources {
+    proto::node::Resources {
+        vcpus: resources.vcpus,
+        memory_mb: resources.memory_mb,
+        disk_gb: resources.disk_gb,
+    }
+}
+
+/// A restore clashing with the instances on the node, reported as `FAILED_PRECONDITION`.
+#[derive(Debug)]
+struct RestoreConflict(String);
+
+impl std::fmt::Display for RestoreConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RestoreConflict {}
+
+/// Requests which don't fit on the node are the caller's problem, anything else is ours.
+fn provision_error(e: anyhow::Error, message: &str) -> Status {
+    match e.downcast_ref::<InsufficientCapacity>() {
+        Some(e) => Status::resource_exhausted(e.to_string()),
+        None => {
+            error!("{}: {}", message, e);
+            Status::internal(message)
+        }
+    }
+}
+
+fn validate_resources(vcpus: i32, memory_mb: i32) -> Result<(), String> {
+    if !(1..=MAX_VCPUS).contains(&vcpus) {
+        return Err(format!("vCPUs must be between 1 and {}", MAX_VCPUS));
+    }
+    if memory_mb < MIN_MEMORY_MB {
+        return Err(format!("Memory must be at least {} MB", MIN_MEMORY_MB));
+    }
+    Ok(())
+}
+
+fn validate_volume_size(size_mb: u64, max_size_mb: u64) -> Result<(), String> {
+    if size_mb == 0 || size_mb > max_size_mb {
+        return Err(format!("Volumes need between 1 and {} MB", max_size_mb));
+    }
+    Ok(())
+}
+
+fn validate_scratch_disk(size_gb: Option<u64>, max_size_gb: u64) -> Result<(), String> {
+    match size_gb {
+        Some(0) => Err("The scratch disk needs at least 1 GB".to_string()),
+        Some(size_gb) if size_gb > max_size_gb => Err(format!(
+            "The scratch disk can be at most {} GB",
+            max_size_gb
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn restart_policy(policy: Option<proto::node::RestartPolicy>) -> RestartPolicy {
+    match policy {
+        None => RestartPolicy::Never,
+        Some(policy) => match policy.mode() {
+            RestartMode::Never => RestartPolicy::Never,
+            RestartMode::OnFailure => RestartPolicy::OnFailure {
+                max_retries: policy.max_retries,
+            },
+            RestartMode::Always => RestartPolicy::Always,
+        },
+    }
+}
+
+fn validate_io_limits(limits: Option<&proto::node::IoLimits>) -> Result<(), String> {
+    let Some(limits) = limits else {
+        return Ok(());
+    };
+    for limiter in [&limits.drive, &limits.network_rx, &limits.network_tx]
+        .into_iter()
+        .flatten()
+    {
+        for bucket in [&limiter.bandwidth, &limiter.ops].into_iter().flatten() {
+            if bucket.size == 0 || bucket.refill_time_ms == 0 {
+                return Err("Token buckets need a size and a refill time".to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the limiters which are set in `patch`, empty ones remove the limit.
+fn patch_io_limits(limits: machine::IoLimits, patch: proto::node::IoLimits) -> machine::IoLimits {
+    let patched = |current, limiter: Option<proto::node::RateLimiter>| match limiter {
+        Some(limiter) => rate_limiter(limiter),
+        None => current,
+    };
+    machine::IoLimits {
+        drive: patched(limits.drive, patch.drive),
+        network_rx: patched(limits.network_rx, patch.network_rx),
+        network_tx: patched(limits.network_tx, patch.network_tx),
+    }
+}
+
+fn rate_limiter(limiter: proto::node::RateLimiter) -> Option<machine::RateLimiter> {
+    let bucket = |bucket: proto::node::TokenBucket| machine::TokenBucket {
+        size: bucket.size,
+        one_time_burst: bucket.one_time_burst,
+        refill_time: bucket.refill_time_ms,
+    };
+    match (limiter.bandwidth, limiter.ops) {
+        (None, None) => None,
+        (bandwidth, ops) => Some(machine::RateLimiter {
+            bandwidth: bandwidth.map(bucket),
+            ops: ops.map(bucket),
+        }),
+    }
+}
+
+fn published_port(request: &PublishServicePortRequest) -> Result<PublishedPort, String> {
+    let host_port = u16::try_from(request.host_port).map_err(|_| "Invalid host port")?;
+    let guest_port = u16::try_from(request.guest_port).map_err(|_| "Invalid guest port")?;
+    let port_count =
+        u16::try_from(request.port_count.unwrap_or(1)).map_err(|_| "Invalid port count")?;
+    let port = PublishedPort {
+        host_port,
+        guest_port,
+        protocol: protocol(request.protocol()),
+        port_count,
+        host_ip: parse_host_ip(request.host_ip.as_deref())?,
+    };
+    port.validate().map_err(|e| e.to_string())?;
+    Ok(port)
+}
+
+fn protocol(protocol: proto::node::Protocol) -> Protocol {
+    match protocol {
+        proto::node::Protocol::Tcp => Protocol::Tcp,
+        proto::node::Protocol::Udp => Protocol::Udp,
+        proto::node::Protocol::Sctp => Protocol::Sctp,
+    }
+}
+
+fn parse_host_ip(host_ip: Option<&str>) -> Result<Option<IpAddr>, String> {
+    host_ip
+        .map(|ip| ip.parse().map_err(|_| format!("Invalid host IP {}", ip)))
+        .transpose()
+}
+
+async fn forward_attach_input(
+    comm: &Mutex<MachineCommunicator>,
+    input: AttachInput,
+) -> Result<(), std::io::Error> {
+    let mut comm = comm.lock().await;
+    if let Some(stdin) = input.stdin {
+        comm.send_stdin(&stdin).await?;
+    }
+    if let Some(size) = input.resize {
+        comm.resize_terminal(
+            u16::try_from(size.rows).unwrap_or(u16::MAX),
+            u16::try_from(size.cols).unwrap_or(u16::MAX),
+        )
+        .await?;
+    }
+    if input.close_stdin {
+        comm.close_stdin().await?;
+    }
+    Ok(())
+}
+
+pub struct NodeManager {
+    inner: Arc<InnerNodeManager>,
+    authentication_secret: Option<Hmac<Sha256>>,
+}
+
+impl NodeManager {
+    pub async fn new(
+        config: ManagerConfig,
+        authentication_secret: Option<&[u8]>,
+        machine_state_subscription: Option<mpsc::Sender<(Uuid, MachineExit)>>,
+    ) -> Result<(
+        Self,
+        tokio::sync::oneshot::Sender<tokio::sync::oneshot::Sender<()>>,
+    )> {
+        config.validate()?;
+        let state = StateStore::open(&config.state_directory)?;
+        let events = {
+            let state = state.clone();
+            EventLog::new(
+                state.load_event_revision()?,
+                Box::new(move |revision| state.save_event_revision(revision)),
+            )
+        };
+        let inner = Arc::new(InnerNodeManager {
+            machines: RwLock::new(HashMap::new()),
+            network: Mutex::new(NetworkManager::new(
+                config.ipv6,
+                config.allocatable_host_ports.clone(),
+                config.private_networks.clone(),
+            )?),
+            images: ImageCache::new(&config.image_cache_directory)?,
+            state,
+            volumes: VolumeStore::open(&config.volume_directory)?,
+            events,
+            metrics: Metrics::new(),
+            config,
+            machine_state_subscription,
+        });
+        inner._recover(inner.clone()).await?;
+
+        if let Some(addr) = inner.config.metrics_address {
+            let listener = metrics::bind(addr).await?;
+            let inner = inner.clone();
+            tokio::spawn(async move {
+                let gather = move || {
+                    let inner = inner.clone();
+                    async move { inner.gather_metrics().await }
+                };
+                metrics::serve(listener, gather).await;
+            });
+        }
+
+        let (shutdown_tx, shutdown_rx) =
+            tokio::sync::oneshot::channel::<tokio::sync::oneshot::Sender<()>>();
+
+        let inner_clone = inner.clone();
+        tokio::spawn(async move {
+            if let Ok(finished) = shutdown_rx.await {
+                warn!("Received shutdown signal, draining all machines");
+                inner_clone._drain(None).await.unwrap_or_else(|e| {
+                    error!("Failed to drain node manager: {}", e);
+                });
+                let _ = finished.send(());
+            }
+        });
+
+        Ok((
+            NodeManager {
+                inner,
+                authentication_secret: authentication_secret
+                    .map(|s| Hmac::new_from_slice(s).expect("Inavlid secret key.")),
+            },
+            shutdown_tx,
+        ))
+    }
+
+    fn registry_auth(
+        &self,
+        credentials: Option<RegistryCredentials>,
+    ) -> anyhow::Result<Option<RegistryAuth>> {
+        let auth = match credentials.and_then(|c| c.credentials) {
+            None => None,
+            Some(Credentials::Basic(basic)) => Some(RegistryAuth::Basic {
+                username: basic.username,
+                password: basic.password,
+            }),
+            Some(Credentials::BearerToken(token)) => Some(RegistryAuth::Bearer { token }),
+            Some(Credentials::Named(name)) => Some(
+                self.inner
+                    .config
+                    .registry_credentials
+                    .get(&name)
+                    .cloned()
+                    .ok_or(anyhow::anyhow!("Unknown registry credentials {}", name))?,
+            ),
+        };
+        Ok(auth)
+    }
+
+    fn validate_auth(
+        &self,
+        metadata: &MetadataMap,
+        expected_audience: Option<&str>,
+    ) -> Result<(), Status> {
+        let secret = match self.authentication_secret {
+            Some(ref secret) => secret,
+            None => return Ok(()),
+        };
+        if !proto_auth::validate_authentication(
+            metadata
+                .get("auth")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or(""),
+            secret,
+            expected_audience,
+        ) {
+            return Err(Status::unauthenticated("Invalid authentication token"));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl NodeManagerService for NodeManager {
+    async fn provision(
+        &self,
+        request: Request<ProvisionRequest>,
+    ) -> Result<Response<ProvisionResponse>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let mut request = request.into_inner();
+        // Taken out before the request is logged
+        let registry_auth = self
+            .registry_auth(request.registry_credentials.take())
+            .map_err(|e| {
+                warn!("Invalid registry credentials requested: {}", e);
+                Status::invalid_argument("Unknown registry credentials")
+            })?;
+        debug!("Provisioning machine with request: {:?}", request);
+        labels::validate(&request.labels).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        validate_resources(request.vcpus, request.memory_mb).map_err(Status::invalid_argument)?;
+        validate_volume_mounts(&request.volumes).map_err(Status::invalid_argument)?;
+        validate_private_networks(
+            &request.private_networks,
+            &self.inner.config.private_networks,
+        )
+        .map_err(Status::invalid_argument)?;
+        validate_io_limits(request.io_limits.as_ref()).map_err(Status::invalid_argument)?;
+        validate_scratch_disk(
+            request.scratch_disk_gb,
+            self.inner.config.max_scratch_disk_gb,
+        )
+        .map_err(Status::invalid_argument)?;
+        {
+            let machines = self.inner.machines.read().await;
+            self.inner
+                .resolve_volumes(&machines, &request.volumes)
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+            let requested = Resources {
+                vcpus: request.vcpus as u32,
+                memory_mb: request.memory_mb as u64,
+                disk_gb: request
+                    .scratch_disk_gb
+                    .unwrap_or(self.inner.config.default_scratch_disk_gb),
+            };
+            self.inner
+                .check_capacity(&machines, requested)
+                .await
+                .map_err(|e| provision_error(e, "Failed to provision machine"))?;
+        }
+        let started = Instant::now();
+        let provisioned = self
+            .inner
+            ._provision(request, registry_auth, self.inner.clone())
+            .await;
+        self.inner
+            .metrics
+            .record_provision(provisioned.is_ok(), started.elapsed());
+        let id = provisioned.map_err(|e| provision_error(e, "Failed to provision machine"))?;
+        Ok(Response::new(ProvisionResponse { id }))
+    }
+
+    async fn deprovision(
+        &self,
+        request: Request<DeprovisionRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().instance_id))?;
+        let request = request.into_inner();
+
+        let timeout;
+        if request.timeout_millis > 0 {
+            timeout = Some(Duration::from_millis(request.timeout_millis as u64));
+        } else {
+            timeout = None;
+        }
+        self.inner
+            ._deprovision(&request.instance_id, timeout)
+            .await
+            .map_err(|e| {
+                error!("Failed to deprovision machine: {}", e);
+                Status::internal("Failed to deprovision machine")
+            })?;
+        Ok(Response::new(Empty {}))
+    }
+
+    type StreamLogsStream = Pin<Box<dyn Stream<Item = Result<LogMessage, Status>> + Send>>;
 
     async fn stream_logs(
         &self,
@@ -303,17 +1395,40 @@ impl NodeManagerService for NodeManager {
 
     async fn list_instances(
         &self,
-        request: Request<Empty>,
+        request: Request<ListInstancesRequest>,
     ) -> Result<Response<InstanceList>, Status> {
         self.validate_auth(request.metadata(), None)?;
+        let selector = Selector::parse(&request.get_ref().label_selector)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
         let machines = self.inner.machines.read().await;
         let instances = machines
             .iter()
+            .filter(|(_, machine)| selector.matches(machine.labels()))
             .map(|(id, _)| InstanceId { id: id.clone() })
             .collect::<Vec<_>>();
         Ok(Response::new(InstanceList { instances }))
     }
 
+    async fn inspect_instance(
+        &self,
+        request: Request<InstanceId>,
+    ) -> Result<Response<InstanceInfo>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        let machines = self.inner.machines.read().await;
+        let machine = match machines.get(&request.id) {
+            Some(machine) => machine,
+            None => {
+                warn!(
+                    "Requested inspect of missing machine with id {}",
+                    &request.id
+                );
+                return Err(Status::not_found("Machine not found"));
+            }
+        };
+        Ok(Response::new(machine.inspect().await))
+    }
+
     async fn get_logs(&self, request: Request<InstanceId>) -> Result<Response<AllLogs>, Status> {
         self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
         let request = request.into_inner();
@@ -344,15 +1459,12 @@ impl NodeManagerService for NodeManager {
     async fn publish_service_port(
         &self,
         request: Request<PublishServicePortRequest>,
-    ) -> Result<Response<Empty>, Status> {
+    ) -> Result<Response<PublishedPortMessage>, Status> {
         self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
         let request = request.into_inner();
         debug!("Publishing service port with request: {:?}", request);
 
-        let host_port = u16::try_from(request.host_port)
-            .map_err(|_| Status::invalid_argument("Invalid host port"))?;
-        let guest_port = u16::try_from(request.guest_port)
-            .map_err(|_| Status::invalid_argument("Invalid guest port"))?;
+        let port = published_port(&request).map_err(Status::invalid_argument)?;
 
         let machines = self.inner.machines.read().await;
         let machine = match machines.get(&request.id) {
@@ -366,44 +1478,901 @@ impl NodeManagerService for NodeManager {
             }
         };
 
-        machine
-            .network()
+        let mut network = machine.network().lock().await;
+        if port.host_ip.is_some_and(|ip| ip.is_ipv6()) && network.ipv6().is_none() {
+            return Err(Status::failed_precondition(
+                "The instance has no IPv6 address",
+            ));
+        }
+        let port = self
+            .inner
+            .network
             .lock()
             .await
-            .setup_forwarding(
+            .publish_port(
+                &mut network,
                 &self.inner.config.service_network_interface,
-                host_port,
-                guest_port,
+                port,
             )
             .map_err(|e| {
+                if let Some(e) = e.downcast_ref::<PortConflict>() {
+                    return Status::already_exists(e.to_string());
+                }
+                if let Some(e) = e.downcast_ref::<InsufficientCapacity>() {
+                    return Status::resource_exhausted(e.to_string());
+                }
                 error!("Failed to publish service port: {}", e);
                 Status::internal("Failed to publish service port")
-            })?;
+            })?
+            .as_proto();
+        drop(network);
+        self.inner.persist(machine).await;
+        self.inner
+            .events
+            .record(&request.id, Event::PortPublished(port.clone()));
 
-        Ok(Response::new(Empty {}))
+        Ok(Response::new(port))
     }
 
-    async fn drain(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        self.validate_auth(request.metadata(), None)?;
-        warn!("Draining all machines on node");
-        self.inner._drain().await.map_err(|e| {
-            error!("Failed to drain node: {}", e);
-            Status::internal("Failed to drain node")
-        })?;
-        Ok(Response::new(Empty {}))
-    }
-}
+    async fn unpublish_service_port(
+        &self,
+        request: Request<UnpublishServicePortRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        debug!("Unpublishing service port with request: {:?}", request);
 
-pub async fn serve(
-    manager: NodeManager,
-    addr: SocketAddr,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let server = NodeManagerServiceServer::new(manager);
-    info!("NodeManager server listening on {}", addr);
-    tonic::transport::Server::builder()
-        .add_service(server)
-        .serve(addr)
-        .await
-        .expect("Failed to start server");
-    Ok(())
+        let host_port = u16::try_from(request.host_port)
+            .map_err(|_| Status::invalid_argument("Invalid host port"))?;
+        let host_ip =
+            parse_host_ip(request.host_ip.as_deref()).map_err(Status::invalid_argument)?;
+
+        let machines = self.inner.machines.read().await;
+        let machine = match machines.get(&request.id) {
+            Some(machine) => machine,
+            None => {
+                warn!(
+                    "Requested unpublish port for missing machine with id {}",
+                    &request.id
+                );
+                return Err(Status::not_found("Machine not found"));
+            }
+        };
+
+        let mut network = machine.network().lock().await;
+        let port = self
+            .inner
+            .network
+            .lock()
+            .await
+            .unpublish_port(
+                &mut network,
+                protocol(request.protocol()),
+                host_port,
+                host_ip,
+            )
+            .map_err(|e| {
+                error!("Failed to unpublish service port: {}", e);
+                Status::internal("Failed to unpublish service port")
+            })?
+            .ok_or(Status::not_found("Port not published"))?;
+        drop(network);
+        self.inner.persist(machine).await;
+        self.inner
+            .events
+            .record(&request.id, Event::PortUnpublished(port.as_proto()));
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn list_published_ports(
+        &self,
+        request: Request<InstanceId>,
+    ) -> Result<Response<PublishedPortList>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        let machines = self.inner.machines.read().await;
+        let machine = match machines.get(&request.id) {
+            Some(machine) => machine,
+            None => {
+                warn!(
+                    "Requested published ports of missing machine with id {}",
+                    &request.id
+                );
+                return Err(Status::not_found("Machine not found"));
+            }
+        };
+        let ports = machine
+            .network()
+            .lock()
+            .await
+            .published_ports()
+            .iter()
+            .map(|port| port.as_proto())
+            .collect();
+        Ok(Response::new(PublishedPortList { ports }))
+    }
+
+    async fn snapshot_instance(
+        &self,
+        request: Request<SnapshotInstanceRequest>,
+    ) -> Result<Response<SnapshotId>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        debug!("Snapshotting machine with request: {:?}", request);
+        let snapshot_id = self
+            .inner
+            ._snapshot(&request.id, request.stop)
+            .await
+            .map_err(|e| {
+                error!("Failed to snapshot machine: {}", e);
+                Status::internal("Failed to snapshot machine")
+            })?;
+        match snapshot_id {
+            Some(id) => Ok(Response::new(SnapshotId { id })),
+            None => {
+                warn!(
+                    "Requested snapshot of missing machine with id {}",
+                    &request.id
+                );
+                Err(Status::not_found("Machine not found"))
+            }
+        }
+    }
+
+    async fn restore_instance(
+        &self,
+        request: Request<RestoreInstanceRequest>,
+    ) -> Result<Response<ProvisionResponse>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let request = request.into_inner();
+        debug!("Restoring machine with request: {:?}", request);
+        if Uuid::parse_str(&request.snapshot_id).is_err() {
+            return Err(Status::invalid_argument("Invalid snapshot id"));
+        }
+        if !self
+            .inner
+            .config
+            .snapshot_directory
+            .join(&request.snapshot_id)
+            .exists()
+        {
+            warn!(
+                "Requested restore of missing snapshot {}",
+                &request.snapshot_id
+            );
+            return Err(Status::not_found("Snapshot not found"));
+        }
+        let id = self
+            .inner
+            ._restore(&request.snapshot_id, self.inner.clone())
+            .await
+            .map_err(|e| match e.downcast_ref::<RestoreConflict>() {
+                Some(e) => Status::failed_precondition(e.to_string()),
+                None => provision_error(e, "Failed to restore machine"),
+            })?;
+        Ok(Response::new(ProvisionResponse { id }))
+    }
+
+    async fn list_snapshots(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<SnapshotList>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let snapshots = self
+            .inner
+            ._list_snapshots()
+            .map_err(|e| {
+                error!("Failed to list snapshots: {:?}", e);
+                Status::internal("Failed to list snapshots")
+            })?
+            .into_iter()
+            .map(|(id, metadata)| SnapshotInfo {
+                id,
+                container_reference: metadata.config.container_reference,
+                created_at_ms: metadata.created_at_ms as i64,
+            })
+            .collect();
+        Ok(Response::new(SnapshotList { snapshots }))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: Request<SnapshotId>,
+    ) -> Result<Response<Empty>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let request = request.into_inner();
+        if Uuid::parse_str(&request.id).is_err() {
+            return Err(Status::invalid_argument("Invalid snapshot id"));
+        }
+        // Held so the snapshot can't be restored while it's deleted
+        let _machines = self.inner.machines.read().await;
+        let deleted = self.inner._delete_snapshot(&request.id).map_err(|e| {
+            error!("Failed to delete snapshot: {:?}", e);
+            Status::internal("Failed to delete snapshot")
+        })?;
+        if !deleted {
+            warn!("Requested deletion of missing snapshot {}", &request.id);
+            return Err(Status::not_found("Snapshot not found"));
+        }
+        info!("Deleted snapshot {}", &request.id);
+        Ok(Response::new(Empty {}))
+    }
+
+    type ExecStream = Pin<Box<dyn Stream<Item = Result<ExecOutput, Status>> + Send>>;
+
+    async fn exec(
+        &self,
+        request: Request<ExecRequest>,
+    ) -> Result<Response<Self::ExecStream>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        debug!("Exec in machine with request: {:?}", request);
+        validate_exec_args(&request.cmd_args).map_err(Status::invalid_argument)?;
+
+        let machines = self.inner.machines.read().await;
+        let machine = match machines.get(&request.id) {
+            Some(machine) => machine,
+            None => {
+                warn!("Requested exec in missing machine with id {}", &request.id);
+                return Err(Status::not_found("Machine not found"));
+            }
+        };
+
+        let mut exec_rx = machine.exec(request.cmd_args).await.map_err(|e| {
+            error!("Failed to exec in machine: {}", e);
+            Status::internal("Failed to exec in machine")
+        })?;
+
+        let (tx, rpc_rx) = mpsc::channel(128);
+        tokio::spawn(async move {
+            while let Some(event) = exec_rx.recv().await {
+                let exited = matches!(event, ExecEvent::Exited(_));
+                if tx.send(Ok(event.as_proto_exec_output())).await.is_err() || exited {
+                    return;
+                }
+            }
+            // The machine stopped or the output wasn't read fast enough
+            let _ = tx
+                .send(Err(Status::aborted("Exec ended without an exit code")))
+                .await;
+        });
+
+        let output_stream = ReceiverStream::new(rpc_rx);
+        Ok(Response::new(Box::pin(output_stream) as Self::ExecStream))
+    }
+
+    type AttachStream = Pin<Box<dyn Stream<Item = Result<AttachOutput, Status>> + Send>>;
+
+    async fn attach(
+        &self,
+        request: Request<Streaming<AttachInput>>,
+    ) -> Result<Response<Self::AttachStream>, Status> {
+        let metadata = request.metadata().clone();
+        let mut inbound = request.into_inner();
+        let first = inbound.message().await?.ok_or(Status::invalid_argument(
+            "Expected an initial attach message",
+        ))?;
+        self.validate_auth(&metadata, Some(&first.id))?;
+        debug!("Attaching to machine with id {}", &first.id);
+
+        let (comm, terminal) = {
+            let machines = self.inner.machines.read().await;
+            let machine = match machines.get(&first.id) {
+                Some(machine) => machine,
+                None => {
+                    warn!("Requested attach to missing machine with id {}", &first.id);
+                    return Err(Status::not_found("Machine not found"));
+                }
+            };
+            let comm = machine.communicator().map_err(|e| {
+                error!("Failed to attach to machine: {}", e);
+                Status::internal("Failed to attach to machine")
+            })?;
+            (comm, machine.terminal())
+        };
+
+        let (tx, rpc_rx) = mpsc::channel(128);
+        if terminal {
+            let (buffered, mut terminal_rx) = comm.lock().await.subscribe_terminal();
+            tokio::spawn(async move {
+                let output = |data: &[u8]| AttachOutput {
+                    output_type: "terminal".to_string(),
+                    data: data.to_vec(),
+                };
+                for data in buffered {
+                    if tx.send(Ok(output(&data))).await.is_err() {
+                        return;
+                    }
+                }
+                while let Some(data) = terminal_rx.recv().await {
+                    if tx.send(Ok(output(&data))).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        } else {
+            let mut log_rx = comm.lock().await.subscribe_log();
+            tokio::spawn(async move {
+                while let Some(log) = log_rx.recv().await {
+                    let log = match &*log {
+                        MachineLog::VmLog(log) if log.message_type != LogMessageType::System => log,
+                        _ => continue,
+                    };
+                    let output = AttachOutput {
+                        output_type: log.message_type.as_str().to_string(),
+                        data: format!("{}\n", log.text).into_bytes(),
+                    };
+                    if tx.send(Ok(output)).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        forward_attach_input(&comm, first).await.map_err(|e| {
+            error!("Failed to forward attach input: {}", e);
+            Status::internal("Failed to forward attach input")
+        })?;
+        tokio::spawn(async move {
+            while let Ok(Some(input)) = inbound.message().await {
+                if let Err(e) = forward_attach_input(&comm, input).await {
+                    debug!("Stopped forwarding attach input: {}", e);
+                    return;
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rpc_rx);
+        Ok(Response::new(Box::pin(output_stream) as Self::AttachStream))
+    }
+
+    async fn drain(&self, request: Request<DrainRequest>) -> Result<Response<Empty>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let request = request.into_inner();
+        let selector = Selector::parse(&request.label_selector)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if request.label_selector.is_empty() {
+            warn!("Draining all machines on node");
+        } else {
+            warn!("Draining machines matching {}", &request.label_selector);
+        }
+        self.inner._drain(Some(&selector)).await.map_err(|e| {
+            error!("Failed to drain node: {}", e);
+            Status::internal("Failed to drain node")
+        })?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn create_volume(
+        &self,
+        request: Request<CreateVolumeRequest>,
+    ) -> Result<Response<Volume>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let request = request.into_inner();
+        if !volumes::is_valid_name(&request.name) {
+            return Err(Status::invalid_argument("Invalid volume name"));
+        }
+        validate_volume_size(request.size_mb, self.inner.config.max_volume_size_mb)
+            .map_err(Status::invalid_argument)?;
+        let existing = self.inner.volumes.get(&request.name).map_err(|e| {
+            error!("Failed to look up volume: {:?}", e);
+            Status::internal("Failed to create volume")
+        })?;
+        if existing.is_some() {
+            return Err(Status::already_exists("Volume already exists"));
+        }
+        {
+            let machines = self.inner.machines.read().await;
+            let requested = volume_resources(request.size_mb.saturating_mul(1024 * 1024));
+            self.inner
+                .check_capacity(&machines, requested)
+                .await
+                .map_err(|e| provision_error(e, "Failed to create volume"))?;
+        }
+
+        info!(
+            "Creating volume {} with {} MB",
+            &request.name, request.size_mb
+        );
+        let inner = self.inner.clone();
+        let volume = tokio::task::spawn_blocking(move || {
+            inner.volumes.create(&request.name, request.size_mb)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res)
+        .map_err(|e| {
+            error!("Failed to create volume: {:?}", e);
+            Status::internal("Failed to create volume")
+        })?;
+        Ok(Response::new(Volume {
+            name: volume.name,
+            size_mb: volume.size_mb,
+            attached_to: None,
+        }))
+    }
+
+    async fn delete_volume(&self, request: Request<VolumeName>) -> Result<Response<Empty>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let request = request.into_inner();
+        // Held so the volume can't be attached while it's deleted
+        let machines = self.inner.machines.read().await;
+        if let Some(id) = volume_user(&machines, &request.name) {
+            warn!(
+                "Requested deletion of volume {} attached to {}",
+                &request.name, id
+            );
+            return Err(Status::failed_precondition(
+                "Volume is attached to an instance",
+            ));
+        }
+        let deleted = self.inner.volumes.delete(&request.name).map_err(|e| {
+            error!("Failed to delete volume: {:?}", e);
+            Status::internal("Failed to delete volume")
+        })?;
+        if !deleted {
+            warn!("Requested deletion of missing volume {}", &request.name);
+            return Err(Status::not_found("Volume not found"));
+        }
+        info!("Deleted volume {}", &request.name);
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn list_volumes(&self, request: Request<Empty>) -> Result<Response<VolumeList>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let machines = self.inner.machines.read().await;
+        let volumes = self
+            .inner
+            .volumes
+            .list()
+            .map_err(|e| {
+                error!("Failed to list volumes: {:?}", e);
+                Status::internal("Failed to list volumes")
+            })?
+            .into_iter()
+            .map(|volume| Volume {
+                attached_to: volume_user(&machines, &volume.name).map(str::to_string),
+                name: volume.name,
+                size_mb: volume.size_mb,
+            })
+            .collect();
+        Ok(Response::new(VolumeList { volumes }))
+    }
+
+    async fn get_instance_stats(
+        &self,
+        request: Request<InstanceId>,
+    ) -> Result<Response<InstanceStats>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        let machines = self.inner.machines.read().await;
+        let machine = match machines.get(&request.id) {
+            Some(machine) => machine,
+            None => {
+                warn!("Requested stats of missing machine with id {}", &request.id);
+                return Err(Status::not_found("Machine not found"));
+            }
+        };
+        match machine.stats().await {
+            Some(stats) => Ok(Response::new(stats)),
+            None => Err(Status::unavailable("No stats reported yet")),
+        }
+    }
+
+    async fn patch_instance_limits(
+        &self,
+        request: Request<PatchInstanceLimitsRequest>,
+    ) -> Result<Response<proto::node::IoLimits>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        debug!("Patching limits with request: {:?}", request);
+        validate_io_limits(request.io_limits.as_ref()).map_err(Status::invalid_argument)?;
+        let mut machines = self.inner.machines.write().await;
+        let machine = match machines.get_mut(&request.id) {
+            Some(machine) => machine,
+            None => {
+                warn!(
+                    "Requested patching limits of missing machine with id {}",
+                    &request.id
+                );
+                return Err(Status::not_found("Machine not found"));
+            }
+        };
+        let limits = patch_io_limits(machine.io_limits(), request.io_limits.unwrap_or_default());
+        let applied = machine.set_io_limits(limits).await;
+        // Some devices may have been updated before a failure
+        self.inner.persist(machine).await;
+        applied.map_err(|e| {
+            error!("Failed to patch limits: {:?}", e);
+            Status::internal("Failed to patch instance limits")
+        })?;
+        Ok(Response::new(machine.io_limits().as_proto()))
+    }
+
+    async fn set_instance_balloon(
+        &self,
+        request: Request<SetBalloonRequest>,
+    ) -> Result<Response<proto::node::BalloonStats>, Status> {
+        self.validate_auth(request.metadata(), Some(&request.get_ref().id))?;
+        let request = request.into_inner();
+        debug!("Setting balloon with request: {:?}", request);
+        // Held for writing so no other machine takes the memory handed back by deflating
+        let machines = self.inner.machines.write().await;
+        let machine = match machines.get(&request.id) {
+            Some(machine) => machine,
+            None => {
+                warn!(
+                    "Requested setting the balloon of missing machine with id {}",
+                    &request.id
+                );
+                return Err(Status::not_found("Machine not found"));
+            }
+        };
+        if request.target_mb >= machine.memory_mb() {
+            return Err(Status::invalid_argument(
+                "The balloon must be smaller than the memory of the instance",
+            ));
+        }
+
+        refresh_reclaimed_memory(&machines).await;
+        let returned = machine
+            .reclaimed_memory_mb()
+            .saturating_sub(request.target_mb as u64);
+        if returned > 0 {
+            let total = self.inner.total_capacity().map_err(|e| {
+                error!("Failed to get node capacity: {:?}", e);
+                Status::internal("Failed to set balloon")
+            })?;
+            total
+                .admit(
+                    allocated_resources(&machines),
+                    Resources {
+                        memory_mb: returned,
+                        ..Default::default()
+                    },
+                )
+                .map_err(|e| Status::resource_exhausted(e.to_string()))?;
+        }
+
+        let stats = machine.set_balloon(request.target_mb).await.map_err(|e| {
+            error!("Failed to set balloon: {:?}", e);
+            Status::internal("Failed to set balloon")
+        })?;
+        Ok(Response::new(machine::balloon_message(stats)))
+    }
+
+    async fn get_node_capacity(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<NodeCapacity>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let total = self.inner.total_capacity().map_err(|e| {
+            error!("Failed to get node capacity: {:?}", e);
+            Status::internal("Failed to get node capacity")
+        })?;
+        let (volume_bytes, _) = self.inner.volumes.disk_usage().map_err(|e| {
+            error!("Failed to get node capacity: {:?}", e);
+            Status::internal("Failed to get node capacity")
+        })?;
+        let machines = self.inner.machines.read().await;
+        refresh_reclaimed_memory(&machines).await;
+        let allocated = allocated_resources(&machines) + volume_resources(volume_bytes);
+        Ok(Response::new(NodeCapacity {
+            total: Some(resources_message(total)),
+            allocated: Some(resources_message(allocated)),
+            free: Some(resources_message(total.saturating_sub(allocated))),
+            instances: machines.len() as u32,
+        }))
+    }
+
+    type WatchEventsStream = Pin<Box<dyn Stream<Item = Result<InstanceEvent, Status>> + Send>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        self.validate_auth(request.metadata(), None)?;
+        let after_revision = request.into_inner().after_revision;
+        let (events, mut event_rx) = self.inner.events.watch(after_revision).ok_or_else(|| {
+            Status::out_of_range(format!(
+                "Events after revision {} are no longer available",
+                after_revision.unwrap_or_default()
+            ))
+        })?;
+
+        let (tx, rpc_rx) = mpsc::channel(128);
+        tokio::spawn(async move {
+            for event in events {
+                if tx.send(Ok((*event).clone())).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let event = tokio::select! {
+                    _ = tx.closed() => return,
+                    event = event_rx.recv() => event,
+                };
+                let event = match event {
+                    Ok(event) => Ok((*event).clone()),
+                    Err(broadcast::error::RecvError::Lagged(_)) => Err(Status::aborted(
+                        "Watcher fell behind, resume from the last revision received",
+                    )),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let lagged = event.is_err();
+                if tx.send(event).await.is_err() || lagged {
+                    return;
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rpc_rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::WatchEventsStream
+        ))
+    }
+}
+
+pub async fn serve(
+    manager: NodeManager,
+    addr: SocketAddr,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = NodeManagerServiceServer::new(manager);
+    info!("NodeManager server listening on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(server)
+        .serve(addr)
+        .await
+        .expect("Failed to start server");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::isolated;
+
+    fn test_config() -> ManagerConfig {
+        serde_json::from_value(serde_json::json!({
+            "firecracker_config": {
+                "rootfs": "/srv/rootfs.ext4",
+                "kernel_image": "/srv/vmlinux",
+                "jailer_binary": "/srv/missing/jailer",
+                "firecracker_binary": "/srv/missing/firecracker",
+            },
+            "public_network_interface": "eth0",
+            "service_network_interface": "eth0",
+            "snapshot_directory": "/srv/snapshots",
+            "image_cache_directory": "/srv/images",
+            "state_directory": "/srv/state",
+            "volume_directory": "/srv/volumes",
+            "allocatable_vcpus": 4,
+            "allocatable_memory_mb": 4096,
+            "allocatable_disk_gb": 16,
+        }))
+        .unwrap()
+    }
+
+    fn test_manager() -> Arc<InnerNodeManager> {
+        let config = test_config();
+        let state = StateStore::open(&config.state_directory).unwrap();
+        Arc::new(InnerNodeManager {
+            machines: RwLock::new(HashMap::new()),
+            network: Mutex::new(
+                NetworkManager::new(None, config.allocatable_host_ports.clone(), HashMap::new())
+                    .unwrap(),
+            ),
+            images: ImageCache::new(&config.image_cache_directory).unwrap(),
+            state,
+            volumes: VolumeStore::open(&config.volume_directory).unwrap(),
+            events: EventLog::new(0, Box::new(|_| Ok(()))),
+            metrics: Metrics::new(),
+            config,
+            machine_state_subscription: None,
+        })
+    }
+
+    #[test]
+    fn validates_scratch_disk_config() {
+        let config = test_config();
+        assert_eq!(config.default_scratch_disk_gb, 8);
+        assert_eq!(config.max_scratch_disk_gb, 64);
+        assert_eq!(config.disk_overcommit_ratio, 2.0);
+        config.validate().unwrap();
+
+        let mut config = test_config();
+        config.default_scratch_disk_gb = 65;
+        assert!(config.validate().is_err());
+        config.max_scratch_disk_gb = 65;
+        config.validate().unwrap();
+
+        for ratio in [0.0, -1.0, f64::NAN] {
+            let mut config = test_config();
+            config.disk_overcommit_ratio = ratio;
+            assert!(config.validate().is_err());
+        }
+
+        let mut config = test_config();
+        config.scratch_filesystem.mount_options = vec!["noatime,dev".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validates_scratch_disk_sizes() {
+        assert_eq!(validate_scratch_disk(None, 64), Ok(()));
+        assert_eq!(validate_scratch_disk(Some(1), 64), Ok(()));
+        assert_eq!(validate_scratch_disk(Some(64), 64), Ok(()));
+        assert!(validate_scratch_disk(Some(0), 64).is_err());
+        assert_eq!(
+            validate_scratch_disk(Some(65), 64),
+            Err("The scratch disk can be at most 64 GB".to_string())
+        );
+    }
+
+    #[test]
+    fn converts_publish_requests() {
+        let request = |host_port, port_count, host_ip: Option<&str>| PublishServicePortRequest {
+            id: String::new(),
+            guest_port: 53,
+            host_port,
+            protocol: proto::node::Protocol::Udp as i32,
+            port_count,
+            host_ip: host_ip.map(str::to_string),
+        };
+        assert_eq!(
+            published_port(&request(5353, Some(4), Some("fd00::1"))),
+            Ok(PublishedPort {
+                host_port: 5353,
+                guest_port: 53,
+                protocol: Protocol::Udp,
+                port_count: 4,
+                host_ip: Some("fd00::1".parse().unwrap()),
+            })
+        );
+        assert_eq!(
+            published_port(&request(5353, None, None))
+                .unwrap()
+                .port_count,
+            1
+        );
+        for invalid in [
+            request(5353, Some(-1), None),
+            request(5353, Some(0), None),
+            request(65535, Some(2), None),
+            request(-1, None, None),
+            request(70000, None, None),
+            request(5353, None, Some("192.0.2")),
+        ] {
+            assert!(published_port(&invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    fn bucket(size: u64, refill_time_ms: u64) -> proto::node::TokenBucket {
+        proto::node::TokenBucket {
+            size,
+            refill_time_ms,
+            one_time_burst: None,
+        }
+    }
+
+    #[test]
+    fn validates_io_limits() {
+        assert_eq!(validate_io_limits(None), Ok(()));
+        let limits = |limiter: proto::node::RateLimiter| proto::node::IoLimits {
+            network_tx: Some(limiter),
+            ..Default::default()
+        };
+        // An empty limiter removes the limit
+        assert_eq!(
+            validate_io_limits(Some(&limits(Default::default()))),
+            Ok(())
+        );
+        let valid = proto::node::RateLimiter {
+            bandwidth: Some(bucket(1 << 20, 1000)),
+            ops: Some(bucket(100, 10)),
+        };
+        assert_eq!(validate_io_limits(Some(&limits(valid))), Ok(()));
+        for invalid in [
+            proto::node::RateLimiter {
+                bandwidth: Some(bucket(0, 1000)),
+                ops: None,
+            },
+            proto::node::RateLimiter {
+                bandwidth: Some(bucket(1 << 20, 1000)),
+                ops: Some(bucket(100, 0)),
+            },
+        ] {
+            assert!(validate_io_limits(Some(&limits(invalid))).is_err());
+        }
+    }
+
+    #[test]
+    fn converts_rate_limiters() {
+        assert_eq!(rate_limiter(Default::default()), None);
+        let limiter = rate_limiter(proto::node::RateLimiter {
+            bandwidth: Some(proto::node::TokenBucket {
+                one_time_burst: Some(4096),
+                ..bucket(1 << 20, 1000)
+            }),
+            ops: None,
+        });
+        assert_eq!(
+            limiter,
+            Some(machine::RateLimiter {
+                bandwidth: Some(machine::TokenBucket {
+                    size: 1 << 20,
+                    one_time_burst: Some(4096),
+                    refill_time: 1000,
+                }),
+                ops: None,
+            })
+        );
+
+        let current = machine::IoLimits {
+            drive: limiter,
+            network_rx: limiter,
+            network_tx: None,
+        };
+        let patched = patch_io_limits(
+            current,
+            proto::node::IoLimits {
+                drive: Some(Default::default()),
+                network_rx: None,
+                network_tx: Some(proto::node::RateLimiter {
+                    bandwidth: None,
+                    ops: Some(bucket(100, 10)),
+                }),
+            },
+        );
+        assert_eq!(patched.drive, None);
+        assert_eq!(patched.network_rx, limiter);
+        assert_eq!(
+            patched.network_tx.and_then(|limiter| limiter.ops),
+            Some(machine::TokenBucket {
+                size: 100,
+                one_time_burst: None,
+                refill_time: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn restart_backoff_doubles_up_to_the_maximum() {
+        assert_eq!(restart_backoff(0), RESTART_BACKOFF_INITIAL);
+        assert_eq!(restart_backoff(1), RESTART_BACKOFF_INITIAL * 2);
+        assert_eq!(restart_backoff(4), RESTART_BACKOFF_INITIAL * 16);
+        assert_eq!(restart_backoff(9), RESTART_BACKOFF_MAX);
+        assert_eq!(restart_backoff(u32::MAX), RESTART_BACKOFF_MAX);
+        for streak in 0..40 {
+            assert!(restart_backoff(streak) <= restart_backoff(streak + 1));
+        }
+    }
+
+    #[test]
+    fn failed_provision_returns_the_network_slot() {
+        isolated(|| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let manager = test_manager();
+                let request = ProvisionRequest {
+                    container_reference: "localhost:1/test/image:latest".to_string(),
+                    vcpus: 1,
+                    memory_mb: 128,
+                    scratch_disk_gb: Some(1),
+                    ..Default::default()
+                };
+                // There is no jailer to spawn
+                let res = manager._provision(request, None, manager.clone()).await;
+                assert!(res.is_err());
+
+                let mut network = manager.network.lock().await;
+                assert_eq!(network.slots_in_use(), 0);
+                assert_eq!(network.provision_stack().unwrap().slot_id(), 0);
+            });
+        });
+    }
 }
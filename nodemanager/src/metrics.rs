@@ -0,0 +1,215 @@
+//! Prometheus metrics of the nodemanager, served on `/metrics` if a metrics address is configured.
+
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::machine::{BootTimings, MachineExit};
+
+// Scrapers which don't send their request or read the response in time are disconnected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+pub struct Metrics {
+    registry: Registry,
+    provisions: IntCounterVec,
+    provision_duration: Histogram,
+    provision_phase_duration: HistogramVec,
+    deprovisions: IntCounter,
+    deprovision_duration: Histogram,
+    machine_exits: IntCounterVec,
+    pub running_instances: IntGauge,
+    pub network_slots_in_use: IntGauge,
+    pub log_subscribers: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        // From 10ms up to about 80s, image pulls can take a while
+        let buckets = exponential_buckets(0.01, 2.0, 14).unwrap();
+        let metrics = Self {
+            registry: Registry::new_custom(Some("nodemanager".to_string()), None).unwrap(),
+            provisions: IntCounterVec::new(
+                Opts::new("provisions_total", "Provisioned instances by result"),
+                &["result"],
+            )
+            .unwrap(),
+            provision_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "provision_duration_seconds",
+                    "Time to provision an instance",
+                )
+                .buckets(buckets.clone()),
+            )
+            .unwrap(),
+            provision_phase_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "provision_phase_duration_seconds",
+                    "Time spent in the phases of provisioning an instance",
+                )
+                .buckets(buckets.clone()),
+                &["phase"],
+            )
+            .unwrap(),
+            deprovisions: IntCounter::new("deprovisions_total", "Deprovisioned instances").unwrap(),
+            deprovision_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "deprovision_duration_seconds",
+                    "Time to deprovision an instance, including the graceful shutdown",
+                )
+                .buckets(buckets),
+            )
+            .unwrap(),
+            machine_exits: IntCounterVec::new(
+                Opts::new("machine_exits_total", "Machines which stopped, by reason"),
+                &["exit"],
+            )
+            .unwrap(),
+            running_instances: IntGauge::new("running_instances", "Instances on the node").unwrap(),
+            network_slots_in_use: IntGauge::new(
+                "network_slots_in_use",
+                "Network slots with a TAP device",
+            )
+            .unwrap(),
+            log_subscribers: IntGauge::new(
+                "log_subscribers",
+                "Clients streaming the logs of an instance",
+            )
+            .unwrap(),
+        };
+        for collector in [
+            Box::new(metrics.provisions.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.provision_duration.clone()),
+            Box::new(metrics.provision_phase_duration.clone()),
+            Box::new(metrics.deprovisions.clone()),
+            Box::new(metrics.deprovision_duration.clone()),
+            Box::new(metrics.machine_exits.clone()),
+            Box::new(metrics.running_instances.clone()),
+            Box::new(metrics.network_slots_in_use.clone()),
+            Box::new(metrics.log_subscribers.clone()),
+        ] {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn record_provision(&self, succeeded: bool, duration: Duration) {
+        let result = if succeeded { "success" } else { "failure" };
+        self.provisions.with_label_values(&[result]).inc();
+        if succeeded {
+            self.provision_duration.observe(duration.as_secs_f64());
+        }
+    }
+
+    pub fn record_image_pull(&self, duration: Duration) {
+        self.record_phase("image_pull", duration);
+    }
+
+    pub fn record_boot(&self, timings: BootTimings) {
+        self.record_phase("jailer_spawn", timings.jailer_spawn);
+        self.record_phase("firecracker_config", timings.configure);
+        self.record_phase("vsock_accept", timings.vsock_accept);
+    }
+
+    fn record_phase(&self, phase: &str, duration: Duration) {
+        self.provision_phase_duration
+            .with_label_values(&[phase])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_deprovision(&self, duration: Duration) {
+        self.deprovisions.inc();
+        self.deprovision_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn record_exit(&self, exit: MachineExit) {
+        self.machine_exits.with_label_values(&[exit.as_str()]).inc();
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Unable to listen for metrics on {}", addr))?;
+    info!("Metrics listening on http://{}/metrics", addr);
+    Ok(listener)
+}
+
+/// Answers every HTTP request on `/metrics` with the output of `gather`, anything else with a 404.
+/// Answers scrapes until the task is dropped, failing connections only affect themselves.
+pub async fn serve<F, Fut>(listener: TcpListener, gather: F)
+where
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = String> + Send,
+{
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // E.g. out of file descriptors, which may well pass
+                warn!("Unable to accept metrics connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let gather = gather.clone();
+        tokio::spawn(async move {
+            // Scrapes are a single small GET request, the request line is all that matters
+            let mut request = [0; 1024];
+            let read = match tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut request)).await
+            {
+                Ok(Ok(read)) => read,
+                Ok(Err(e)) => {
+                    debug!("Unable to read metrics request from {}: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("Metrics request from {} timed out", peer);
+                    return;
+                }
+            };
+            let request = String::from_utf8_lossy(&request[..read]);
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let (status, body) = match path.split('?').next() {
+                Some("/metrics") => ("200 OK", gather().await),
+                _ => ("404 Not Found", String::new()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            match tokio::time::timeout(REQUEST_TIMEOUT, stream.write_all(response.as_bytes())).await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Unable to answer metrics request from {}: {}", peer, e),
+                Err(_) => debug!("Metrics response to {} timed out", peer),
+            }
+        });
+    }
+}
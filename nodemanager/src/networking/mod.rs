@@ -0,0 +1,1120 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::RangeInclusive,
+};
+
+use anyhow::{Context, Ok, Result};
+use nft::{Batch, Hook, Rule, RuleInfo, CT_STATE_ESTABLISHED, CT_STATE_NEW, CT_STATE_RELATED};
+use serde::{Deserialize, Serialize};
+
+mod netlink;
+pub mod nft;
+mod ports;
+mod private;
+mod rtnl;
+
+pub use ports::PortConflict;
+pub use private::{AllowedPorts, Ipv4Prefix, PrivateAttachment, PrivateNetworkConfig};
+
+pub fn cmd(cmd: &str, args: &[&str]) -> Result<()> {
+    let mut command = std::process::Command::new(cmd);
+    command.args(args);
+    log::trace!("Running command: {} {}", cmd, args.join(" "));
+
+    let out = command.spawn()?.wait_with_output()?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} {} failed with status: {}",
+            cmd,
+            args.join(" "),
+            out.status
+        ));
+    }
+    Ok(())
+}
+
+/// An IPv6 network in CIDR notation, e.g. `fd00:c0::/48`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Ipv6Prefix {
+    pub addr: Ipv6Addr,
+    pub len: u8,
+}
+
+impl TryFrom<String> for Ipv6Prefix {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let (addr, len) = value
+            .split_once('/')
+            .ok_or(anyhow::anyhow!("Missing prefix length in {}", value))?;
+        let addr: Ipv6Addr = addr.parse()?;
+        let len: u8 = len.parse()?;
+        if len > 128 {
+            return Err(anyhow::anyhow!("Invalid prefix length in {}", value));
+        }
+        // Drop any host bits
+        let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+        Ok(Self {
+            addr: Ipv6Addr::from(u128::from(addr) & mask),
+            len,
+        })
+    }
+}
+
+fn default_instance_prefix_len() -> u8 {
+    126
+}
+
+/// Gives the guests IPv6 addresses next to their IPv4 ones, each stack gets its own subnet out of `prefix`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Ipv6Config {
+    pub prefix: Ipv6Prefix,
+    #[serde(default = "default_instance_prefix_len")]
+    pub instance_prefix_len: u8, // E.g. 64 to hand out a /64 per instance, needs a /48 or larger prefix
+}
+
+impl Ipv6Config {
+    pub fn validate(&self) -> Result<()> {
+        // Every slot id has to fit, with room for the gateway and guest addresses
+        if self.instance_prefix_len > 126 || self.prefix.len + 16 > self.instance_prefix_len {
+            return Err(anyhow::anyhow!(
+                "Instance subnets of /{} don't fit {} slots into a /{} prefix",
+                self.instance_prefix_len,
+                u16::MAX as u32 + 1,
+                self.prefix.len
+            ));
+        }
+        Ok(())
+    }
+
+    /// The (gateway, guest) addresses of a slot, the first two of its subnet.
+    fn slot_addrs(&self, id: u16) -> (Ipv6Addr, Ipv6Addr) {
+        let subnet =
+            u128::from(self.prefix.addr) | ((id as u128) << (128 - self.instance_prefix_len));
+        (Ipv6Addr::from(subnet + 1), Ipv6Addr::from(subnet + 2))
+    }
+}
+
+/// The IPv6 side of a dual-stack network stack.
+#[derive(Clone, Copy)]
+pub struct Ipv6Stack {
+    pub addr: Ipv6Addr,
+    pub gateway: Ipv6Addr,
+    pub prefix_len: u8,
+}
+
+pub struct TunTap {
+    name: String,
+    index: u32,
+}
+
+impl TunTap {
+    pub fn new(name: &str) -> Result<Self> {
+        if let Some(index) = rtnl::link_index(name)? {
+            log::warn!("Removing stale TAP device {}", name);
+            rtnl::delete_link(index)?;
+        }
+        rtnl::create_tap(name)?;
+        let tap = Self {
+            name: name.to_string(),
+            index: rtnl::link_index(name)?
+                .ok_or(anyhow::anyhow!("TAP device {} disappeared", name))?,
+        };
+        Ok(tap)
+    }
+
+    /// Takes over an existing TAP device, e.g. one still used by a machine after a restart.
+    fn existing(name: &str) -> Result<Self> {
+        let index =
+            rtnl::link_index(name)?.ok_or(anyhow::anyhow!("TAP device {} does not exist", name))?;
+        Ok(Self {
+            name: name.to_string(),
+            index,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn add_address(&self, addr: &IpAddr, prefix_len: u8) -> Result<()> {
+        rtnl::add_address(self.index, addr, prefix_len)
+            .with_context(|| format!("Unable to configure {}", self.name))
+    }
+
+    pub fn up(&self) -> Result<()> {
+        rtnl::set_up(self.index).with_context(|| format!("Unable to bring up {}", self.name))
+    }
+}
+
+impl Drop for TunTap {
+    fn drop(&mut self) {
+        if let Err(e) = rtnl::delete_link(self.index) {
+            log::error!("Failed to delete TAP device {}: {:?}", self.name, e);
+        }
+    }
+}
+
+/// The chains of one network stack in the `containerinfra` table. Dropping them removes all of their rules at once.
+struct InstanceChains {
+    prefix: String,
+    rule_count: usize,
+}
+
+impl InstanceChains {
+    const HOOKS: [(Hook, &'static str); 3] = [
+        (Hook::Prerouting, "prerouting"),
+        (Hook::Forward, "forward"),
+        (Hook::Postrouting, "postrouting"),
+    ];
+
+    fn new(prefix: &str) -> Result<Self> {
+        let chains = Self {
+            prefix: prefix.to_string(),
+            rule_count: 0,
+        };
+        // Recreate the chains in case they were left behind by a previous stack
+        let mut batch = Batch::new();
+        for (hook, _) in Self::HOOKS {
+            let name = chains.chain(hook);
+            batch.add_chain(&name, hook);
+            batch.del_chain(&name);
+            batch.add_chain(&name, hook);
+        }
+        batch.send()?;
+        Ok(chains)
+    }
+
+    /// Removes the chains of a stack that no longer exists, if they are present.
+    fn remove(prefix: &str) -> Result<()> {
+        let chains = Self {
+            prefix: prefix.to_string(),
+            rule_count: 0,
+        };
+        let mut batch = Batch::new();
+        for (hook, _) in Self::HOOKS {
+            let name = chains.chain(hook);
+            batch.add_chain(&name, hook);
+            batch.del_chain(&name);
+        }
+        // Dropping would delete the chains again
+        std::mem::forget(chains);
+        batch.send()
+    }
+
+    /// Whether any of the chains of a stack are among `chains`, i.e. it was set up by the nodemanager.
+    fn present(prefix: &str, chains: &[String]) -> bool {
+        Self::HOOKS
+            .iter()
+            .any(|(_, suffix)| chains.contains(&format!("{}_{}", prefix, suffix)))
+    }
+
+    fn chain(&self, hook: Hook) -> String {
+        let (_, suffix) = Self::HOOKS
+            .iter()
+            .find(|(h, _)| *h == hook)
+            .expect("Every hook has a chain");
+        format!("{}_{}", self.prefix, suffix)
+    }
+
+    fn add_rules(&mut self, rules: &[(Hook, Rule)]) -> Result<()> {
+        let mut batch = Batch::new();
+        for (hook, rule) in rules {
+            batch.add_rule(&self.chain(*hook), rule);
+        }
+        batch.send()?;
+        self.rule_count += rules.len();
+        Ok(())
+    }
+
+    /// Replaces all rules of the chains in one go.
+    fn replace_rules(&mut self, rules: &[(Hook, Rule)]) -> Result<()> {
+        let mut batch = Batch::new();
+        for (hook, _) in Self::HOOKS {
+            batch.flush_chain(&self.chain(hook));
+        }
+        for (hook, rule) in rules {
+            batch.add_rule(&self.chain(*hook), rule);
+        }
+        batch.send()?;
+        self.rule_count = rules.len();
+        Ok(())
+    }
+
+    fn rules(&self) -> Result<Vec<RuleInfo>> {
+        let mut rules = Vec::new();
+        for (hook, _) in Self::HOOKS {
+            rules.extend(nft::list_rules(&self.chain(hook))?);
+        }
+        Ok(rules)
+    }
+}
+
+impl Drop for InstanceChains {
+    fn drop(&mut self) {
+        let mut batch = Batch::new();
+        for (hook, _) in Self::HOOKS {
+            batch.del_chain(&self.chain(hook));
+        }
+        if let Err(e) = batch.send() {
+            log::warn!("Failed to delete chains of {}: {:?}", self.prefix, e);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl Protocol {
+    fn number(&self) -> u8 {
+        match self {
+            Protocol::Tcp => libc::IPPROTO_TCP as u8,
+            Protocol::Udp => libc::IPPROTO_UDP as u8,
+            Protocol::Sctp => libc::IPPROTO_SCTP as u8,
+        }
+    }
+
+    pub fn as_proto(&self) -> proto::node::Protocol {
+        match self {
+            Protocol::Tcp => proto::node::Protocol::Tcp,
+            Protocol::Udp => proto::node::Protocol::Udp,
+            Protocol::Sctp => proto::node::Protocol::Sctp,
+        }
+    }
+}
+
+fn default_port_count() -> u16 {
+    1
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedPort {
+    pub host_port: u16,
+    pub guest_port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default = "default_port_count")]
+    pub port_count: u16, // Consecutive ports from host_port and guest_port on
+    #[serde(default)]
+    pub host_ip: Option<IpAddr>, // Any address of the host if unset
+}
+
+impl PublishedPort {
+    // Ranges mapped to other ports need a DNAT rule per port
+    pub const MAX_PORT_COUNT: u16 = 256;
+
+    pub fn validate(&self) -> Result<()> {
+        if self.port_count == 0 || self.port_count > Self::MAX_PORT_COUNT {
+            return Err(anyhow::anyhow!(
+                "Port ranges have to span 1 to {} ports",
+                Self::MAX_PORT_COUNT
+            ));
+        }
+        // Host ports are allocated by the node if the first one is 0
+        if self.guest_port == 0 {
+            return Err(anyhow::anyhow!("Invalid guest port 0"));
+        }
+        for first in [self.host_port, self.guest_port] {
+            if first.checked_add(self.port_count - 1).is_none() {
+                return Err(anyhow::anyhow!(
+                    "Invalid port range {} + {}",
+                    first,
+                    self.port_count
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn host_ports(&self) -> RangeInclusive<u16> {
+        self.host_port..=self.host_port + (self.port_count - 1)
+    }
+
+    pub fn guest_ports(&self) -> RangeInclusive<u16> {
+        self.guest_port..=self.guest_port + (self.port_count - 1)
+    }
+
+    pub fn as_proto(&self) -> proto::node::PublishedPort {
+        proto::node::PublishedPort {
+            host_port: self.host_port as i32,
+            guest_port: self.guest_port as i32,
+            protocol: self.protocol.as_proto() as i32,
+            port_count: self.port_count as i32,
+            host_ip: self.host_ip.map(|ip| ip.to_string()),
+        }
+    }
+}
+
+pub struct NetworkStack {
+    slot_id: u16,
+    ipv4_addr: Ipv4Addr,
+    gateway: Ipv4Addr,
+    ipv6: Option<Ipv6Stack>,
+    nic: TunTap,
+    chains: InstanceChains,
+    public_nat: Option<String>,                 // The outbound interface
+    publications: Vec<(String, PublishedPort)>, // With their inbound interface
+    attachments: Vec<PrivateAttachment>,
+}
+
+impl NetworkStack {
+    fn new(slot: NetworkStackSlot) -> Result<Self> {
+        let tap = TunTap::new(&slot.tap_dev_name)?;
+        tap.add_address(&slot.gateway.into(), 30)?;
+        if let Some(ipv6) = &slot.ipv6 {
+            tap.add_address(&ipv6.gateway.into(), ipv6.prefix_len)?;
+        }
+        tap.up()?;
+        let chains = InstanceChains::new(&slot.tap_dev_name)?;
+
+        Ok(Self {
+            slot_id: slot.id,
+            ipv4_addr: slot.ipv4_addr,
+            gateway: slot.gateway,
+            ipv6: slot.ipv6,
+            nic: tap,
+            chains,
+            public_nat: None,
+            publications: Vec::new(),
+            attachments: Vec::new(),
+        })
+    }
+
+    /// Rebuilds the stack around the TAP device of a slot which is still in use. The rules have to be set up again.
+    fn adopt(slot: NetworkStackSlot) -> Result<Self> {
+        let tap = TunTap::existing(&slot.tap_dev_name)?;
+        let chains = InstanceChains::new(&slot.tap_dev_name)?;
+
+        Ok(Self {
+            slot_id: slot.id,
+            ipv4_addr: slot.ipv4_addr,
+            gateway: slot.gateway,
+            ipv6: slot.ipv6,
+            nic: tap,
+            chains,
+            public_nat: None,
+            publications: Vec::new(),
+            attachments: Vec::new(),
+        })
+    }
+
+    pub fn setup_public_nat(&mut self, outbound_if_name: &str) -> Result<()> {
+        let rules = self.public_nat_rules(outbound_if_name);
+        self.chains.add_rules(&rules)?;
+        self.public_nat = Some(outbound_if_name.to_string());
+        Ok(())
+    }
+
+    fn public_nat_rules(&self, outbound_if_name: &str) -> Vec<(Hook, Rule)> {
+        let nic_name = self.nic.name().to_owned();
+        let mut rules = vec![
+            (
+                Hook::Postrouting,
+                Rule::new()
+                    .oifname(outbound_if_name)
+                    .ip_saddr(&self.ipv4_addr)
+                    .masquerade(),
+            ),
+            (
+                Hook::Forward,
+                Rule::new()
+                    .iifname(&nic_name)
+                    .oifname(outbound_if_name)
+                    .accept(),
+            ),
+        ];
+        if let Some(ipv6) = &self.ipv6 {
+            rules.push((
+                Hook::Postrouting,
+                Rule::new()
+                    .oifname(outbound_if_name)
+                    .ip6_saddr(&ipv6.addr)
+                    .masquerade(),
+            ));
+        }
+        rules
+    }
+
+    fn attach(&mut self, attachment: PrivateAttachment) -> Result<()> {
+        self.chains.add_rules(&attachment.rules())?;
+        self.attachments.push(attachment);
+        Ok(())
+    }
+
+    fn setup_forwarding(&mut self, inbound_if_name: &str, port: PublishedPort) -> Result<()> {
+        port.validate()?;
+        let rules = self.forwarding_rules(inbound_if_name, &port)?;
+        self.chains.add_rules(&rules)?;
+        self.publications.push((inbound_if_name.to_string(), port));
+        Ok(())
+    }
+
+    /// Stops forwarding the ports published from `host_port`, returns them if there were any.
+    fn remove_forwarding(
+        &mut self,
+        protocol: Protocol,
+        host_port: u16,
+        host_ip: Option<IpAddr>,
+    ) -> Result<Option<PublishedPort>> {
+        let Some(i) = self.publications.iter().position(|(_, port)| {
+            port.protocol == protocol && port.host_port == host_port && port.host_ip == host_ip
+        }) else {
+            return Ok(None);
+        };
+        let (inbound_if_name, port) = self.publications.remove(i);
+        // The rules of a publication aren't known by handle, so all of them are installed again without it
+        if let Err(e) = self.rebuild_rules() {
+            self.publications.insert(i, (inbound_if_name, port));
+            return Err(e);
+        }
+        Ok(Some(port))
+    }
+
+    fn rebuild_rules(&mut self) -> Result<()> {
+        let mut rules = match &self.public_nat {
+            Some(outbound_if_name) => self.public_nat_rules(outbound_if_name),
+            None => Vec::new(),
+        };
+        for attachment in &self.attachments {
+            rules.extend(attachment.rules());
+        }
+        for (inbound_if_name, port) in &self.publications {
+            rules.extend(self.forwarding_rules(inbound_if_name, port)?);
+        }
+        self.chains.replace_rules(&rules)
+    }
+
+    fn forwarding_rules(
+        &self,
+        inbound_if_name: &str,
+        port: &PublishedPort,
+    ) -> Result<Vec<(Hook, Rule)>> {
+        forwarding_rules(
+            self.nic.name(),
+            self.ipv4_addr,
+            self.ipv6.map(|ipv6| ipv6.addr),
+            inbound_if_name,
+            port,
+        )
+    }
+
+    pub fn published_ports(&self) -> Vec<PublishedPort> {
+        self.publications
+            .iter()
+            .map(|(_, port)| port.clone())
+            .collect()
+    }
+
+    /// Lists the firewall rules currently installed for this stack.
+    pub fn rules(&self) -> Result<Vec<RuleInfo>> {
+        self.chains.rules()
+    }
+
+    /// Checks that all rules added to this stack are still installed.
+    pub fn verify_rules(&self) -> Result<()> {
+        let found = self.rules()?.len();
+        if found != self.chains.rule_count {
+            return Err(anyhow::anyhow!(
+                "Expected {} rules for {} but found {}",
+                self.chains.rule_count,
+                self.nic.name(),
+                found
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn ipv4_addr(&self) -> &Ipv4Addr {
+        &self.ipv4_addr
+    }
+
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    /// Only set if the node has an IPv6 prefix configured.
+    pub fn ipv6(&self) -> Option<&Ipv6Stack> {
+        self.ipv6.as_ref()
+    }
+
+    pub fn subnet_mask(&self) -> &str {
+        "255.255.255.252"
+    }
+
+    pub fn nic(&self) -> &TunTap {
+        &self.nic
+    }
+
+    /// The private networks of the stack, in the order their interfaces are attached to the machine.
+    pub fn private_networks(&self) -> &[PrivateAttachment] {
+        &self.attachments
+    }
+
+    /// Identifies the TAP device and addresses of this stack, see `NetworkManager::provision_stack_in_slot`.
+    pub fn slot_id(&self) -> u16 {
+        self.slot_id
+    }
+
+    fn reclaim(self) -> NetworkStackSlot {
+        NetworkStackSlot {
+            id: self.slot_id,
+            ipv4_addr: self.ipv4_addr,
+            gateway: self.gateway,
+            ipv6: self.ipv6,
+            tap_dev_name: self.nic.name().to_string(),
+        }
+    }
+}
+
+struct NetworkStackSlot {
+    id: u16,
+    ipv4_addr: Ipv4Addr,
+    gateway: Ipv4Addr,
+    ipv6: Option<Ipv6Stack>,
+    tap_dev_name: String,
+}
+
+impl NetworkStackSlot {
+    fn new(id: u16, ipv6: Option<&Ipv6Config>) -> Self {
+        let tap_dev_name = format!("tap{}", id);
+
+        let ip_id = id * 4 + 1;
+
+        let first_half = (ip_id >> 8) as u8;
+        let second_half = (ip_id & 0xFF) as u8;
+        let gateway = Ipv4Addr::new(172, 16, first_half, second_half);
+        let ipv4_addr = Ipv4Addr::new(172, 16, first_half, second_half + 1);
+        let ipv6 = ipv6.map(|config| {
+            let (gateway, addr) = config.slot_addrs(id);
+            Ipv6Stack {
+                addr,
+                gateway,
+                prefix_len: config.instance_prefix_len,
+            }
+        });
+        NetworkStackSlot {
+            id,
+            ipv4_addr,
+            gateway,
+            ipv6,
+            tap_dev_name,
+        }
+    }
+}
+
+/// The rules forwarding a published port from `inbound_if_name` to the guest behind `nic_name`, on the guest
+/// address of the same version as the host address it is bound to or on both if it is bound to any.
+fn forwarding_rules(
+    nic_name: &str,
+    ipv4_addr: Ipv4Addr,
+    ipv6_addr: Option<Ipv6Addr>,
+    inbound_if_name: &str,
+    port: &PublishedPort,
+) -> Result<Vec<(Hook, Rule)>> {
+    let protocol = port.protocol.number();
+    let guest_addrs: Vec<IpAddr> = match port.host_ip {
+        None => std::iter::once(ipv4_addr.into())
+            .chain(ipv6_addr.map(IpAddr::from))
+            .collect(),
+        Some(IpAddr::V4(_)) => vec![ipv4_addr.into()],
+        Some(IpAddr::V6(_)) => match ipv6_addr {
+            Some(ipv6_addr) => vec![ipv6_addr.into()],
+            None => return Err(anyhow::anyhow!("{} has no IPv6 address", nic_name)),
+        },
+    };
+
+    let mut rules = Vec::new();
+    // DNAT outbound[host port] -> inbound[guest port]
+    for guest_addr in guest_addrs {
+        let inbound = |host_ports: RangeInclusive<u16>| {
+            let rule = Rule::new().iifname(inbound_if_name);
+            let rule = match &port.host_ip {
+                Some(host_ip) => rule.ip_daddr(host_ip),
+                None => rule,
+            };
+            rule.dport(protocol, host_ports)
+        };
+        if port.host_port == port.guest_port {
+            // The ports stay the same, so one rule covers the whole range
+            rules.push((
+                Hook::Prerouting,
+                inbound(port.host_ports()).dnat(&guest_addr, None),
+            ));
+        } else {
+            // DNAT can't shift a range of ports, every port is mapped on its own
+            for (host_port, guest_port) in port.host_ports().zip(port.guest_ports()) {
+                rules.push((
+                    Hook::Prerouting,
+                    inbound(host_port..=host_port).dnat(&guest_addr, Some(guest_port)),
+                ));
+            }
+        }
+    }
+    // The forward rules don't look at addresses and cover both versions
+    rules.extend([
+        (
+            Hook::Forward,
+            Rule::new()
+                .iifname(nic_name)
+                .oifname(inbound_if_name)
+                .sport(protocol, port.guest_ports())
+                .ct_state(CT_STATE_ESTABLISHED | CT_STATE_RELATED)
+                .accept(),
+        ),
+        (
+            Hook::Forward,
+            Rule::new()
+                .iifname(inbound_if_name)
+                .oifname(nic_name)
+                .dport(protocol, port.guest_ports())
+                .ct_state(CT_STATE_NEW | CT_STATE_ESTABLISHED | CT_STATE_RELATED)
+                .accept(),
+        ),
+    ]);
+    Ok(rules)
+}
+
+/// The slot of a `tapN` or `tapNpK` device name as the nodemanager creates them.
+fn tap_slot(name: &str) -> Option<u16> {
+    let rest = name.strip_prefix("tap")?;
+    let (id, network) = match rest.split_once('p') {
+        Some((id, network)) => (id, Some(network)),
+        None => (rest, None),
+    };
+    let canonical = |s: &str| s.parse::<u64>().is_ok_and(|n| n.to_string() == s);
+    if !canonical(id) || !network.is_none_or(canonical) {
+        return None;
+    }
+    id.parse().ok()
+}
+
+pub struct NetworkManager {
+    recovered_slots: Vec<NetworkStackSlot>,
+    next_id: u16,
+    ipv6: Option<Ipv6Config>,
+    host_ports: ports::HostPorts,
+    private_networks: HashMap<String, PrivateNetworkConfig>,
+}
+
+impl NetworkManager {
+    pub const FORWARD_CHAIN: &'static str = "forward";
+
+    pub fn new(
+        ipv6: Option<Ipv6Config>,
+        allocatable_host_ports: RangeInclusive<u16>,
+        private_networks: HashMap<String, PrivateNetworkConfig>,
+    ) -> Result<Self> {
+        if let Some(ipv6) = &ipv6 {
+            ipv6.validate()?;
+        }
+        PrivateNetworkConfig::validate_all(&private_networks)?;
+        if allocatable_host_ports.is_empty() || *allocatable_host_ports.start() == 0 {
+            return Err(anyhow::anyhow!(
+                "Invalid range of allocatable host ports {:?}",
+                allocatable_host_ports
+            ));
+        }
+        let mut batch = Batch::new();
+        batch.add_table();
+        batch.add_chain(Self::FORWARD_CHAIN, Hook::Forward);
+        batch.del_chain(Self::FORWARD_CHAIN);
+        batch.add_chain(Self::FORWARD_CHAIN, Hook::Forward);
+        batch.add_rule(
+            Self::FORWARD_CHAIN,
+            &Rule::new()
+                .ct_state(CT_STATE_ESTABLISHED | CT_STATE_RELATED)
+                .accept(),
+        );
+        // Rules of stacks left behind may still use the sets, the members are added again when stacks are adopted
+        for name in private_networks.keys() {
+            let members = PrivateNetworkConfig::members_set(name);
+            batch.add_set(&members);
+            batch.flush_set(&members);
+        }
+        batch.send()?;
+
+        Ok(Self {
+            recovered_slots: Vec::new(),
+            next_id: 0,
+            ipv6,
+            host_ports: ports::HostPorts::new(allocatable_host_ports),
+            private_networks,
+        })
+    }
+
+    /// Removes `tapN` and `tapNpK` devices and their chains which don't belong to a provisioned stack, e.g. left over after a crash.
+    /// A device is only removed if the chains of stack N are still in the `containerinfra` table, TAP devices named
+    /// like this by anything else on the host are left alone.
+    pub fn remove_orphaned_taps(&self) -> Result<Vec<String>> {
+        let chains = nft::list_chains()?;
+        let mut removed = Vec::new();
+        for link in rtnl::list_links()? {
+            if link.kind.as_deref() != Some("tun") {
+                continue;
+            }
+            let Some(id) = tap_slot(&link.name) else {
+                continue;
+            };
+            if self.slot_in_use(id) || !InstanceChains::present(&format!("tap{}", id), &chains) {
+                continue;
+            }
+            rtnl::delete_link(link.index)
+                .with_context(|| format!("Unable to delete TAP device {}", link.name))?;
+            InstanceChains::remove(&link.name)?;
+            removed.push(link.name);
+        }
+        Ok(removed)
+    }
+
+    pub fn slot_in_use(&self, id: u16) -> bool {
+        id < self.next_id && !self.recovered_slots.iter().any(|slot| slot.id == id)
+    }
+
+    fn next_slot(&mut self) -> NetworkStackSlot {
+        if let Some(slot) = self.recovered_slots.pop() {
+            return slot;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        NetworkStackSlot::new(id, self.ipv6.as_ref())
+    }
+
+    /// Claims a specific slot, if it is free.
+    fn claim_slot(&mut self, id: u16) -> Result<NetworkStackSlot> {
+        if id >= self.next_id {
+            // Keep the skipped slots available
+            for skipped in self.next_id..id {
+                self.recovered_slots
+                    .push(NetworkStackSlot::new(skipped, self.ipv6.as_ref()));
+            }
+            self.next_id = id + 1;
+            return Ok(NetworkStackSlot::new(id, self.ipv6.as_ref()));
+        }
+        match self.recovered_slots.iter().position(|slot| slot.id == id) {
+            Some(i) => Ok(self.recovered_slots.swap_remove(i)),
+            None => Err(anyhow::anyhow!("Network slot {} is in use", id)),
+        }
+    }
+
+    pub fn provision_stack(&mut self) -> Result<NetworkStack> {
+        let slot = self.next_slot();
+        let stack = NetworkStack::new(slot)?;
+        Ok(stack)
+    }
+
+    /// Provisions the stack with the same TAP device and addresses as a previous one, e.g. for a restored snapshot.
+    pub fn provision_stack_in_slot(&mut self, id: u16) -> Result<NetworkStack> {
+        let slot = self.claim_slot(id)?;
+        let stack = NetworkStack::new(slot)?;
+        Ok(stack)
+    }
+
+    /// Takes over the stack of a slot whose TAP device survived a restart of the nodemanager.
+    pub fn adopt_stack_in_slot(&mut self, id: u16) -> Result<NetworkStack> {
+        let slot = self.claim_slot(id)?;
+        NetworkStack::adopt(slot).inspect_err(|_| self.reclaim_slot(id))
+    }
+
+    /// Attaches a new stack to the private networks, each one gets its own TAP device.
+    pub fn attach_private_networks(
+        &self,
+        stack: &mut NetworkStack,
+        names: &[String],
+    ) -> Result<()> {
+        self.attach_networks(stack, names, false)
+    }
+
+    /// Takes over the private network TAP devices of an adopted stack.
+    pub fn adopt_private_networks(&self, stack: &mut NetworkStack, names: &[String]) -> Result<()> {
+        self.attach_networks(stack, names, true)
+    }
+
+    fn attach_networks(
+        &self,
+        stack: &mut NetworkStack,
+        names: &[String],
+        adopt: bool,
+    ) -> Result<()> {
+        for name in names {
+            let config = self
+                .private_networks
+                .get(name)
+                .ok_or(anyhow::anyhow!("Unknown private network {}", name))?;
+            let attachment = PrivateAttachment::new(
+                stack.slot_id,
+                stack.attachments.len(),
+                name,
+                config,
+                adopt,
+            )?;
+            stack.attach(attachment)?;
+        }
+        Ok(())
+    }
+
+    pub fn slots_in_use(&self) -> usize {
+        self.next_id as usize - self.recovered_slots.len()
+    }
+
+    /// Forwards ports of the host to a stack, unless another stack already published any of them.
+    /// Free host ports are picked if `host_port` is 0, the publication is returned with them.
+    pub fn publish_port(
+        &mut self,
+        stack: &mut NetworkStack,
+        inbound_if_name: &str,
+        mut port: PublishedPort,
+    ) -> Result<PublishedPort> {
+        if port.host_port == 0 {
+            port.host_port = self.host_ports.allocate(&port)?;
+        }
+        self.host_ports.claim(stack.slot_id, &port)?;
+        if let Err(e) = stack.setup_forwarding(inbound_if_name, port.clone()) {
+            self.host_ports.release(stack.slot_id, &port);
+            return Err(e);
+        }
+        Ok(port)
+    }
+
+    /// Removes the publication of a stack starting at `host_port`, returns it if there was one.
+    pub fn unpublish_port(
+        &mut self,
+        stack: &mut NetworkStack,
+        protocol: Protocol,
+        host_port: u16,
+        host_ip: Option<IpAddr>,
+    ) -> Result<Option<PublishedPort>> {
+        let port = stack.remove_forwarding(protocol, host_port, host_ip)?;
+        if let Some(port) = &port {
+            self.host_ports.release(stack.slot_id, port);
+        }
+        Ok(port)
+    }
+
+    pub fn reclaim(&mut self, stack: NetworkStack) {
+        self.host_ports.release_slot(stack.slot_id);
+        self.recovered_slots.push(stack.reclaim());
+    }
+
+    /// Returns a slot whose stack was lost, e.g. dropped by a failed provision.
+    pub fn reclaim_slot(&mut self, id: u16) {
+        self.host_ports.release_slot(id);
+        self.recovered_slots
+            .push(NetworkStackSlot::new(id, self.ipv6.as_ref()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(prefix: &str, instance_prefix_len: u8) -> Ipv6Config {
+        Ipv6Config {
+            prefix: Ipv6Prefix::try_from(prefix.to_string()).unwrap(),
+            instance_prefix_len,
+        }
+    }
+
+    fn published(
+        host_port: u16,
+        guest_port: u16,
+        port_count: u16,
+        protocol: Protocol,
+        host_ip: Option<&str>,
+    ) -> PublishedPort {
+        PublishedPort {
+            host_port,
+            guest_port,
+            protocol,
+            port_count,
+            host_ip: host_ip.map(|ip| ip.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn validates_published_ports() {
+        for valid in [
+            published(8080, 80, 1, Protocol::Tcp, None),
+            published(0, 80, 1, Protocol::Udp, None), // Allocated by the node
+            published(65535, 65535, 1, Protocol::Sctp, None),
+            published(65280, 1000, 256, Protocol::Udp, Some("::1")),
+        ] {
+            valid.validate().unwrap();
+        }
+        for invalid in [
+            published(8080, 80, 0, Protocol::Tcp, None),
+            published(8080, 80, 257, Protocol::Tcp, None),
+            published(8080, 0, 1, Protocol::Tcp, None),
+            published(65535, 80, 2, Protocol::Udp, None),
+            published(8080, 65500, 100, Protocol::Sctp, None),
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn forwards_published_ports() {
+        let ipv4: Ipv4Addr = "10.0.0.2".parse().unwrap();
+        let ipv6: Ipv6Addr = "fd00::2".parse().unwrap();
+        let forward = |protocol, ports: RangeInclusive<u16>| {
+            vec![
+                (
+                    Hook::Forward,
+                    Rule::new()
+                        .iifname("tap0")
+                        .oifname("eth0")
+                        .sport(protocol, ports.clone())
+                        .ct_state(CT_STATE_ESTABLISHED | CT_STATE_RELATED)
+                        .accept(),
+                ),
+                (
+                    Hook::Forward,
+                    Rule::new()
+                        .iifname("eth0")
+                        .oifname("tap0")
+                        .dport(protocol, ports)
+                        .ct_state(CT_STATE_NEW | CT_STATE_ESTABLISHED | CT_STATE_RELATED)
+                        .accept(),
+                ),
+            ]
+        };
+        let dnat =
+            |host_ip: Option<IpAddr>, protocol, host_ports, guest_addr: IpAddr, guest_port| {
+                let rule = Rule::new().iifname("eth0");
+                let rule = match &host_ip {
+                    Some(host_ip) => rule.ip_daddr(host_ip),
+                    None => rule,
+                };
+                (
+                    Hook::Prerouting,
+                    rule.dport(protocol, host_ports)
+                        .dnat(&guest_addr, guest_port),
+                )
+            };
+        let rules = |port: &PublishedPort, ipv6_addr| {
+            forwarding_rules("tap0", ipv4, ipv6_addr, "eth0", port).unwrap()
+        };
+
+        // UDP on any address goes to both addresses of the guest
+        let port = published(5353, 53, 1, Protocol::Udp, None);
+        let mut expected = vec![
+            dnat(None, 17, 5353..=5353, ipv4.into(), Some(53)),
+            dnat(None, 17, 5353..=5353, ipv6.into(), Some(53)),
+        ];
+        expected.extend(forward(17, 53..=53));
+        assert_eq!(rules(&port, Some(ipv6)), expected);
+
+        // A range on the same ports needs a single rule, SCTP is matched by its own number
+        let port = published(9000, 9000, 10, Protocol::Sctp, None);
+        let mut expected = vec![dnat(None, 132, 9000..=9009, ipv4.into(), None)];
+        expected.extend(forward(132, 9000..=9009));
+        assert_eq!(rules(&port, None), expected);
+
+        // A range moved to other ports needs one per port
+        let port = published(27015, 7000, 3, Protocol::Udp, None);
+        let mut expected: Vec<_> = (0..3)
+            .map(|i| dnat(None, 17, 27015 + i..=27015 + i, ipv4.into(), Some(7000 + i)))
+            .collect();
+        expected.extend(forward(17, 7000..=7002));
+        assert_eq!(rules(&port, None), expected);
+
+        // A bound port only forwards the traffic to its address, to the guest address of the same version
+        let host_ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let port = published(8080, 80, 1, Protocol::Tcp, Some("192.0.2.1"));
+        let mut expected = vec![dnat(Some(host_ip), 6, 8080..=8080, ipv4.into(), Some(80))];
+        expected.extend(forward(6, 80..=80));
+        assert_eq!(rules(&port, Some(ipv6)), expected);
+
+        let host_ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let port = published(8080, 80, 1, Protocol::Tcp, Some("2001:db8::1"));
+        let mut expected = vec![dnat(Some(host_ip), 6, 8080..=8080, ipv6.into(), Some(80))];
+        expected.extend(forward(6, 80..=80));
+        assert_eq!(rules(&port, Some(ipv6)), expected);
+        assert!(forwarding_rules("tap0", ipv4, None, "eth0", &port).is_err());
+    }
+
+    #[test]
+    fn parses_tap_names() {
+        assert_eq!(tap_slot("tap0"), Some(0));
+        assert_eq!(tap_slot("tap12"), Some(12));
+        assert_eq!(tap_slot("tap12p3"), Some(12));
+        for name in [
+            "tap", "tapp1", "tap03", "tap1p", "tap1p03", "tap1p2p3", "tap-1", "tap70000", "eth0",
+        ] {
+            assert_eq!(tap_slot(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn removes_only_orphaned_taps_it_owns() {
+        crate::testing::isolated(|| {
+            // A stack left behind by a previous nodemanager, with a private network attached
+            let mut previous = NetworkManager::new(None, 20000..=20100, HashMap::new()).unwrap();
+            std::mem::forget(previous.provision_stack().unwrap());
+            rtnl::create_tap("tap0p0").unwrap();
+            // Devices of someone else which happen to be named alike
+            rtnl::create_tap("tap1").unwrap();
+            rtnl::create_tap("tap5p0").unwrap();
+
+            let manager = NetworkManager::new(None, 20000..=20100, HashMap::new()).unwrap();
+            let mut removed = manager.remove_orphaned_taps().unwrap();
+            removed.sort();
+            assert_eq!(removed, ["tap0", "tap0p0"]);
+            assert!(nft::list_chains()
+                .unwrap()
+                .iter()
+                .all(|c| !c.starts_with("tap0_")));
+            for name in ["tap1", "tap5p0"] {
+                assert!(rtnl::link_index(name).unwrap().is_some(), "{}", name);
+            }
+        });
+    }
+
+    #[test]
+    fn carves_slot_subnets() {
+        let ipv6 = config("fd00:c0::/64", 126);
+        assert_eq!(
+            ipv6.slot_addrs(0),
+            ("fd00:c0::1".parse().unwrap(), "fd00:c0::2".parse().unwrap())
+        );
+        assert_eq!(
+            ipv6.slot_addrs(3),
+            ("fd00:c0::d".parse().unwrap(), "fd00:c0::e".parse().unwrap())
+        );
+
+        let ipv6 = config("fd00:c0::/48", 64);
+        assert_eq!(
+            ipv6.slot_addrs(0x102),
+            (
+                "fd00:c0:0:102::1".parse().unwrap(),
+                "fd00:c0:0:102::2".parse().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn validates_prefixes() {
+        assert_eq!(
+            Ipv6Prefix::try_from("fd00:c0::1/64".to_string()).unwrap(),
+            Ipv6Prefix {
+                addr: "fd00:c0::".parse().unwrap(),
+                len: 64
+            }
+        );
+        assert!(Ipv6Prefix::try_from("fd00:c0::".to_string()).is_err());
+        assert!(Ipv6Prefix::try_from("fd00:c0::/129".to_string()).is_err());
+
+        assert!(config("fd00:c0::/110", 126).validate().is_ok());
+        assert!(config("fd00:c0::/112", 126).validate().is_err());
+        assert!(config("fd00:c0::/64", 64).validate().is_err());
+        assert!(config("fd00:c0::/48", 127).validate().is_err());
+    }
+}
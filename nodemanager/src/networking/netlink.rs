@@ -0,0 +1,253 @@
+//! Netlink message encoding and sockets shared by the nftables and rtnetlink clients.
+
+use std::{
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+// linux/netlink.h
+pub const NLM_F_REQUEST: u16 = 0x1;
+pub const NLM_F_ACK: u16 = 0x4;
+pub const NLM_F_DUMP: u16 = 0x300;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+pub const NLM_F_APPEND: u16 = 0x800;
+pub const NLMSG_ERROR: u16 = 0x2;
+pub const NLMSG_DONE: u16 = 0x3;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(1 << 15 | 1 << 14);
+const NLMSG_HDRLEN: usize = 16;
+
+/// A netlink message being built, attributes are appended in order.
+pub struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    /// Starts a message with the fixed header of its protocol family, e.g. `nfgenmsg` or `ifinfomsg`.
+    pub fn new(msg_type: u16, flags: u16, family_header: &[u8]) -> Self {
+        let mut buf = vec![0; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        buf.extend(family_header);
+        let mut msg = Self { buf };
+        msg.pad();
+        msg
+    }
+
+    pub fn attr(&mut self, attr_type: u16, data: &[u8]) {
+        self.buf.extend(((4 + data.len()) as u16).to_ne_bytes());
+        self.buf.extend(attr_type.to_ne_bytes());
+        self.buf.extend(data);
+        self.pad();
+    }
+
+    pub fn attr_str(&mut self, attr_type: u16, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(attr_type, &data);
+    }
+
+    /// Adds a big-endian integer, as used by nftables.
+    pub fn attr_be32(&mut self, attr_type: u16, value: u32) {
+        self.attr(attr_type, &value.to_be_bytes());
+    }
+
+    pub fn nested(&mut self, attr_type: u16, build: impl FnOnce(&mut Self)) {
+        let start = self.buf.len();
+        self.buf.extend([0; 2]);
+        self.buf.extend((attr_type | NLA_F_NESTED).to_ne_bytes());
+        build(self);
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn pad(&mut self) {
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+    }
+
+    pub fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Iterates over the attributes in a message payload, yielding their type and value.
+pub struct Attributes<'a>(pub &'a [u8]);
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([self.0[0], self.0[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([self.0[2], self.0[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > self.0.len() {
+            return None;
+        }
+        let value = &self.0[4..len];
+        self.0 = &self.0[len.next_multiple_of(4).min(self.0.len())..];
+        Some((attr_type, value))
+    }
+}
+
+pub fn parse_str(value: &[u8]) -> String {
+    String::from_utf8_lossy(value)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+pub struct Header {
+    pub msg_type: u16,
+    pub seq: u32,
+}
+
+pub struct Socket {
+    fd: OwnedFd,
+}
+
+impl Socket {
+    pub fn open(protocol: i32) -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("Unable to open netlink socket");
+        }
+        let socket = Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+
+        // Don't hang forever if the kernel never answers
+        let timeout = libc::timeval {
+            tv_sec: RECV_TIMEOUT.as_secs() as libc::time_t,
+            tv_usec: 0,
+        };
+        let r = unsafe {
+            libc::setsockopt(
+                socket.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(std::io::Error::last_os_error()).context("Unable to set netlink timeout");
+        }
+        Ok(socket)
+    }
+
+    pub fn send(&self, buf: &[u8]) -> Result<()> {
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let n = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error()).context("Unable to send netlink message");
+        }
+        Ok(())
+    }
+
+    /// Receives one datagram, split into its messages.
+    pub fn recv(&self) -> Result<Vec<(Header, Vec<u8>)>> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let n = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Unable to receive netlink message");
+        }
+        buf.truncate(n as usize);
+
+        let mut messages = Vec::new();
+        let mut rest = &buf[..];
+        while rest.len() >= NLMSG_HDRLEN {
+            let len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+            if len < NLMSG_HDRLEN || len > rest.len() {
+                return Err(anyhow!("Malformed netlink message"));
+            }
+            let header = Header {
+                msg_type: u16::from_ne_bytes(rest[4..6].try_into().unwrap()),
+                seq: u32::from_ne_bytes(rest[8..12].try_into().unwrap()),
+            };
+            messages.push((header, rest[NLMSG_HDRLEN..len].to_vec()));
+            rest = &rest[len.next_multiple_of(4).min(rest.len())..];
+        }
+        Ok(messages)
+    }
+
+    /// Waits for the acknowledgements of the given sequence numbers, failing on the first error.
+    pub fn wait_for_acks(&self, mut pending: Vec<u32>) -> Result<()> {
+        while !pending.is_empty() {
+            for (header, payload) in self.recv()? {
+                if header.msg_type == NLMSG_ERROR {
+                    check_error(&payload)?;
+                    pending.retain(|seq| *seq != header.seq);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a single request with `NLM_F_ACK` set and waits for its acknowledgement.
+    pub fn request(&self, msg: Message) -> Result<()> {
+        self.send(&msg.finish(1))?;
+        self.wait_for_acks(vec![1])
+    }
+
+    /// Sends a dump request and collects the payloads of the returned messages of type `msg_type`.
+    pub fn dump(&self, msg: Message, msg_type: u16) -> Result<Vec<Vec<u8>>> {
+        self.send(&msg.finish(1))?;
+        let mut payloads = Vec::new();
+        loop {
+            for (header, payload) in self.recv()? {
+                match header.msg_type {
+                    NLMSG_DONE => return Ok(payloads),
+                    NLMSG_ERROR => check_error(&payload)?,
+                    t if t == msg_type => payloads.push(payload),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn check_error(payload: &[u8]) -> Result<()> {
+    let error = i32::from_ne_bytes(
+        payload
+            .get(..4)
+            .and_then(|e| e.try_into().ok())
+            .ok_or(anyhow!("Truncated netlink error"))?,
+    );
+    if error != 0 {
+        return Err(std::io::Error::from_raw_os_error(-error).into());
+    }
+    Ok(())
+}
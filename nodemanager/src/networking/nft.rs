@@ -0,0 +1,826 @@
+//! Minimal nftables client talking netlink directly, without the `nft` binary or libnftnl.
+//! It only knows the chains, sets and rule expressions the network stacks need.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::RangeInclusive,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::netlink::{
+    parse_str, Attributes, Message, Socket, NLM_F_ACK, NLM_F_APPEND, NLM_F_CREATE, NLM_F_DUMP,
+    NLM_F_REQUEST,
+};
+
+pub const TABLE: &str = "containerinfra";
+
+// linux/netfilter/nfnetlink.h
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFGENMSG_LEN: usize = 4;
+
+// linux/netfilter/nf_tables.h
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_GETCHAIN: u16 = 4;
+const NFT_MSG_DELCHAIN: u16 = 5;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFT_MSG_GETRULE: u16 = 7;
+const NFT_MSG_DELRULE: u16 = 8;
+const NFT_MSG_NEWSET: u16 = 9;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFT_MSG_DELSETELEM: u16 = 14;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_HANDLE: u16 = 3;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_SET_TABLE: u16 = 1;
+const NFTA_SET_NAME: u16 = 2;
+const NFTA_SET_KEY_LEN: u16 = 5;
+const NFTA_SET_ID: u16 = 10;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_CT_DREG: u16 = 1;
+const NFTA_CT_KEY: u16 = 2;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_LOOKUP_SET: u16 = 1;
+const NFTA_LOOKUP_SREG: u16 = 2;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_NAT_TYPE: u16 = 1;
+const NFTA_NAT_FAMILY: u16 = 2;
+const NFTA_NAT_REG_ADDR_MIN: u16 = 3;
+const NFTA_NAT_REG_PROTO_MIN: u16 = 5;
+
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_REG_2: u32 = 2;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+const NFT_CMP_LTE: u32 = 3;
+const NFT_CMP_GTE: u32 = 5;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_NFPROTO: u32 = 15;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const NFT_CT_STATE: u32 = 0;
+const NFT_NAT_DNAT: u32 = 1;
+
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+const IFNAMSIZ: usize = 16;
+
+// Conntrack state bits as loaded by `ct state`, in host byte order
+pub const CT_STATE_ESTABLISHED: u32 = 1 << 1;
+pub const CT_STATE_RELATED: u32 = 1 << 2;
+pub const CT_STATE_NEW: u32 = 1 << 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    Prerouting,
+    Forward,
+    Postrouting,
+}
+
+impl Hook {
+    fn hooknum(&self) -> u32 {
+        match self {
+            Hook::Prerouting => 0,  // NF_INET_PRE_ROUTING
+            Hook::Forward => 2,     // NF_INET_FORWARD
+            Hook::Postrouting => 4, // NF_INET_POST_ROUTING
+        }
+    }
+
+    fn priority(&self) -> i32 {
+        match self {
+            Hook::Prerouting => -100, // NF_IP_PRI_NAT_DST
+            Hook::Forward => 0,       // NF_IP_PRI_FILTER
+            Hook::Postrouting => 100, // NF_IP_PRI_NAT_SRC
+        }
+    }
+
+    fn chain_type(&self) -> &'static str {
+        match self {
+            Hook::Prerouting | Hook::Postrouting => "nat",
+            Hook::Forward => "filter",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Expr {
+    Meta(u32),
+    Cmp(u32, Vec<u8>),
+    Payload(u32, u32, u32), // (base, offset, len)
+    CtState,
+    Bitwise(Vec<u8>),
+    Immediate(u32, Vec<u8>),
+    Lookup(String), // Name of the set
+    Verdict(u32),
+    Masquerade,
+    Dnat(u8, bool), // Family of the address, whether the port is translated too
+}
+
+impl Expr {
+    fn name(&self) -> &'static str {
+        match self {
+            Expr::Meta(_) => "meta",
+            Expr::Cmp(_, _) => "cmp",
+            Expr::Payload(_, _, _) => "payload",
+            Expr::CtState => "ct",
+            Expr::Bitwise(_) => "bitwise",
+            Expr::Immediate(_, _) | Expr::Verdict(_) => "immediate",
+            Expr::Lookup(_) => "lookup",
+            Expr::Masquerade => "masq",
+            Expr::Dnat(_, _) => "nat",
+        }
+    }
+
+    fn encode(&self, msg: &mut Message) {
+        match self {
+            Expr::Meta(key) => {
+                msg.attr_be32(NFTA_META_DREG, NFT_REG_1);
+                msg.attr_be32(NFTA_META_KEY, *key);
+            }
+            Expr::Cmp(op, data) => {
+                msg.attr_be32(NFTA_CMP_SREG, NFT_REG_1);
+                msg.attr_be32(NFTA_CMP_OP, *op);
+                msg.nested(NFTA_CMP_DATA, |msg| msg.attr(NFTA_DATA_VALUE, data));
+            }
+            Expr::Payload(base, offset, len) => {
+                msg.attr_be32(NFTA_PAYLOAD_DREG, NFT_REG_1);
+                msg.attr_be32(NFTA_PAYLOAD_BASE, *base);
+                msg.attr_be32(NFTA_PAYLOAD_OFFSET, *offset);
+                msg.attr_be32(NFTA_PAYLOAD_LEN, *len);
+            }
+            Expr::CtState => {
+                msg.attr_be32(NFTA_CT_DREG, NFT_REG_1);
+                msg.attr_be32(NFTA_CT_KEY, NFT_CT_STATE);
+            }
+            Expr::Bitwise(mask) => {
+                msg.attr_be32(NFTA_BITWISE_SREG, NFT_REG_1);
+                msg.attr_be32(NFTA_BITWISE_DREG, NFT_REG_1);
+                msg.attr_be32(NFTA_BITWISE_LEN, mask.len() as u32);
+                msg.nested(NFTA_BITWISE_MASK, |msg| msg.attr(NFTA_DATA_VALUE, mask));
+                msg.nested(NFTA_BITWISE_XOR, |msg| {
+                    msg.attr(NFTA_DATA_VALUE, &vec![0; mask.len()])
+                });
+            }
+            Expr::Immediate(reg, data) => {
+                msg.attr_be32(NFTA_IMMEDIATE_DREG, *reg);
+                msg.nested(NFTA_IMMEDIATE_DATA, |msg| msg.attr(NFTA_DATA_VALUE, data));
+            }
+            Expr::Lookup(set) => {
+                msg.attr_str(NFTA_LOOKUP_SET, set);
+                msg.attr_be32(NFTA_LOOKUP_SREG, NFT_REG_1);
+            }
+            Expr::Verdict(code) => {
+                msg.attr_be32(NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT);
+                msg.nested(NFTA_IMMEDIATE_DATA, |msg| {
+                    msg.nested(NFTA_DATA_VERDICT, |msg| {
+                        msg.attr_be32(NFTA_VERDICT_CODE, *code)
+                    })
+                });
+            }
+            Expr::Masquerade => {}
+            Expr::Dnat(family, with_port) => {
+                msg.attr_be32(NFTA_NAT_TYPE, NFT_NAT_DNAT);
+                msg.attr_be32(NFTA_NAT_FAMILY, *family as u32);
+                msg.attr_be32(NFTA_NAT_REG_ADDR_MIN, NFT_REG_1);
+                if *with_port {
+                    msg.attr_be32(NFTA_NAT_REG_PROTO_MIN, NFT_REG_2);
+                }
+            }
+        }
+    }
+}
+
+/// A rule built from matches, ending in a verdict or NAT statement.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Rule {
+    exprs: Vec<Expr>,
+}
+
+impl Rule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn iifname(self, name: &str) -> Self {
+        self.meta_eq(NFT_META_IIFNAME, ifname(name))
+    }
+
+    pub fn oifname(self, name: &str) -> Self {
+        self.meta_eq(NFT_META_OIFNAME, ifname(name))
+    }
+
+    /// Matches packets coming in on any of the interfaces in `set`, see `Batch::add_set`.
+    pub fn iifname_in(mut self, set: &str) -> Self {
+        self.exprs.push(Expr::Meta(NFT_META_IIFNAME));
+        self.exprs.push(Expr::Lookup(set.to_string()));
+        self
+    }
+
+    pub fn ip_saddr(self, addr: &Ipv4Addr) -> Self {
+        self.meta_eq(NFT_META_NFPROTO, vec![NFPROTO_IPV4])
+            .payload_eq(NFT_PAYLOAD_NETWORK_HEADER, 12, addr.octets().to_vec())
+    }
+
+    pub fn ip6_saddr(self, addr: &Ipv6Addr) -> Self {
+        self.meta_eq(NFT_META_NFPROTO, vec![NFPROTO_IPV6])
+            .payload_eq(NFT_PAYLOAD_NETWORK_HEADER, 8, addr.octets().to_vec())
+    }
+
+    /// Matches IPv4 packets with a source address in `addr/prefix_len`.
+    pub fn ip_saddr_in(self, addr: &Ipv4Addr, prefix_len: u8) -> Self {
+        self.meta_eq(NFT_META_NFPROTO, vec![NFPROTO_IPV4])
+            .prefix_eq(12, addr, prefix_len)
+    }
+
+    pub fn ip_daddr_in(self, addr: &Ipv4Addr, prefix_len: u8) -> Self {
+        self.meta_eq(NFT_META_NFPROTO, vec![NFPROTO_IPV4])
+            .prefix_eq(16, addr, prefix_len)
+    }
+
+    pub fn ip_daddr(self, addr: &IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(addr) => self
+                .meta_eq(NFT_META_NFPROTO, vec![NFPROTO_IPV4])
+                .payload_eq(NFT_PAYLOAD_NETWORK_HEADER, 16, addr.octets().to_vec()),
+            IpAddr::V6(addr) => self
+                .meta_eq(NFT_META_NFPROTO, vec![NFPROTO_IPV6])
+                .payload_eq(NFT_PAYLOAD_NETWORK_HEADER, 24, addr.octets().to_vec()),
+        }
+    }
+
+    /// Matches the destination port of a TCP, UDP or SCTP packet, `protocol` being its IPPROTO number.
+    pub fn dport(self, protocol: u8, ports: RangeInclusive<u16>) -> Self {
+        self.meta_eq(NFT_META_L4PROTO, vec![protocol])
+            .port_in(2, ports)
+    }
+
+    pub fn sport(self, protocol: u8, ports: RangeInclusive<u16>) -> Self {
+        self.meta_eq(NFT_META_L4PROTO, vec![protocol])
+            .port_in(0, ports)
+    }
+
+    /// Matches any of the `CT_STATE_*` bits in `states`.
+    pub fn ct_state(mut self, states: u32) -> Self {
+        self.exprs.push(Expr::CtState);
+        self.exprs
+            .push(Expr::Bitwise(states.to_ne_bytes().to_vec()));
+        self.exprs.push(Expr::Cmp(NFT_CMP_NEQ, vec![0; 4]));
+        self
+    }
+
+    pub fn accept(mut self) -> Self {
+        self.exprs.push(Expr::Verdict(NF_ACCEPT));
+        self
+    }
+
+    pub fn drop_packet(mut self) -> Self {
+        self.exprs.push(Expr::Verdict(NF_DROP));
+        self
+    }
+
+    pub fn masquerade(mut self) -> Self {
+        self.exprs.push(Expr::Masquerade);
+        self
+    }
+
+    /// Rewrites the destination, only packets of the same IP version as `addr` are translated.
+    /// The destination port is kept if `port` is unset.
+    pub fn dnat(mut self, addr: &IpAddr, port: Option<u16>) -> Self {
+        let (family, octets) = match addr {
+            IpAddr::V4(addr) => (NFPROTO_IPV4, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (NFPROTO_IPV6, addr.octets().to_vec()),
+        };
+        self.exprs.push(Expr::Immediate(NFT_REG_1, octets));
+        if let Some(port) = port {
+            self.exprs
+                .push(Expr::Immediate(NFT_REG_2, port.to_be_bytes().to_vec()));
+        }
+        self.exprs.push(Expr::Dnat(family, port.is_some()));
+        self
+    }
+
+    fn meta_eq(mut self, key: u32, data: Vec<u8>) -> Self {
+        self.exprs.push(Expr::Meta(key));
+        self.exprs.push(Expr::Cmp(NFT_CMP_EQ, data));
+        self
+    }
+
+    // Ports are compared in network byte order, which keeps their order
+    fn port_in(mut self, offset: u32, ports: RangeInclusive<u16>) -> Self {
+        if ports.start() == ports.end() {
+            return self.payload_eq(
+                NFT_PAYLOAD_TRANSPORT_HEADER,
+                offset,
+                ports.start().to_be_bytes().to_vec(),
+            );
+        }
+        self.exprs
+            .push(Expr::Payload(NFT_PAYLOAD_TRANSPORT_HEADER, offset, 2));
+        self.exprs
+            .push(Expr::Cmp(NFT_CMP_GTE, ports.start().to_be_bytes().to_vec()));
+        self.exprs
+            .push(Expr::Cmp(NFT_CMP_LTE, ports.end().to_be_bytes().to_vec()));
+        self
+    }
+
+    fn prefix_eq(mut self, offset: u32, addr: &Ipv4Addr, prefix_len: u8) -> Self {
+        let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        self.exprs
+            .push(Expr::Payload(NFT_PAYLOAD_NETWORK_HEADER, offset, 4));
+        self.exprs.push(Expr::Bitwise(mask.to_be_bytes().to_vec()));
+        self.exprs.push(Expr::Cmp(
+            NFT_CMP_EQ,
+            (u32::from(*addr) & mask).to_be_bytes().to_vec(),
+        ));
+        self
+    }
+
+    fn payload_eq(mut self, base: u32, offset: u32, data: Vec<u8>) -> Self {
+        self.exprs
+            .push(Expr::Payload(base, offset, data.len() as u32));
+        self.exprs.push(Expr::Cmp(NFT_CMP_EQ, data));
+        self
+    }
+}
+
+fn ifname(name: &str) -> Vec<u8> {
+    let mut data = name.as_bytes().to_vec();
+    data.resize(IFNAMSIZ, 0);
+    data
+}
+
+/// A rule as found in the kernel, with the names of its expressions.
+pub struct RuleInfo {
+    pub chain: String,
+    pub handle: u64,
+    pub expressions: Vec<String>,
+}
+
+impl RuleInfo {
+    pub fn as_proto(&self) -> proto::node::FirewallRule {
+        proto::node::FirewallRule {
+            chain: self.chain.clone(),
+            handle: self.handle,
+            expressions: self.expressions.clone(),
+        }
+    }
+}
+
+impl Display for RuleInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} handle {}: {}",
+            self.chain,
+            self.handle,
+            self.expressions.join(" ")
+        )
+    }
+}
+
+/// Changes applied atomically, either all of them or none.
+#[derive(Default)]
+pub struct Batch {
+    messages: Vec<Message>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the table unless it exists.
+    pub fn add_table(&mut self) {
+        self.push(NFT_MSG_NEWTABLE, NLM_F_CREATE, |msg| {
+            msg.attr_str(NFTA_TABLE_NAME, TABLE)
+        });
+    }
+
+    /// Creates a base chain accepting by default, unless it exists.
+    pub fn add_chain(&mut self, name: &str, hook: Hook) {
+        self.push(NFT_MSG_NEWCHAIN, NLM_F_CREATE, |msg| {
+            msg.attr_str(NFTA_CHAIN_TABLE, TABLE);
+            msg.attr_str(NFTA_CHAIN_NAME, name);
+            msg.nested(NFTA_CHAIN_HOOK, |msg| {
+                msg.attr_be32(NFTA_HOOK_HOOKNUM, hook.hooknum());
+                msg.attr_be32(NFTA_HOOK_PRIORITY, hook.priority() as u32);
+            });
+            msg.attr_be32(NFTA_CHAIN_POLICY, NF_ACCEPT);
+            msg.attr_str(NFTA_CHAIN_TYPE, hook.chain_type());
+        });
+    }
+
+    /// Deletes a chain along with its rules.
+    pub fn del_chain(&mut self, name: &str) {
+        self.push(NFT_MSG_DELCHAIN, 0, |msg| {
+            msg.attr_str(NFTA_CHAIN_TABLE, TABLE);
+            msg.attr_str(NFTA_CHAIN_NAME, name);
+        });
+    }
+
+    /// Deletes all rules of a chain, keeping the chain.
+    pub fn flush_chain(&mut self, name: &str) {
+        self.push(NFT_MSG_DELRULE, 0, |msg| {
+            msg.attr_str(NFTA_RULE_TABLE, TABLE);
+            msg.attr_str(NFTA_RULE_CHAIN, name);
+        });
+    }
+
+    /// Creates a set of interface names unless it exists.
+    pub fn add_set(&mut self, name: &str) {
+        self.push(NFT_MSG_NEWSET, NLM_F_CREATE, |msg| {
+            msg.attr_str(NFTA_SET_TABLE, TABLE);
+            msg.attr_str(NFTA_SET_NAME, name);
+            msg.attr_be32(NFTA_SET_KEY_LEN, IFNAMSIZ as u32);
+            // Only refers to the set within the batch, which nothing does
+            msg.attr_be32(NFTA_SET_ID, 1);
+        });
+    }
+
+    /// Removes all interfaces from a set, keeping the rules which use it.
+    pub fn flush_set(&mut self, name: &str) {
+        self.push(NFT_MSG_DELSETELEM, 0, |msg| {
+            msg.attr_str(NFTA_SET_ELEM_LIST_TABLE, TABLE);
+            msg.attr_str(NFTA_SET_ELEM_LIST_SET, name);
+        });
+    }
+
+    pub fn add_set_element(&mut self, set: &str, if_name: &str) {
+        self.push(NFT_MSG_NEWSETELEM, NLM_F_CREATE, |msg| {
+            set_element(msg, set, if_name)
+        });
+    }
+
+    pub fn del_set_element(&mut self, set: &str, if_name: &str) {
+        self.push(NFT_MSG_DELSETELEM, 0, |msg| set_element(msg, set, if_name));
+    }
+
+    /// Appends a rule to the end of a chain.
+    pub fn add_rule(&mut self, chain: &str, rule: &Rule) {
+        self.push(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND, |msg| {
+            msg.attr_str(NFTA_RULE_TABLE, TABLE);
+            msg.attr_str(NFTA_RULE_CHAIN, chain);
+            msg.nested(NFTA_RULE_EXPRESSIONS, |msg| {
+                for expr in &rule.exprs {
+                    msg.nested(NFTA_LIST_ELEM, |msg| {
+                        msg.attr_str(NFTA_EXPR_NAME, expr.name());
+                        msg.nested(NFTA_EXPR_DATA, |msg| expr.encode(msg));
+                    });
+                }
+            });
+        });
+    }
+
+    fn push(&mut self, msg_type: u16, flags: u16, build: impl FnOnce(&mut Message)) {
+        let mut msg = Message::new(
+            NFNL_SUBSYS_NFTABLES << 8 | msg_type,
+            NLM_F_REQUEST | NLM_F_ACK | flags,
+            &nfgenmsg(NFPROTO_INET, 0),
+        );
+        build(&mut msg);
+        self.messages.push(msg);
+    }
+
+    pub fn send(self) -> Result<()> {
+        if self.messages.is_empty() {
+            return Ok(());
+        }
+        let socket = Socket::open(libc::NETLINK_NETFILTER)?;
+
+        let mut buf = Vec::new();
+        let mut pending = Vec::with_capacity(self.messages.len());
+        let mut seq = 0;
+        let batch_header = nfgenmsg(0, NFNL_SUBSYS_NFTABLES);
+        let begin = Message::new(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, &batch_header);
+        buf.extend(begin.finish(seq));
+        for msg in self.messages {
+            seq += 1;
+            pending.push(seq);
+            buf.extend(msg.finish(seq));
+        }
+        let end = Message::new(NFNL_MSG_BATCH_END, NLM_F_REQUEST, &batch_header);
+        buf.extend(end.finish(seq + 1));
+        socket.send(&buf)?;
+
+        socket
+            .wait_for_acks(pending)
+            .context("nftables request failed")
+    }
+}
+
+/// Lists the rules of a chain in the `containerinfra` table.
+pub fn list_rules(chain: &str) -> Result<Vec<RuleInfo>> {
+    let socket = Socket::open(libc::NETLINK_NETFILTER)?;
+    let mut msg = Message::new(
+        NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_GETRULE,
+        NLM_F_REQUEST | NLM_F_DUMP,
+        &nfgenmsg(NFPROTO_INET, 0),
+    );
+    msg.attr_str(NFTA_RULE_TABLE, TABLE);
+    msg.attr_str(NFTA_RULE_CHAIN, chain);
+
+    socket
+        .dump(msg, NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_NEWRULE)
+        .context("Unable to list nftables rules")?
+        .iter()
+        .map(|payload| parse_rule(payload.get(NFGENMSG_LEN..).unwrap_or(&[])))
+        .collect()
+}
+
+/// Lists the names of the chains in the `containerinfra` table.
+pub fn list_chains() -> Result<Vec<String>> {
+    let socket = Socket::open(libc::NETLINK_NETFILTER)?;
+    let msg = Message::new(
+        NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_GETCHAIN,
+        NLM_F_REQUEST | NLM_F_DUMP,
+        &nfgenmsg(NFPROTO_INET, 0),
+    );
+
+    // The dump covers the chains of every table of the family
+    let mut chains = Vec::new();
+    for payload in socket
+        .dump(msg, NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_NEWCHAIN)
+        .context("Unable to list nftables chains")?
+    {
+        let mut table = String::new();
+        let mut name = String::new();
+        for (attr_type, value) in Attributes(payload.get(NFGENMSG_LEN..).unwrap_or(&[])) {
+            match attr_type {
+                NFTA_CHAIN_TABLE => table = parse_str(value),
+                NFTA_CHAIN_NAME => name = parse_str(value),
+                _ => {}
+            }
+        }
+        if table == TABLE {
+            chains.push(name);
+        }
+    }
+    Ok(chains)
+}
+
+fn set_element(msg: &mut Message, set: &str, if_name: &str) {
+    msg.attr_str(NFTA_SET_ELEM_LIST_TABLE, TABLE);
+    msg.attr_str(NFTA_SET_ELEM_LIST_SET, set);
+    msg.nested(NFTA_SET_ELEM_LIST_ELEMENTS, |msg| {
+        msg.nested(NFTA_LIST_ELEM, |msg| {
+            msg.nested(NFTA_SET_ELEM_KEY, |msg| {
+                msg.attr(NFTA_DATA_VALUE, &ifname(if_name))
+            })
+        })
+    });
+}
+
+/// The header of every nftables message: family, version and resource id.
+fn nfgenmsg(family: u8, res_id: u16) -> [u8; NFGENMSG_LEN] {
+    let res_id = res_id.to_be_bytes();
+    [family, 0, res_id[0], res_id[1]]
+}
+
+fn parse_rule(data: &[u8]) -> Result<RuleInfo> {
+    let mut rule = RuleInfo {
+        chain: String::new(),
+        handle: 0,
+        expressions: Vec::new(),
+    };
+    for (attr_type, value) in Attributes(data) {
+        match attr_type {
+            NFTA_RULE_CHAIN => rule.chain = parse_str(value),
+            NFTA_RULE_HANDLE => {
+                rule.handle = u64::from_be_bytes(
+                    value
+                        .try_into()
+                        .map_err(|_| anyhow!("Invalid rule handle"))?,
+                )
+            }
+            NFTA_RULE_EXPRESSIONS => {
+                for (_, expr) in Attributes(value) {
+                    if let Some((_, name)) =
+                        Attributes(expr).find(|(attr_type, _)| *attr_type == NFTA_EXPR_NAME)
+                    {
+                        rule.expressions.push(parse_str(name));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(rule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Attrs = Vec<(u16, Vec<u8>)>;
+
+    /// The expressions of a rule as sent to the kernel, by name with the attributes of their data.
+    fn encoded(rule: &Rule) -> Vec<(String, Attrs)> {
+        let mut batch = Batch::new();
+        batch.add_rule("tap0_prerouting", rule);
+        let msg = batch.messages.pop().unwrap().finish(1);
+        let (_, exprs) = Attributes(&msg[16 + NFGENMSG_LEN..])
+            .find(|(attr_type, _)| *attr_type == NFTA_RULE_EXPRESSIONS)
+            .unwrap();
+        Attributes(exprs)
+            .map(|(_, expr)| {
+                let mut name = String::new();
+                let mut data = Vec::new();
+                for (attr_type, value) in Attributes(expr) {
+                    match attr_type {
+                        NFTA_EXPR_NAME => name = parse_str(value),
+                        NFTA_EXPR_DATA => {
+                            data = Attributes(value).map(|(t, v)| (t, v.to_vec())).collect()
+                        }
+                        _ => {}
+                    }
+                }
+                (name, data)
+            })
+            .collect()
+    }
+
+    fn be32(value: u32) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+
+    /// `data` nested in an `NFTA_DATA_VALUE` attribute, as held by cmp and immediate expressions.
+    fn value(data: &[u8]) -> Vec<u8> {
+        let mut msg = Message::new(0, 0, &[]);
+        msg.attr(NFTA_DATA_VALUE, data);
+        msg.finish(0)[16..].to_vec()
+    }
+
+    fn names(exprs: &[(String, Attrs)]) -> Vec<&str> {
+        exprs.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn encodes_dnat() {
+        let addr: IpAddr = "172.16.0.2".parse().unwrap();
+        let exprs = encoded(&Rule::new().dnat(&addr, Some(8080)));
+        assert_eq!(names(&exprs), ["immediate", "immediate", "nat"]);
+        assert_eq!(
+            exprs[0].1,
+            [
+                (NFTA_IMMEDIATE_DREG, be32(NFT_REG_1)),
+                (NFTA_IMMEDIATE_DATA, value(&[172, 16, 0, 2]))
+            ]
+        );
+        assert_eq!(
+            exprs[1].1,
+            [
+                (NFTA_IMMEDIATE_DREG, be32(NFT_REG_2)),
+                (NFTA_IMMEDIATE_DATA, value(&8080u16.to_be_bytes()))
+            ]
+        );
+        assert_eq!(
+            exprs[2].1,
+            [
+                (NFTA_NAT_TYPE, be32(NFT_NAT_DNAT)),
+                (NFTA_NAT_FAMILY, be32(NFPROTO_IPV4 as u32)),
+                (NFTA_NAT_REG_ADDR_MIN, be32(NFT_REG_1)),
+                (NFTA_NAT_REG_PROTO_MIN, be32(NFT_REG_2))
+            ]
+        );
+
+        // Keeping the port leaves the second register out
+        let addr: IpAddr = "fd00:c0::2".parse().unwrap();
+        let exprs = encoded(&Rule::new().dnat(&addr, None));
+        assert_eq!(names(&exprs), ["immediate", "nat"]);
+        assert_eq!(
+            exprs[1].1,
+            [
+                (NFTA_NAT_TYPE, be32(NFT_NAT_DNAT)),
+                (NFTA_NAT_FAMILY, be32(NFPROTO_IPV6 as u32)),
+                (NFTA_NAT_REG_ADDR_MIN, be32(NFT_REG_1))
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_masquerade() {
+        let addr = "172.16.0.2".parse().unwrap();
+        let exprs = encoded(&Rule::new().oifname("eth0").ip_saddr(&addr).masquerade());
+        assert_eq!(
+            names(&exprs),
+            ["meta", "cmp", "meta", "cmp", "payload", "cmp", "masq"]
+        );
+        assert_eq!(
+            exprs[0].1,
+            [
+                (NFTA_META_DREG, be32(NFT_REG_1)),
+                (NFTA_META_KEY, be32(NFT_META_OIFNAME))
+            ]
+        );
+        assert_eq!(
+            exprs[1].1,
+            [
+                (NFTA_CMP_SREG, be32(NFT_REG_1)),
+                (NFTA_CMP_OP, be32(NFT_CMP_EQ)),
+                (NFTA_CMP_DATA, value(b"eth0\0\0\0\0\0\0\0\0\0\0\0\0"))
+            ]
+        );
+        assert_eq!(
+            exprs[4].1,
+            [
+                (NFTA_PAYLOAD_DREG, be32(NFT_REG_1)),
+                (NFTA_PAYLOAD_BASE, be32(NFT_PAYLOAD_NETWORK_HEADER)),
+                (NFTA_PAYLOAD_OFFSET, be32(12)),
+                (NFTA_PAYLOAD_LEN, be32(4))
+            ]
+        );
+        assert_eq!(exprs[5].1[2], (NFTA_CMP_DATA, value(&[172, 16, 0, 2])));
+        assert!(exprs[6].1.is_empty());
+    }
+
+    #[test]
+    fn encodes_set_lookups() {
+        let exprs = encoded(&Rule::new().iifname_in("private_db").accept());
+        assert_eq!(names(&exprs), ["meta", "lookup", "immediate"]);
+        assert_eq!(
+            exprs[0].1,
+            [
+                (NFTA_META_DREG, be32(NFT_REG_1)),
+                (NFTA_META_KEY, be32(NFT_META_IIFNAME))
+            ]
+        );
+        assert_eq!(
+            exprs[1].1,
+            [
+                (NFTA_LOOKUP_SET, b"private_db\0".to_vec()),
+                (NFTA_LOOKUP_SREG, be32(NFT_REG_1))
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_port_ranges() {
+        let exprs = encoded(&Rule::new().dport(libc::IPPROTO_UDP as u8, 5000..=5009));
+        assert_eq!(names(&exprs), ["meta", "cmp", "payload", "cmp", "cmp"]);
+        assert_eq!(
+            exprs[1].1[2],
+            (NFTA_CMP_DATA, value(&[libc::IPPROTO_UDP as u8]))
+        );
+        assert_eq!(
+            exprs[3].1[1..],
+            [
+                (NFTA_CMP_OP, be32(NFT_CMP_GTE)),
+                (NFTA_CMP_DATA, value(&5000u16.to_be_bytes()))
+            ]
+        );
+        assert_eq!(
+            exprs[4].1[1..],
+            [
+                (NFTA_CMP_OP, be32(NFT_CMP_LTE)),
+                (NFTA_CMP_DATA, value(&5009u16.to_be_bytes()))
+            ]
+        );
+
+        // A single port is one comparison
+        let exprs = encoded(&Rule::new().dport(libc::IPPROTO_TCP as u8, 80..=80));
+        assert_eq!(names(&exprs), ["meta", "cmp", "payload", "cmp"]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Host ports published on the node, so no two instances forward the same one.
+
+use std::{fmt, ops::RangeInclusive};
+
+use super::PublishedPort;
+use crate::capacity::InsufficientCapacity;
+
+/// A publication overlapping one which already exists on the node, reported as `ALREADY_EXISTS`.
+#[derive(Debug)]
+pub struct PortConflict(pub String);
+
+impl fmt::Display for PortConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PortConflict {}
+
+/// The published ports of all network stacks, by slot.
+pub struct HostPorts {
+    published: Vec<(u16, PublishedPort)>,
+    allocatable: RangeInclusive<u16>, // For publications which leave the host ports to the node
+}
+
+impl HostPorts {
+    pub fn new(allocatable: RangeInclusive<u16>) -> Self {
+        Self {
+            published: Vec::new(),
+            allocatable,
+        }
+    }
+
+    /// Finds the lowest free host ports in the allocatable range for `port`, they still have to be claimed.
+    pub fn allocate(&self, port: &PublishedPort) -> Result<u16, InsufficientCapacity> {
+        let last = *self.allocatable.end() as u32;
+        let mut first = *self.allocatable.start() as u32;
+        while first + port.port_count as u32 - 1 <= last {
+            let candidate = PublishedPort {
+                host_port: first as u16,
+                ..port.clone()
+            };
+            match self
+                .published
+                .iter()
+                .find(|(_, existing)| overlaps(existing, &candidate))
+            {
+                None => return Ok(candidate.host_port),
+                // Nothing before the end of the publication in the way fits
+                Some((_, existing)) => first = *existing.host_ports().end() as u32 + 1,
+            }
+        }
+        Err(InsufficientCapacity(format!(
+            "No {} free host ports left to allocate",
+            port.port_count
+        )))
+    }
+
+    pub fn claim(&mut self, slot: u16, port: &PublishedPort) -> Result<(), PortConflict> {
+        if let Some((_, existing)) = self
+            .published
+            .iter()
+            .find(|(_, existing)| overlaps(existing, port))
+        {
+            return Err(PortConflict(format!(
+                "Host port {} is already published",
+                existing.host_port
+            )));
+        }
+        self.published.push((slot, port.clone()));
+        Ok(())
+    }
+
+    pub fn release(&mut self, slot: u16, port: &PublishedPort) {
+        self.published
+            .retain(|(owner, existing)| *owner != slot || existing != port);
+    }
+
+    /// Releases all ports of a slot whose stack is gone.
+    pub fn release_slot(&mut self, slot: u16) {
+        self.published.retain(|(owner, _)| *owner != slot);
+    }
+}
+
+// A publication on any address of the host collides with all of them
+fn overlaps(a: &PublishedPort, b: &PublishedPort) -> bool {
+    let (a_ports, b_ports) = (a.host_ports(), b.host_ports());
+    a.protocol == b.protocol
+        && a_ports.start() <= b_ports.end()
+        && b_ports.start() <= a_ports.end()
+        && (a.host_ip.is_none() || b.host_ip.is_none() || a.host_ip == b.host_ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::networking::Protocol;
+
+    use super::*;
+
+    fn port(
+        host_port: u16,
+        port_count: u16,
+        protocol: Protocol,
+        host_ip: Option<&str>,
+    ) -> PublishedPort {
+        PublishedPort {
+            host_port,
+            guest_port: 80,
+            protocol,
+            port_count,
+            host_ip: host_ip.map(|ip| ip.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn rejects_overlapping_ports() {
+        let mut ports = HostPorts::new(30000..=30009);
+        ports
+            .claim(0, &port(8000, 10, Protocol::Tcp, None))
+            .unwrap();
+
+        assert!(ports.claim(1, &port(8009, 1, Protocol::Tcp, None)).is_err());
+        assert!(ports
+            .claim(1, &port(7990, 11, Protocol::Tcp, Some("10.0.0.1")))
+            .is_err());
+        ports.claim(1, &port(8010, 1, Protocol::Tcp, None)).unwrap();
+        ports
+            .claim(1, &port(8000, 10, Protocol::Udp, None))
+            .unwrap();
+
+        ports
+            .claim(2, &port(9000, 1, Protocol::Tcp, Some("10.0.0.1")))
+            .unwrap();
+        ports
+            .claim(2, &port(9000, 1, Protocol::Tcp, Some("10.0.0.2")))
+            .unwrap();
+        assert!(ports.claim(3, &port(9000, 1, Protocol::Tcp, None)).is_err());
+    }
+
+    #[test]
+    fn releases_ports() {
+        let mut ports = HostPorts::new(30000..=30009);
+        let published = port(8000, 1, Protocol::Tcp, None);
+        ports.claim(0, &published).unwrap();
+        ports.claim(0, &port(8001, 1, Protocol::Tcp, None)).unwrap();
+
+        ports.release(0, &published);
+        ports.claim(1, &published).unwrap();
+        assert!(ports.claim(2, &port(8001, 1, Protocol::Tcp, None)).is_err());
+
+        ports.release_slot(0);
+        ports.claim(2, &port(8001, 1, Protocol::Tcp, None)).unwrap();
+    }
+
+    #[test]
+    fn allocates_free_ports() {
+        let mut ports = HostPorts::new(30000..=30009);
+        ports
+            .claim(0, &port(30000, 2, Protocol::Tcp, None))
+            .unwrap();
+        ports
+            .claim(0, &port(30003, 1, Protocol::Tcp, None))
+            .unwrap();
+
+        assert_eq!(
+            ports.allocate(&port(0, 1, Protocol::Tcp, None)).unwrap(),
+            30002
+        );
+        assert_eq!(
+            ports.allocate(&port(0, 2, Protocol::Tcp, None)).unwrap(),
+            30004
+        );
+        assert_eq!(
+            ports.allocate(&port(0, 2, Protocol::Udp, None)).unwrap(),
+            30000
+        );
+        assert!(ports.allocate(&port(0, 7, Protocol::Tcp, None)).is_err());
+
+        ports
+            .claim(1, &port(30004, 6, Protocol::Tcp, None))
+            .unwrap();
+        ports
+            .claim(1, &port(30002, 1, Protocol::Tcp, None))
+            .unwrap();
+        assert!(ports.allocate(&port(0, 1, Protocol::Tcp, None)).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+//! Named networks between instances of the node. Every member gets an extra TAP device with a /30 out of the
+//! network's subnet, traffic between members is routed by the host and filtered by the policy of the network.
+//! The TAP devices of a network's members are kept in an nftables set, only traffic coming in on one of them
+//! reaches the members.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{
+    default_port_count,
+    nft::{Batch, Hook, Rule, CT_STATE_ESTABLISHED, CT_STATE_RELATED},
+    Protocol, TunTap,
+};
+
+/// An IPv4 network in CIDR notation, e.g. `10.100.0.0/16`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Ipv4Prefix {
+    pub addr: Ipv4Addr,
+    pub len: u8,
+}
+
+impl TryFrom<String> for Ipv4Prefix {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let (addr, len) = value
+            .split_once('/')
+            .ok_or(anyhow::anyhow!("Missing prefix length in {}", value))?;
+        let addr: Ipv4Addr = addr.parse()?;
+        let len: u8 = len.parse()?;
+        if len > 32 {
+            return Err(anyhow::anyhow!("Invalid prefix length in {}", value));
+        }
+        Ok(Self {
+            addr: Ipv4Addr::from(u32::from(addr) & Self::mask(len)),
+            len,
+        })
+    }
+}
+
+impl Ipv4Prefix {
+    fn mask(len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - len as u32).unwrap_or(0)
+    }
+
+    fn overlaps(&self, other: &Ipv4Prefix) -> bool {
+        let mask = Self::mask(self.len.min(other.len));
+        u32::from(self.addr) & mask == u32::from(other.addr) & mask
+    }
+}
+
+impl std::fmt::Display for Ipv4Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// Ports members of a private network accept connections on from each other.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AllowedPorts {
+    #[serde(default)]
+    pub protocol: Protocol,
+    pub port: u16,
+    #[serde(default = "default_port_count")]
+    pub port_count: u16,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrivateNetworkConfig {
+    pub subnet: Ipv4Prefix, // Split into a /30 per network slot
+    #[serde(default)]
+    pub allowed_ports: Option<Vec<AllowedPorts>>, // Members accept anything from each other if unset
+}
+
+impl PrivateNetworkConfig {
+    // The subnets of the stacks on the public side
+    const STACK_SUBNETS: Ipv4Prefix = Ipv4Prefix {
+        addr: Ipv4Addr::new(172, 16, 0, 0),
+        len: 12,
+    };
+
+    pub fn validate(&self, name: &str) -> Result<()> {
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Private networks need a name"));
+        }
+        if self.subnet.len > 30 {
+            return Err(anyhow::anyhow!(
+                "Subnet {} of private network {} is smaller than a /30",
+                self.subnet,
+                name
+            ));
+        }
+        if self.subnet.overlaps(&Self::STACK_SUBNETS) {
+            return Err(anyhow::anyhow!(
+                "Subnet {} of private network {} overlaps the instance subnets {}",
+                self.subnet,
+                name,
+                Self::STACK_SUBNETS
+            ));
+        }
+        for allowed in self.allowed_ports.iter().flatten() {
+            if allowed.port == 0
+                || allowed.port_count == 0
+                || allowed.port.checked_add(allowed.port_count - 1).is_none()
+            {
+                return Err(anyhow::anyhow!(
+                    "Invalid allowed ports {} + {} of private network {}",
+                    allowed.port,
+                    allowed.port_count,
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that no two networks share addresses.
+    pub fn validate_all<'a>(
+        networks: impl IntoIterator<Item = (&'a String, &'a PrivateNetworkConfig)>,
+    ) -> Result<()> {
+        let mut seen: Vec<(&String, &PrivateNetworkConfig)> = Vec::new();
+        for (name, config) in networks {
+            config.validate(name)?;
+            if let Some((other, _)) = seen.iter().find(|(_, c)| c.subnet.overlaps(&config.subnet)) {
+                return Err(anyhow::anyhow!(
+                    "Private networks {} and {} overlap",
+                    other,
+                    name
+                ));
+            }
+            seen.push((name, config));
+        }
+        Ok(())
+    }
+
+    /// The nftables set holding the TAP devices of the members of a network.
+    pub(super) fn members_set(name: &str) -> String {
+        format!("private_{}", name)
+    }
+
+    /// The (gateway, guest) addresses of a slot, the first two of its /30. None if the subnet is too small.
+    fn slot_addrs(&self, id: u16) -> Option<(Ipv4Addr, Ipv4Addr)> {
+        let slots = 1u64 << (30 - self.subnet.len);
+        if id as u64 >= slots {
+            return None;
+        }
+        let base = u32::from(self.subnet.addr) + id as u32 * 4;
+        Some((Ipv4Addr::from(base + 1), Ipv4Addr::from(base + 2)))
+    }
+}
+
+/// The interface of a network stack in a private network.
+pub struct PrivateAttachment {
+    network: String,
+    config: PrivateNetworkConfig,
+    addr: Ipv4Addr,
+    gateway: Ipv4Addr,
+    nic: TunTap,
+}
+
+impl PrivateAttachment {
+    /// Sets up the `index`th private network of a slot, or takes over its TAP device if `adopt` is set.
+    pub(super) fn new(
+        slot_id: u16,
+        index: usize,
+        network: &str,
+        config: &PrivateNetworkConfig,
+        adopt: bool,
+    ) -> Result<Self> {
+        let (gateway, addr) = config.slot_addrs(slot_id).ok_or(anyhow::anyhow!(
+            "Network slot {} doesn't fit into private network {} ({})",
+            slot_id,
+            network,
+            config.subnet
+        ))?;
+        let tap_dev_name = format!("tap{}p{}", slot_id, index);
+        let nic = if adopt {
+            TunTap::existing(&tap_dev_name)?
+        } else {
+            let tap = TunTap::new(&tap_dev_name)?;
+            tap.add_address(&IpAddr::V4(gateway), 30)?;
+            tap.up()?;
+            tap
+        };
+        // Adopted devices are added again, the sets are emptied when the nodemanager starts
+        let mut batch = Batch::new();
+        batch.add_set_element(&PrivateNetworkConfig::members_set(network), nic.name());
+        batch.send()?;
+        Ok(Self {
+            network: network.to_string(),
+            config: config.clone(),
+            addr,
+            gateway,
+            nic,
+        })
+    }
+
+    /// Only lets members of the network talk to each other, on the allowed ports if the network restricts them.
+    /// Both the sending and the receiving member's rules check the traffic, so a member only sends from its own
+    /// address and only accepts traffic which came in on the TAP device of another member.
+    pub(super) fn rules(&self) -> Vec<(Hook, Rule)> {
+        let nic_name = self.nic.name();
+        let subnet = &self.config.subnet;
+        let members = PrivateNetworkConfig::members_set(&self.network);
+        let established = || Rule::new().ct_state(CT_STATE_ESTABLISHED | CT_STATE_RELATED);
+        let outbound = || {
+            Rule::new()
+                .iifname(nic_name)
+                .ip_saddr(&self.addr)
+                .ip_daddr_in(&subnet.addr, subnet.len)
+        };
+        let inbound = || {
+            Rule::new()
+                .oifname(nic_name)
+                .iifname_in(&members)
+                .ip_saddr_in(&subnet.addr, subnet.len)
+        };
+
+        let mut rules = vec![
+            (Hook::Forward, established().iifname(nic_name).accept()),
+            (Hook::Forward, established().oifname(nic_name).accept()),
+        ];
+        match &self.config.allowed_ports {
+            None => rules.extend([
+                (Hook::Forward, outbound().accept()),
+                (Hook::Forward, inbound().accept()),
+            ]),
+            Some(allowed_ports) => {
+                for allowed in allowed_ports {
+                    let protocol = allowed.protocol.number();
+                    let ports = allowed.port..=allowed.port + (allowed.port_count - 1);
+                    rules.extend([
+                        (
+                            Hook::Forward,
+                            outbound().dport(protocol, ports.clone()).accept(),
+                        ),
+                        (Hook::Forward, inbound().dport(protocol, ports).accept()),
+                    ]);
+                }
+            }
+        }
+        rules.extend([
+            (Hook::Forward, Rule::new().iifname(nic_name).drop_packet()),
+            (Hook::Forward, Rule::new().oifname(nic_name).drop_packet()),
+        ]);
+        rules
+    }
+
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    pub fn subnet(&self) -> &Ipv4Prefix {
+        &self.config.subnet
+    }
+
+    pub fn ipv4_addr(&self) -> &Ipv4Addr {
+        &self.addr
+    }
+
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    pub fn nic(&self) -> &TunTap {
+        &self.nic
+    }
+}
+
+impl Drop for PrivateAttachment {
+    fn drop(&mut self) {
+        let mut batch = Batch::new();
+        batch.del_set_element(
+            &PrivateNetworkConfig::members_set(&self.network),
+            self.nic.name(),
+        );
+        if let Err(e) = batch.send() {
+            log::warn!(
+                "Failed to remove {} from private network {}: {:?}",
+                self.nic.name(),
+                self.network,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(subnet: &str) -> PrivateNetworkConfig {
+        PrivateNetworkConfig {
+            subnet: Ipv4Prefix::try_from(subnet.to_string()).unwrap(),
+            allowed_ports: None,
+        }
+    }
+
+    #[test]
+    fn carves_slot_subnets() {
+        let config = network("10.100.0.0/24");
+        assert_eq!(
+            config.slot_addrs(0),
+            Some(("10.100.0.1".parse().unwrap(), "10.100.0.2".parse().unwrap()))
+        );
+        assert_eq!(
+            config.slot_addrs(63),
+            Some((
+                "10.100.0.253".parse().unwrap(),
+                "10.100.0.254".parse().unwrap()
+            ))
+        );
+        assert_eq!(config.slot_addrs(64), None);
+    }
+
+    #[test]
+    fn validates_networks() {
+        assert_eq!(
+            Ipv4Prefix::try_from("10.100.3.7/16".to_string()).unwrap(),
+            Ipv4Prefix {
+                addr: "10.100.0.0".parse().unwrap(),
+                len: 16
+            }
+        );
+        assert!(Ipv4Prefix::try_from("10.100.0.0".to_string()).is_err());
+        assert!(Ipv4Prefix::try_from("10.100.0.0/33".to_string()).is_err());
+
+        assert!(network("10.100.0.0/30").validate("db").is_ok());
+        assert!(network("10.100.0.0/31").validate("db").is_err());
+        assert!(network("172.20.0.0/16").validate("db").is_err());
+        assert!(network("172.0.0.0/8").validate("db").is_err());
+
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let (wide, narrow, next) = (
+            network("10.0.0.0/8"),
+            network("10.100.0.0/16"),
+            network("10.101.0.0/16"),
+        );
+        assert!(PrivateNetworkConfig::validate_all([(&a, &wide), (&b, &narrow)]).is_err());
+        assert!(PrivateNetworkConfig::validate_all([(&a, &narrow), (&b, &next)]).is_ok());
+    }
+}
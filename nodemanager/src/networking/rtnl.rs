@@ -0,0 +1,162 @@
+//! TAP devices and links managed through the tun driver and rtnetlink instead of the `ip` binary.
+
+use std::{
+    ffi::CString,
+    fs::OpenOptions,
+    net::IpAddr,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::netlink::{
+    parse_str, Attributes, Message, Socket, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL,
+    NLM_F_REQUEST,
+};
+
+const IFINFOMSG_LEN: usize = 16;
+
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    /// The driver of the link, e.g. `tun` for TAP devices.
+    pub kind: Option<String>,
+}
+
+/// Creates a persistent TAP device which Firecracker can attach to later.
+pub fn create_tap(name: &str) -> Result<()> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(anyhow!("Interface name {} is too long", name));
+    }
+    let tun = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open("/dev/net/tun")
+        .context("Unable to open /dev/net/tun")?;
+
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+    if unsafe { libc::ioctl(tun.as_raw_fd(), libc::TUNSETIFF, &ifr) } < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Unable to create TAP device {}", name));
+    }
+    // Keep the device around after the descriptor is closed
+    if unsafe { libc::ioctl(tun.as_raw_fd(), libc::TUNSETPERSIST, 1) } < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Unable to make TAP device {} persistent", name));
+    }
+    Ok(())
+}
+
+/// Looks up the index of a link, `None` if there is no link with that name.
+pub fn link_index(name: &str) -> Result<Option<u32>> {
+    let c_name = CString::new(name)?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENODEV) {
+                return Ok(None);
+            }
+            Err(e).with_context(|| format!("Unable to find link {}", name))
+        }
+        index => Ok(Some(index)),
+    }
+}
+
+pub fn add_address(index: u32, addr: &IpAddr, prefix_len: u8) -> Result<()> {
+    let (family, flags, octets) = match addr {
+        IpAddr::V4(addr) => (libc::AF_INET, 0, addr.octets().to_vec()),
+        // The address is ours alone, skip duplicate address detection so it is usable right away
+        IpAddr::V6(addr) => (libc::AF_INET6, libc::IFA_F_NODAD, addr.octets().to_vec()),
+    };
+    let mut header = vec![
+        family as u8,
+        prefix_len,
+        flags as u8,
+        libc::RT_SCOPE_UNIVERSE,
+    ];
+    header.extend(index.to_ne_bytes());
+    let mut msg = Message::new(
+        libc::RTM_NEWADDR,
+        NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        &header,
+    );
+    msg.attr(libc::IFA_LOCAL, &octets);
+    msg.attr(libc::IFA_ADDRESS, &octets);
+    Socket::open(libc::NETLINK_ROUTE)?
+        .request(msg)
+        .with_context(|| format!("Unable to add address {}/{}", addr, prefix_len))
+}
+
+pub fn set_up(index: u32) -> Result<()> {
+    let flags = libc::IFF_UP as u32;
+    let msg = Message::new(
+        libc::RTM_NEWLINK,
+        NLM_F_REQUEST | NLM_F_ACK,
+        &ifinfomsg(index, flags, flags),
+    );
+    Socket::open(libc::NETLINK_ROUTE)?
+        .request(msg)
+        .context("Unable to set link up")
+}
+
+pub fn delete_link(index: u32) -> Result<()> {
+    let msg = Message::new(
+        libc::RTM_DELLINK,
+        NLM_F_REQUEST | NLM_F_ACK,
+        &ifinfomsg(index, 0, 0),
+    );
+    Socket::open(libc::NETLINK_ROUTE)?
+        .request(msg)
+        .context("Unable to delete link")
+}
+
+pub fn list_links() -> Result<Vec<Link>> {
+    let msg = Message::new(
+        libc::RTM_GETLINK,
+        NLM_F_REQUEST | NLM_F_DUMP,
+        &ifinfomsg(0, 0, 0),
+    );
+    let payloads = Socket::open(libc::NETLINK_ROUTE)?
+        .dump(msg, libc::RTM_NEWLINK)
+        .context("Unable to list links")?;
+
+    let mut links = Vec::with_capacity(payloads.len());
+    for payload in payloads {
+        let Some(index) = payload.get(4..8) else {
+            continue;
+        };
+        let mut link = Link {
+            index: u32::from_ne_bytes(index.try_into().unwrap()),
+            name: String::new(),
+            kind: None,
+        };
+        for (attr_type, value) in Attributes(payload.get(IFINFOMSG_LEN..).unwrap_or(&[])) {
+            match attr_type {
+                libc::IFLA_IFNAME => link.name = parse_str(value),
+                libc::IFLA_LINKINFO => {
+                    link.kind = Attributes(value)
+                        .find(|(attr_type, _)| *attr_type == libc::IFLA_INFO_KIND)
+                        .map(|(_, kind)| parse_str(kind))
+                }
+                _ => {}
+            }
+        }
+        links.push(link);
+    }
+    Ok(links)
+}
+
+/// The header of link messages: family, type, index, flags and the mask of flags to change.
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> [u8; IFINFOMSG_LEN] {
+    let mut header = [0; IFINFOMSG_LEN];
+    header[0] = libc::AF_UNSPEC as u8;
+    header[4..8].copy_from_slice(&index.to_ne_bytes());
+    header[8..12].copy_from_slice(&flags.to_ne_bytes());
+    header[12..16].copy_from_slice(&change.to_ne_bytes());
+    header
+}
@@ -0,0 +1,112 @@
+//! Instances persisted to disk, so they can be reattached after the nodemanager restarts or crashes.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{machine::MachineState, networking::PublishedPort};
+
+const INSTANCES_DIRECTORY: &str = "instances";
+const EVENT_REVISION_FILE: &str = "event_revision";
+
+#[derive(Serialize, Deserialize)]
+pub struct InstanceRecord {
+    pub machine: MachineState,
+    pub network_slot: u16,
+    #[serde(default)]
+    pub published_ports: Vec<PublishedPort>,
+}
+
+/// One JSON file per instance, replaced atomically on every change.
+#[derive(Clone)]
+pub struct StateStore {
+    dir: PathBuf,
+    instances_dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let instances_dir = dir.join(INSTANCES_DIRECTORY);
+        std::fs::create_dir_all(&instances_dir)
+            .with_context(|| format!("Unable to create state directory {:?}", instances_dir))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            instances_dir,
+        })
+    }
+
+    pub fn save(&self, record: &InstanceRecord) -> Result<()> {
+        let path = self.record_path(&record.machine.uuid);
+        write_atomically(&path, &serde_json::to_vec(record)?)
+    }
+
+    /// The highest event revision reserved so far, 0 if none was.
+    pub fn load_event_revision(&self) -> Result<u64> {
+        match std::fs::read_to_string(self.dir.join(EVENT_REVISION_FILE)) {
+            Ok(revision) => Ok(revision.trim().parse()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_event_revision(&self, revision: u64) -> Result<()> {
+        write_atomically(
+            &self.dir.join(EVENT_REVISION_FILE),
+            revision.to_string().as_bytes(),
+        )
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        match std::fs::remove_file(self.record_path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads all records. Unreadable ones, e.g. from a crash while writing, are removed.
+    pub fn load_all(&self) -> Result<Vec<InstanceRecord>> {
+        let mut records = Vec::new();
+        for entry in std::fs::read_dir(&self.instances_dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let record = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_slice::<InstanceRecord>(&data)?));
+            match record {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!("Removing unreadable instance record {:?}: {:?}", path, e);
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.instances_dir.join(format!("{}.json", id))
+    }
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    // Make the rename itself durable
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+/// Runs `test` on a thread of its own in new network and mount namespaces with a tmpfs on `/srv`,
+/// so the TAP devices, firewall rules and jails it creates don't touch the host.
+/// Skipped if the namespaces can't be created, e.g. when not running as root.
+pub(crate) fn isolated(test: impl FnOnce() + Send + 'static) {
+    let res = std::thread::spawn(move || {
+        let srv = c"/srv";
+        unsafe {
+            if libc::unshare(libc::CLONE_NEWNET | libc::CLONE_NEWNS) < 0 {
+                eprintln!(
+                    "Skipped, no namespaces: {}",
+                    std::io::Error::last_os_error()
+                );
+                return;
+            }
+            assert_eq!(
+                libc::mount(
+                    std::ptr::null(),
+                    c"/".as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null()
+                ),
+                0
+            );
+            std::fs::create_dir_all("/srv").unwrap();
+            assert_eq!(
+                libc::mount(
+                    c"tmpfs".as_ptr(),
+                    srv.as_ptr(),
+                    c"tmpfs".as_ptr(),
+                    0,
+                    c"size=64g".as_ptr().cast()
+                ),
+                0
+            );
+        }
+        test();
+    })
+    .join();
+    if let Err(panic) = res {
+        std::panic::resume_unwind(panic);
+    }
+}
@@ -0,0 +1,176 @@
+//! Named volumes which outlive the instances they are attached to, stored as ext4 filesystem images on the host.
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::networking::cmd;
+
+const VOLUME_EXTENSION: &str = "ext4";
+const MAX_NAME_LENGTH: usize = 64;
+
+pub struct Volume {
+    pub name: String,
+    pub size_mb: u64,
+    pub path: PathBuf,
+}
+
+/// A volume attached to a machine and where the container sees it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VolumeMount {
+    pub name: String,
+    pub path: PathBuf,
+    pub mount_path: String,
+    pub read_only: bool,
+}
+
+/// Volume names end up in file names and guest paths, so only a safe subset of characters is allowed.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Mount paths in the container have to be absolute and must not escape with `..`.
+pub fn is_valid_mount_path(path: &str) -> bool {
+    path.starts_with('/') && path != "/" && !path.split('/').any(|part| part == "..")
+}
+
+/// One filesystem image per volume in a directory, `<name>.ext4`.
+pub struct VolumeStore {
+    dir: PathBuf,
+}
+
+impl VolumeStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Unable to create volume directory {:?}", dir))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Creates an empty ext4 volume, failing if a volume with that name exists already.
+    pub fn create(&self, name: &str, size_mb: u64) -> Result<Volume> {
+        if !is_valid_name(name) {
+            return Err(anyhow!("Invalid volume name {}", name));
+        }
+        let len = size_mb
+            .checked_mul(1024 * 1024)
+            .ok_or(anyhow!("Invalid volume size {} MB", size_mb))?;
+        let path = self.volume_path(name);
+        let partial = path.with_extension("partial");
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+            .with_context(|| format!("Unable to create volume {}", name))?
+            .set_len(len)?;
+
+        let res = (|| {
+            cmd(
+                "mkfs.ext4",
+                &["-q", "-F", "-m", "0", partial.to_str().unwrap_or_default()],
+            )?;
+            // Unlike a rename, linking fails if the volume was created in the meantime
+            std::fs::hard_link(&partial, &path)
+                .with_context(|| format!("Unable to create volume {}", name))
+        })();
+        let _ = std::fs::remove_file(&partial);
+        res?;
+
+        Ok(Volume {
+            name: name.to_string(),
+            size_mb,
+            path,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Volume>> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+        let path = self.volume_path(name);
+        match std::fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(Volume {
+                name: name.to_string(),
+                size_mb: metadata.size() / 1024 / 1024,
+                path,
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Unable to read volume {}", name)),
+        }
+    }
+
+    /// Removes a volume and its data, returns whether it existed.
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        if !is_valid_name(name) {
+            return Ok(false);
+        }
+        match std::fs::remove_file(self.volume_path(name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Unable to delete volume {}", name)),
+        }
+    }
+
+    /// The total size of all volumes and the bytes they take up on disk, which is less as long as they are sparse.
+    pub fn disk_usage(&self) -> Result<(u64, u64)> {
+        let mut usage = (0, 0);
+        for volume in self.list()? {
+            let metadata = std::fs::metadata(&volume.path)?;
+            usage.0 += metadata.size();
+            usage.1 += metadata.blocks() * 512;
+        }
+        Ok(usage)
+    }
+
+    pub fn list(&self) -> Result<Vec<Volume>> {
+        let mut volumes = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != VOLUME_EXTENSION) {
+                continue;
+            }
+            let name = path
+                .file_stem()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if let Some(volume) = self.get(&name)? {
+                volumes.push(volume);
+            }
+        }
+        volumes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(volumes)
+    }
+
+    fn volume_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, VOLUME_EXTENSION))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_names_and_mount_paths() {
+        for name in ["data", "pg-data_1", "v1.2"] {
+            assert!(is_valid_name(name), "{}", name);
+        }
+        for name in ["", ".hidden", "a/b", "a b", &"x".repeat(65)] {
+            assert!(!is_valid_name(name), "{}", name);
+        }
+        assert!(is_valid_mount_path("/var/lib/data"));
+        for path in ["/", "data", "/var/../etc", "/.."] {
+            assert!(!is_valid_mount_path(path), "{}", path);
+        }
+    }
+}
@@ -5,6 +5,7 @@ pub enum GuestExitCode {
     GracefulShutdown, // Requested by the host to shut down gracefully
     FailedToPullContainerImage,
     ContainerExited(i32),
+    FailedToMountVolume,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
@@ -77,12 +78,57 @@ impl LogMessage {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum ExecOutputType {
+    Stdout,
+    Stderr,
+}
+
+impl ExecOutputType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecOutputType::Stdout => "stdout",
+            ExecOutputType::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ExecOutput {
+    pub exec_id: u32,
+    pub output_type: ExecOutputType,
+    pub data: Vec<u8>, // Raw bytes, not split into lines
+}
+
+/// Resource usage of the container, sampled periodically by the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub struct ResourceUsage {
+    pub timestamp_ms: u64,
+    pub cpu_usage_usec: u64, // Total CPU time, from the cgroup of the container
+    pub cpu_user_usec: u64,
+    pub cpu_system_usec: u64,
+    pub memory_bytes: u64,
+    pub memory_limit_bytes: Option<u64>, // Only with a limit on the cgroup
+    pub disk_read_bytes: u64,
+    pub disk_write_bytes: u64,
+    pub disk_used_bytes: u64, // On the scratch disk holding the container
+    pub disk_total_bytes: u64,
+    pub net_rx_bytes: u64, // All interfaces of the VM besides the loopback
+    pub net_rx_packets: u64,
+    pub net_tx_bytes: u64,
+    pub net_tx_packets: u64,
+}
+
 // Guest -> Host
 #[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
 pub enum GuestPacket {
     Log(LogMessage),
     VmState((InitVmState, u64)), // (state, timestamp_ms)
     Exited(GuestExitCode),
+    ExecOutput(ExecOutput),
+    ExecExited((u32, i32)),  // (exec_id, exit_code)
+    TerminalOutput(Vec<u8>), // Raw output from the container terminal, if running with one
+    Metrics(ResourceUsage),
 }
 
 pub fn serialize_guest_packet(packet: &GuestPacket) -> Vec<u8> {
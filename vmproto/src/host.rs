@@ -1,8 +1,22 @@
 use bitcode::{Decode, Encode};
+
+pub const MAX_HOST_PACKET_SIZE: usize = 2 * 1024; // Serialized, the guest rejects larger host packets
+pub const MAX_STDIN_CHUNK_SIZE: usize = 1024; // Leaves room for the rest of a Stdin packet
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ExecRequest {
+    pub exec_id: u32, // Chosen by the host, echoed back in every exec packet from the guest
+    pub args: Vec<String>,
+}
+
 // Host -> Guest
 #[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
 pub enum HostPacket {
     Shutdown,
+    Exec(ExecRequest),
+    Stdin(Vec<u8>), // Raw bytes for the container stdin (or terminal), at most MAX_STDIN_CHUNK_SIZE
+    CloseStdin,
+    ResizeTerminal((u16, u16)), // (rows, cols)
 }
 
 pub fn serialize_host_packet(packet: &HostPacket) -> Vec<u8> {
@@ -2,3 +2,4 @@ pub const MAX_PACKET_SIZE: usize = 1024 * 1024; // 1 MB
 
 pub mod guest;
 pub mod host;
+pub mod registry;
@@ -0,0 +1,129 @@
+//! Helpers for talking to OCI registries, shared by the guest which pulls images itself
+//! and the image cache of the host.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+/// Parses a `WWW-Authenticate` header into its lowercased scheme and parameters.
+pub fn parse_challenge(header: &str) -> Option<(String, HashMap<String, String>)> {
+    let (scheme, rest) = header.trim().split_once(' ').unwrap_or((header.trim(), ""));
+    let mut params = HashMap::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() {
+            break;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect();
+        }
+        params.insert(key.trim().to_lowercase(), value.trim().to_string());
+    }
+    Some((scheme.to_lowercase(), params))
+}
+
+/// The base URL of a registry as resolved from an image reference, e.g. `docker.io` or `localhost:5000`.
+/// Registries on the loopback are spoken to over plain HTTP, like Docker does.
+pub fn registry_url(registry: &str) -> String {
+    let host = registry.split(':').next().unwrap_or(registry);
+    let scheme = if host == "localhost" || host.starts_with("127.") {
+        "http"
+    } else {
+        "https"
+    };
+    format!("{}://{}", scheme, registry)
+}
+
+/// The realm and query parameters to request a token for pulling `repository` with,
+/// following the parameters of a bearer challenge. None if the challenge has no realm.
+pub fn token_request<'a>(
+    repository: &str,
+    params: &'a HashMap<String, String>,
+) -> Option<(&'a str, Vec<(&'static str, String)>)> {
+    let realm = params.get("realm")?;
+    let mut query = vec![("scope", format!("repository:{}:pull", repository))];
+    if let Some(service) = params.get("service") {
+        query.push(("service", service.clone()));
+    }
+    Some((realm, query))
+}
+
+/// The response of a token server, which may call the token either of the two names.
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+impl TokenResponse {
+    pub fn into_token(self) -> Option<String> {
+        self.token.or(self.access_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_params() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/alpine:pull,push");
+
+        let (scheme, params) = parse_challenge(r#"Basic realm="Registry Realm""#).unwrap();
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "Registry Realm");
+    }
+
+    #[test]
+    fn loopback_registries_use_http() {
+        assert_eq!(registry_url("localhost:5000"), "http://localhost:5000");
+        assert_eq!(registry_url("127.0.0.1:5000"), "http://127.0.0.1:5000");
+        assert_eq!(registry_url("ghcr.io"), "https://ghcr.io");
+        assert_eq!(
+            registry_url("localhost.example.com"),
+            "https://localhost.example.com"
+        );
+    }
+
+    #[test]
+    fn token_request_scopes_the_repository() {
+        let (_, params) =
+            parse_challenge(r#"Bearer realm="https://ghcr.io/token",service="ghcr.io""#).unwrap();
+        let (realm, query) = token_request("org/app", &params).unwrap();
+        assert_eq!(realm, "https://ghcr.io/token");
+        assert_eq!(
+            query,
+            vec![
+                ("scope", "repository:org/app:pull".to_string()),
+                ("service", "ghcr.io".to_string())
+            ]
+        );
+
+        let (_, params) = parse_challenge("Bearer service=\"ghcr.io\"").unwrap();
+        assert!(token_request("org/app", &params).is_none());
+
+        let response: TokenResponse = serde_json::from_str(r#"{"access_token":"abc"}"#).unwrap();
+        assert_eq!(response.into_token().as_deref(), Some("abc"));
+    }
+}